@@ -0,0 +1,199 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use common::Verbosity;
+
+/// Where the configuration file lives on the boot volume.
+pub const CONFIG_PATH: &str = "\\hhh\\boot.cfg";
+
+/// Bootloader settings, read from [`CONFIG_PATH`].
+///
+/// The file consists of `key = value` lines; empty lines and lines starting with `#` are ignored.
+///
+/// - `timeout`: seconds the boot menu waits before starting the default entry; with 0 the menu is
+///   only shown if a key is held down
+/// - `default`: title of the entry started when the timeout runs out
+/// - `resolution`: preferred video mode, as `<width>x<height>`
+/// - `max_resolution`: if there is no preferred mode (or it isn't available), the largest mode that
+///   fits within this is used
+/// - `verbosity`: `quiet`, `normal` or `verbose`; of the boot log, quiet shows only errors on
+///   screen, normal adds warnings and verbose shows everything. The kernel gets the whole log.
+/// - `entry`: starts a boot menu entry with the given title, described by the keys that follow it:
+///   - `kernel`: path of the kernel image on the boot volume
+///   - `cmdline`: command line handed to the kernel
+///   - `gdb`: `yes` to wait for a debugger before jumping to the kernel
+///   - `kaslr`: `no` to load the kernel at the address it was linked at
+///   - `initrd`: path of the initial ramdisk archive, `initrd.tar` by default; an empty value boots
+///     without one
+///   - `fallback`: title of the entry to boot instead if this one did not finish booting last time;
+///     without it, the most recent other entry that booted is used
+///
+/// Entry keys before the first `entry` line describe an entry titled `default`.
+pub struct Config {
+    pub entries: Vec<Entry>,
+    /// Index of the default entry.
+    pub default: usize,
+    pub timeout: u32,
+    pub resolution: Option<(usize, usize)>,
+    pub max_resolution: Option<(usize, usize)>,
+    pub verbosity: Verbosity,
+}
+
+pub struct Entry {
+    pub title: String,
+    pub kernel_path: String,
+    pub command_line: String,
+    pub wait_for_gdb: bool,
+    /// Load the kernel at a random address, if it can be relocated.
+    pub kaslr: bool,
+    /// Path of the initial ramdisk; empty for none.
+    pub initrd_path: String,
+    /// Index of the entry to boot if this one failed.
+    pub fallback: Option<usize>,
+}
+
+impl Entry {
+    fn new(title: &str) -> Self {
+        Self {
+            title: String::from(title),
+            kernel_path: String::from("kernel.elf"),
+            command_line: String::new(),
+            wait_for_gdb: false,
+            kaslr: true,
+            initrd_path: String::from("initrd.tar"),
+            fallback: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            entries: vec![Entry::new("default")],
+            default: 0,
+            timeout: 0,
+            resolution: None,
+            max_resolution: None,
+            verbosity: Verbosity::Normal,
+        }
+    }
+}
+
+impl Config {
+    /// Parses a configuration file. Unknown keys are reported and skipped, so an older bootloader
+    /// can still boot with a newer file.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Self {
+            entries: Vec::new(),
+            ..Self::default()
+        };
+        let mut default = None;
+        // Entry index, fallback title and line number, resolved once all entries are known
+        let mut fallbacks = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(format!("line {}: expected `key = value`", number + 1)),
+            };
+            if let "kernel" | "cmdline" | "gdb" | "kaslr" | "initrd" | "fallback" = key {
+                if config.entries.is_empty() {
+                    config.entries.push(Entry::new("default"));
+                }
+            }
+            match key {
+                "entry" => {
+                    if config.entries.iter().any(|entry| entry.title == value) {
+                        return Err(format!("line {}: duplicate entry `{}`", number + 1, value));
+                    }
+                    config.entries.push(Entry::new(value))
+                }
+                "kernel" => config.entries.last_mut().unwrap().kernel_path = String::from(value),
+                "cmdline" => config.entries.last_mut().unwrap().command_line = String::from(value),
+                "gdb" => {
+                    config.entries.last_mut().unwrap().wait_for_gdb = parse_bool(value, number)?
+                }
+                "kaslr" => config.entries.last_mut().unwrap().kaslr = parse_bool(value, number)?,
+                "initrd" => config.entries.last_mut().unwrap().initrd_path = String::from(value),
+                "fallback" => fallbacks.push((config.entries.len() - 1, value, number)),
+                "default" => default = Some(value),
+                "timeout" => {
+                    config.timeout = value
+                        .parse()
+                        .map_err(|_| format!("line {}: bad timeout", number + 1))?
+                }
+                "resolution" => {
+                    config.resolution = Some(
+                        parse_resolution(value)
+                            .ok_or_else(|| format!("line {}: bad resolution", number + 1))?,
+                    )
+                }
+                "max_resolution" => {
+                    config.max_resolution = Some(
+                        parse_resolution(value)
+                            .ok_or_else(|| format!("line {}: bad resolution", number + 1))?,
+                    )
+                }
+                "verbosity" => {
+                    config.verbosity = match value {
+                        "quiet" => Verbosity::Quiet,
+                        "normal" => Verbosity::Normal,
+                        "verbose" => Verbosity::Verbose,
+                        _ => return Err(format!("line {}: bad verbosity", number + 1)),
+                    }
+                }
+                _ => warn!("{}: unknown key `{}`, ignoring", CONFIG_PATH, key),
+            }
+        }
+
+        if config.entries.is_empty() {
+            config.entries.push(Entry::new("default"));
+        }
+        if let Some(title) = default {
+            config.default = config
+                .entries
+                .iter()
+                .position(|entry| entry.title == title)
+                .ok_or_else(|| format!("default entry `{}` does not exist", title))?;
+        }
+        for (index, title, number) in fallbacks {
+            let fallback = config
+                .entries
+                .iter()
+                .position(|entry| entry.title == title)
+                .ok_or_else(|| {
+                    format!(
+                        "line {}: fallback entry `{}` does not exist",
+                        number + 1,
+                        title
+                    )
+                })?;
+            if fallback == index {
+                return Err(format!(
+                    "line {}: entry `{}` cannot fall back to itself",
+                    number + 1,
+                    title
+                ));
+            }
+            config.entries[index].fallback = Some(fallback);
+        }
+        Ok(config)
+    }
+}
+
+fn parse_bool(value: &str, number: usize) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("line {}: expected `yes` or `no`", number + 1)),
+    }
+}
+
+/// Parses `<width>x<height>`.
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let i = value.find('x')?;
+    let width = value[..i].trim().parse().ok()?;
+    let height = value[i + 1..].trim().parse().ok()?;
+    Some((width, height))
+}
@@ -0,0 +1,162 @@
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use common::{
+    elf::{Elf, ElfError, PAGE_SIZE, STT_FUNC},
+    Symbol, SymbolMap,
+};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, BootServices, MemoryType},
+};
+use x86_64::{instructions::random::RdRand, structures::paging::PageTableFlags};
+
+/// The kernel is linked at the start of this window and may be slid anywhere inside it.
+const KERNEL_WINDOW: Range<u64> = 0xFFFF8000_00000000..0xFFFF8080_00000000;
+/// Granularity of the slide, so the image keeps any large-page alignment it was linked with.
+const SLIDE_ALIGN: u64 = 0x200000;
+
+/// A loaded kernel segment and the permissions it is mapped with.
+pub struct KernelSegment {
+    pub virt_start: u64,
+    pub virt_end: u64,
+    pub phys_start: u64,
+    pub flags: PageTableFlags,
+}
+
+pub struct LoadedKernel {
+    /// Virtual addresses include the slide.
+    pub segments: Vec<KernelSegment>,
+    pub entry: u64,
+    /// Physical memory the image was copied into, BSS and gaps between segments included.
+    pub phys_range: Range<u64>,
+    pub slide: u64,
+}
+
+/// Picks a random offset to load a relocatable kernel at, keeping it inside its window. Kernels
+/// that can't be relocated, or that are linked outside the window, aren't moved.
+pub fn choose_slide(elf: &Elf) -> Result<u64, ElfError> {
+    if !elf.is_relocatable() {
+        return Ok(0);
+    }
+    let segments = elf.loadable_segments()?;
+    let start = segments[0].virtual_addr;
+    let last = segments[segments.len() - 1];
+    let end = last.virtual_addr + last.mem_size;
+    if start < KERNEL_WINDOW.start || end > KERNEL_WINDOW.end {
+        return Ok(0);
+    }
+    let align = segments
+        .iter()
+        .map(|segment| segment.align)
+        .fold(SLIDE_ALIGN, u64::max);
+    let positions = (KERNEL_WINDOW.end - end) / align + 1;
+    Ok(random() % positions * align)
+}
+
+/// Copies the kernel into freshly allocated memory, zeroes its BSS and applies its relocations for
+/// `slide`.
+pub fn load(bs: &BootServices, elf: &Elf, slide: u64) -> Result<LoadedKernel, ElfError> {
+    let segments = elf.loadable_segments()?;
+    let image = elf.image_range()?;
+    let image_size = image.end - image.start;
+    let phys_base = bs
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            (image_size / PAGE_SIZE) as usize,
+        )
+        .unwrap_success();
+    let phys_of = |virt: u64| phys_base + (virt - image.start);
+
+    // Safety: the allocation above is `image_size` bytes, and nothing else refers to it yet
+    let buffer =
+        unsafe { core::slice::from_raw_parts_mut(phys_base as *mut u8, image_size as usize) };
+    elf.load_into(buffer, slide)?;
+
+    // Every segment is mapped with 4K pages carrying its own permissions, so text can't be written
+    // and data can't be executed
+    let mut kernel_segments = Vec::with_capacity(segments.len());
+    for segment in &segments {
+        let mut flags = PageTableFlags::PRESENT;
+        if segment.is_writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.is_executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if segment.is_writable() && segment.is_executable() {
+            warn!(
+                "Kernel segment at {:x} is both writable and executable",
+                segment.virtual_addr
+            );
+        }
+        kernel_segments.push(KernelSegment {
+            virt_start: segment.virtual_addr + slide,
+            virt_end: segment.virtual_addr + segment.mem_size + slide,
+            phys_start: phys_of(segment.virtual_addr),
+            flags,
+        });
+    }
+
+    Ok(LoadedKernel {
+        segments: kernel_segments,
+        entry: elf.entry + slide,
+        phys_range: phys_base..phys_base + image_size,
+        slide,
+    })
+}
+
+/// Collects the function symbols of the kernel into the compact map the kernel symbolizes
+/// backtraces with. The map is leaked, so it outlives the bootloader.
+pub fn symbol_map(elf: &Elf, slide: u64) -> Result<SymbolMap<'static>, ElfError> {
+    let mut symbols = Vec::new();
+    let mut names = Vec::new();
+    for symbol in elf.symbols()? {
+        if symbol.kind != STT_FUNC || symbol.value == 0 || symbol.name.is_empty() {
+            continue;
+        }
+        symbols.push(Symbol {
+            address: symbol.value + slide,
+            size: symbol.size,
+            name_offset: names.len() as u32,
+            name_len: symbol.name.len() as u32,
+        });
+        names.extend_from_slice(symbol.name.as_bytes());
+    }
+    symbols.sort_unstable_by_key(|symbol| symbol.address);
+    Ok(SymbolMap {
+        symbols: symbols.leak(),
+        names: names.leak(),
+    })
+}
+
+/// Copies the initial ramdisk into page-aligned memory of its own, which the kernel keeps when it
+/// reclaims the bootloader's memory. Returns the physical range it is in.
+pub fn load_initrd(bs: &BootServices, data: &[u8]) -> Range<u64> {
+    let size = align_up(data.len() as u64);
+    let phys_base = bs
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            (size / PAGE_SIZE) as usize,
+        )
+        .unwrap_success();
+    // Safety: the allocation is at least `data.len()` bytes
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), phys_base as *mut u8, data.len());
+    }
+    phys_base..phys_base + data.len() as u64
+}
+
+fn align_up(address: u64) -> u64 {
+    (address + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// A random number from RDRAND, or the time stamp counter on CPUs without it.
+fn random() -> u64 {
+    match RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        Some(value) => value,
+        None => unsafe { core::arch::x86_64::_rdtsc() },
+    }
+}
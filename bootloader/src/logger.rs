@@ -0,0 +1,78 @@
+//! Leveled logging. Every message goes into the boot log handed to the kernel; only those at or
+//! above the screen level are printed as well.
+
+use core::fmt;
+
+use common::{
+    boot_log::{Level, LogBuffer},
+    BootLog, Framebuffer, Verbosity,
+};
+
+const LOG_SIZE: usize = 64 * 1024;
+
+// The bootloader runs on one core, and nothing logs from interrupt handlers
+static mut LOG: LogBuffer<LOG_SIZE> = LogBuffer::new();
+static mut SCREEN_LEVEL: Level = Level::Warn;
+static mut SCREEN_READY: bool = false;
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::logger::_log(common::boot_log::Level::Error, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::logger::_log(common::boot_log::Level::Warn, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::logger::_log(common::boot_log::Level::Info, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::logger::_log(common::boot_log::Level::Debug, format_args!($($arg)*)));
+}
+
+/// Starts printing to `framebuffer`, beginning with what was logged at screen level before it was
+/// available. Quiet shows only errors; verbose shows everything.
+pub fn init_screen(framebuffer: Framebuffer, verbosity: Verbosity) {
+    unsafe {
+        common::writer::init(framebuffer);
+        SCREEN_LEVEL = match verbosity {
+            Verbosity::Quiet => Level::Error,
+            Verbosity::Normal => Level::Warn,
+            Verbosity::Verbose => Level::Debug,
+        };
+        for (level, message) in LOG.log().records() {
+            if level <= SCREEN_LEVEL {
+                show(level, format_args!("{}", message));
+            }
+        }
+        SCREEN_READY = true;
+    }
+}
+
+/// Everything logged so far.
+pub fn boot_log() -> BootLog<'static> {
+    unsafe { LOG.log() }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    unsafe {
+        LOG.record(level, args);
+        if SCREEN_READY && level <= SCREEN_LEVEL {
+            show(level, args);
+        }
+    }
+}
+
+fn show(level: Level, args: fmt::Arguments) {
+    match level {
+        Level::Error => println!("Error: {}", args),
+        Level::Warn => println!("Warning: {}", args),
+        _ => println!("{}", args),
+    }
+}
@@ -2,8 +2,6 @@
 #![no_main]
 #![feature(lang_items)]
 #![feature(abi_efiapi)]
-#![feature(maybe_uninit_uninit_array)]
-#![feature(maybe_uninit_array_assume_init)]
 #![feature(asm)]
 #![feature(vec_into_raw_parts)]
 #![feature(abi_x86_interrupt)]
@@ -19,33 +17,37 @@ extern crate uefi_services;
 #[macro_use]
 extern crate common;
 
-mod elf;
+#[macro_use]
+mod logger;
+
+mod config;
 mod exceptions;
+mod loader;
+mod menu;
 mod panic;
 
-use acpi::{
-    mcfg::{Mcfg, McfgEntry},
-    sdt::Signature,
-    AcpiHandler, PhysicalMapping,
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use common::{
+    efi::{self, BootState},
+    elf::Elf,
+    BootLog, Framebuffer, MachineInfo, MachineInfoC, MemoryRegion, MemoryRegionType, SymbolMap,
 };
-use alloc::vec::Vec;
-use common::{Framebuffer, MachineInfo, MachineInfoC};
+use config::Config;
 use core::fmt::Debug;
-use elf::{Elf, EntryType, HeaderEntry, SectionType};
 use exceptions::page_fault;
-use log::info;
 use uefi::{
     prelude::*,
     proto::{
-        console::gop::GraphicsOutput,
+        console::gop::{GraphicsOutput, Mode, PixelFormat},
+        loaded_image::LoadedImage,
         media::{
-            file::{File, FileAttribute, FileMode, FileType},
+            file::{Directory, File, FileAttribute, FileMode, FileType, RegularFile},
             fs::SimpleFileSystem,
         },
     },
     table::{
-        boot::{AllocateType, MemoryAttribute, MemoryDescriptor, MemoryType},
-        cfg::{self, ACPI2_GUID},
+        boot::{MemoryAttribute, MemoryDescriptor, MemoryType},
+        cfg::{self, ACPI2_GUID, ACPI_GUID},
     },
 };
 use uefi::{
@@ -54,6 +56,10 @@ use uefi::{
 };
 use x86_64::{
     instructions,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable},
         idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
@@ -71,222 +77,13 @@ extern crate alloc;
 
 #[allow(clippy::clippy::identity_op)]
 const _1G: u64 = 1 * 1024 * 1024 * 1024;
-const _2M: u64 = 2 * 1024 * 1024;
 const _4K: u64 = 4 * 1024;
 
-fn to_utf16<const CHAR_COUNT: usize>(string: &str) -> [u16; CHAR_COUNT] {
-    let mut ret = [0; CHAR_COUNT];
-    ucs2::encode(string, &mut ret).unwrap();
-    ret
-}
-
 #[entry]
 fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
     uefi_services::init(&st).unwrap().unwrap();
-    let (entry, mut machine_info, kernel_addresses) = {
+    let (entry, mut machine_info, kernel_segments, wait_for_gdb) = {
         st.stdout().clear().unwrap().unwrap();
-        let framebuffer = st
-            .boot_services()
-            .locate_protocol::<GraphicsOutput>()
-            .unwrap()
-            .unwrap();
-        let framebuffer = unsafe { framebuffer.get().as_mut().unwrap() };
-        let current_mode = framebuffer.current_mode_info();
-        let pixel_format = current_mode.pixel_format();
-        let framebuffer = Framebuffer {
-            ptr: framebuffer.frame_buffer().as_mut_ptr() as _,
-            resolution_x: current_mode.resolution().0,
-            resolution_y: current_mode.resolution().1,
-            stride: current_mode.stride(),
-        };
-        unsafe {
-            common::writer::init(framebuffer.clone());
-        }
-        println!("Writer initialized");
-
-        #[derive(Copy, Clone)]
-        struct SimpleHandler;
-        impl AcpiHandler for SimpleHandler {
-            unsafe fn map_physical_region<T>(
-                &self,
-                physical_address: usize,
-                size: usize,
-            ) -> PhysicalMapping<Self, T> {
-                let ptr = core::ptr::NonNull::new(physical_address as *mut T).unwrap();
-                PhysicalMapping {
-                    physical_start: physical_address,
-                    virtual_start: ptr,
-                    region_length: size,
-                    mapped_length: size,
-                    handler: *self,
-                }
-            }
-
-            fn unmap_physical_region<T>(&self, region: &PhysicalMapping<Self, T>) {}
-        }
-
-        println!("Finding ACPI tables...");
-        let mut acpi_tables = None;
-        for config_table in st.config_table() {
-            if config_table.guid == ACPI2_GUID {
-                unsafe {
-                    acpi_tables = Some(
-                        acpi::AcpiTables::from_rsdp(SimpleHandler, config_table.address as usize)
-                            .unwrap(),
-                    );
-                };
-            }
-        }
-        let acpi_tables = acpi_tables.unwrap();
-
-        fn entries(mcfg: &Mcfg) -> &[McfgEntry] {
-            use acpi::AcpiTable;
-            use core::mem;
-            use core::slice;
-            let length = mcfg.header().length as usize - mem::size_of::<Mcfg>();
-
-            // Intentionally round down in case length isn't an exact multiple of McfgEntry size
-            // (see rust-osdev/acpi#58)
-            let num_entries = length / mem::size_of::<McfgEntry>();
-
-            unsafe {
-                let pointer = (mcfg as *const Mcfg as *const u8).add(mem::size_of::<Mcfg>())
-                    as *const McfgEntry;
-                slice::from_raw_parts(pointer, num_entries)
-            }
-        }
-
-        #[derive(Clone, Copy, Debug)]
-        #[repr(C, packed)]
-        pub struct McfgEntry {
-            base_address: u64,
-            pci_segment_group: u16,
-            bus_number_start: u8,
-            bus_number_end: u8,
-            _reserved: u32,
-        }
-
-        let xhci_base =
-            if let Some(mcfg) = unsafe { acpi_tables.get_sdt::<Mcfg>(Signature::MCFG).unwrap() } {
-                let mcfg = unsafe { mcfg.virtual_start.as_ref() };
-                let entries = entries(mcfg);
-                println!("MCFG entry count: {}", entries.len());
-                for entry in entries {
-                    println!(
-                        "bus 0x{:x}..=0x{:x}, addr {:x}",
-                        entry.bus_number_start, entry.bus_number_end, entry.base_address
-                    );
-                }
-                let base = entries[0].base_address;
-
-                let mut xhci_cfg_base = None;
-                // let mut pata_base = None;
-                for i in 0.. {
-                    let base = base + (i << 12);
-                    if base > 0xffffffff {
-                        break;
-                    }
-                    let base = base as *const u32;
-                    let info = unsafe { base.add(2).read() };
-                    if info != !0u32 && info != 0 {
-                        let class = info >> 24 & 0xFF;
-                        let subclass = info >> 16 & 0xFF;
-                        let function = info >> 8 & 0xFF;
-                        let revision = info & 0xFF;
-                        if xhci_cfg_base.is_none() && class == 0xC && subclass == 0x3 && function == 0x30 {
-                            xhci_cfg_base = Some(base);
-                        } else if /*pata_base.is_none() &&*/ class == 0x01 {
-                            println!("PCI device {:02x} {:02x} {:02x} {:02x}", class, subclass, function, revision);
-                            
-                        }
-                    }
-                }
-                let xhci_cfg_base = xhci_cfg_base.expect("No USB 3 controller found");
-
-                unsafe {
-                    let bar0 = xhci_cfg_base.add(4).read() as u64;
-                    let bar1 = xhci_cfg_base.add(5).read() as u64;
-                    bar1 << 32 | bar0
-                }
-            } else {
-                println!("PCIe not supported");
-
-                fn set_cfg_addr(addr: u32) {
-                    unsafe {
-                        asm!(
-                            "mov dx, 0xCF8",
-                            "out dx, eax",
-                            in("eax") addr,
-                            lateout("dx") _,
-                            options(nostack)
-                        );
-                    }
-                }
-
-                fn read_data() -> u32 {
-                    unsafe {
-                        let mut out;
-                        asm!(
-                            "mov dx, 0xCFC",
-                            "in eax, dx",
-                            out("eax") out,
-                            lateout("dx") _,
-                            options(nostack)
-                        );
-                        out
-                    }
-                }
-                fn write_data(data: u32) {
-                    unsafe {
-                        asm!(
-                            "mov dx, 0xCFC",
-                            "out dx, eax",
-                            in("eax") data,
-                            lateout("dx") _,
-                            options(nostack)
-                        );
-                    }
-                }
-
-                let bus = 0xC;
-                let slot = 0x3;
-                let func = 0x30;
-
-                let mut xhci_base = None;
-                for bus in 0..256 {
-                    for device in 0..32 {
-                        for function in 0..8 {
-                            let addr = 0x80000000 | bus << 16 | device << 11 | function << 8;
-                            set_cfg_addr(addr);
-                            let data = read_data();
-                            if data != 0xFFFFFFFF {
-                                // Device found!
-                                set_cfg_addr(addr | 0x8);
-                                let data = read_data();
-                                let class = data >> 24 & 0xFF;
-                                let subclass = data >> 16 & 0xFF;
-                                let function = data >> 8 & 0xFF;
-                                let revision = data & 0xFF;
-                                if class == 0xC && subclass == 0x3 && function == 0x30 {
-                                    // Found XHCI!
-                                    set_cfg_addr(addr | 0x10);
-                                    let bar0 = read_data() as u64;
-                                    let bar0 = bar0 & !0xFFF;
-                                    set_cfg_addr(addr | 0x14);
-                                    let bar1 = read_data() as u64;
-                                    xhci_base = Some(bar1 << 32 | bar0);
-                                    println!("xhci_base: {:x}", xhci_base.unwrap());
-                                    // loop {}
-                                } else if class == 0x01 {
-                                    println!("PCI device {:02x} {:02x} {:02x} {:02x}", class, subclass, function, revision);
-                                }
-                            }
-                        }
-                    }
-                }
-                xhci_base.expect("No USB 3 controller found")
-            };
-        loop {}
 
         // let fs_proto = system_table.boot_services().locate_protocol::<SimpleFileSystem>().unwrap().unwrap();
         let fs_proto = st
@@ -299,216 +96,148 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
         // This reference should never be used after another call to locate_protocol::<SimpleFileSystem>().
         let fs_proto = unsafe { fs_proto.get().as_mut().unwrap() };
         let mut root_dir = fs_proto.open_volume().unwrap_success();
-        let mut buffer = Vec::new();
-        let kernel_file_info = loop {
-            let buffer_size = root_dir.read_entry(&mut []).unwrap_err().data().unwrap();
-            buffer.resize(buffer_size, 0);
-            let entries = match root_dir.read_entry(&mut buffer).unwrap_success() {
-                Some(v) => v,
-                None => panic!("kernel.elf not found"),
-            };
-            if entries.attribute().contains(FileAttribute::DIRECTORY) {
-                println!("directory {}", entries.file_name());
-                continue;
-            }
-            println!("file {}", entries.file_name());
-            if entries.file_name().to_u16_slice() == to_utf16::<10>("kernel.elf") {
-                break entries;
-            }
-        };
 
-        let kernel_size = kernel_file_info.file_size();
-        let kernel_file = root_dir
-            .open("kernel.elf", FileMode::Read, FileAttribute::empty())
-            .unwrap_success();
-
-        let mut elf_buffer = alloc::vec![0; kernel_size as _];
-        let kernel_elf = match kernel_file.into_type().unwrap_success() {
-            FileType::Regular(mut f) => {
-                if f.read(&mut elf_buffer).unwrap_success() < kernel_size as _ {
-                    log::error!("Entire kernel file was not read at once");
-                    loop {
-                        x86_64::instructions::hlt();
-                    }
+        let config = match read_file(&mut root_dir, config::CONFIG_PATH) {
+            Some(text) => match Config::parse(&String::from_utf8_lossy(&text)) {
+                Ok(config) => config,
+                Err(e) => {
+                    warn!("{}: {}; using defaults", config::CONFIG_PATH, e);
+                    Config::default()
                 }
-                Elf::parse(&elf_buffer).unwrap()
-            }
-            FileType::Dir(_) => unreachable!(),
+            },
+            None => Config::default(),
         };
 
-        println!("Program header count: {}", kernel_elf.program_headers.len());
-        println!("Section header count: {}", kernel_elf.section_headers.len());
-
-        let mut lowest_base = u64::MAX;
-        for segment in &kernel_elf.program_headers {
-            if segment.entry_type == EntryType::Load {
-                lowest_base = lowest_base.min(segment.virtual_addr);
-            }
-        }
-
-        let mut kernel_addresses = Vec::new();
-
-        for segment in &kernel_elf.program_headers {
-            if segment.entry_type == EntryType::Load {
-                let virt_start = segment.virtual_addr;
-                let virt_end = virt_start + segment.mem_size;
-                let phys_start = segment.virtual_addr - lowest_base;
-                let phys_end = phys_start + segment.mem_size;
-                let mut align_mask = !0;
-                let mut t = segment.align;
-                while t > 1 {
-                    align_mask <<= 1;
-                    t >>= 1;
-                }
-                let aligned_virt_start = virt_start & align_mask;
-                let first_virt_page = aligned_virt_start >> 21; // 2M pages
-                let last_virt_page = virt_end >> 21;
-                let aligned_phys_start = phys_start & align_mask;
-                let first_phys_page = aligned_phys_start >> 21; // 2M pages
-                let last_phys_page = phys_end >> 21;
-
-                kernel_addresses.push((
-                    (first_virt_page, last_virt_page),
-                    (first_phys_page, last_phys_page),
-                ));
-            }
-        }
-
-        let mut has_changed = true;
-        while has_changed {
-            has_changed = false;
-            let mut i = 0;
-            while i < kernel_addresses.len() {
-                let mut j = i + 1;
-                while j < kernel_addresses.len() {
-                    let ((vstart1, vend1), (pstart1, pend1)) = kernel_addresses[i];
-                    let ((vstart2, vend2), (pstart2, pend2)) = kernel_addresses[j];
-
-                    // Possible cases:
-                    //  s-------e
-                    //      s------
-                    //
-                    //  s-------e
-                    // -----e
-                    //
-                    //   s------e
-                    // s-----------e
-                    //
-                    //  s-------e
-                    //              s-----e
-                    //
-                    // We assume vstart1 + C = pstart1 etc for all v.. and p.. pairs where C is constant
-                    // As such, comparisons between v.. will also hold for p..
-                    if
-                    // s------e
-                    //    s--
-                    vstart1 <= vstart2 && vend1 >= vstart2 ||
-                    // s-----e
-                    // ---e
-                    vstart1 <= vend2 && vend1 >= vend2 ||
-                    // s-----e
-                    //---------
-                    vstart1 >= vstart2 && vend1 <= vend2
-                    {
-                        let vfirst = vstart1.min(vstart2);
-                        let pfirst = pstart1.min(pstart2);
-                        let vlast = vend1.max(vend2);
-                        let plast = pend1.max(pend2);
-                        kernel_addresses.remove(j);
-                        kernel_addresses.remove(i);
-                        kernel_addresses.push(((vfirst, vlast), (pfirst, plast)));
-                        has_changed = true;
-                        continue;
+        // An entry that was started but never reported back is assumed broken, and stays out of the
+        // default spot until it boots again
+        let runtime_services =
+            unsafe { &*(st.runtime_services() as *const _ as *const efi::RuntimeServices) };
+        let saved_boot_state =
+            unsafe { BootState::read(runtime_services) }.unwrap_or_else(BootState::new);
+        let mut boot_state = saved_boot_state;
+        if boot_state.last_boot_failed() {
+            boot_state.failed = boot_state.pending;
+        }
+        let mut default = config.default;
+        let mut notice = None;
+        if let Some(failed) = config.entries.get(boot_state.failed as usize) {
+            if default == boot_state.failed as usize {
+                match fallback_entry(&config, &boot_state) {
+                    Some(fallback) => {
+                        default = fallback;
+                        notice = Some(format!(
+                            "{} did not finish booting; falling back to {}",
+                            failed.title, config.entries[fallback].title
+                        ));
+                    }
+                    None => {
+                        notice = Some(format!(
+                            "{} did not finish booting and there is no other entry to fall back to",
+                            failed.title
+                        ))
                     }
-                    j += 1;
                 }
-                i += 1;
             }
         }
+        let choice = menu::run(&st, &config, default, notice.as_deref());
+        let boot_entry = &config.entries[choice.entry];
+        boot_state.pending = choice.entry as u32;
+        // NVRAM wears out, so the variable is only written when it changes
+        let boot_state_result = if boot_state == saved_boot_state {
+            Ok(())
+        } else {
+            unsafe { boot_state.write(runtime_services) }
+        };
 
-        println!("2M page offset ranges to allocate (inclusive):");
-        for (_, (pstart, pend)) in &kernel_addresses {
-            println!("{} .. {}", pstart, pend);
+        let framebuffer = st
+            .boot_services()
+            .locate_protocol::<GraphicsOutput>()
+            .unwrap()
+            .unwrap();
+        let framebuffer = unsafe { framebuffer.get().as_mut().unwrap() };
+        select_video_mode(framebuffer, &config);
+        let current_mode = framebuffer.current_mode_info();
+        let pixel_format = current_mode.pixel_format();
+        let framebuffer = Framebuffer {
+            ptr: framebuffer.frame_buffer().as_mut_ptr() as _,
+            resolution_x: current_mode.resolution().0,
+            resolution_y: current_mode.resolution().1,
+            stride: current_mode.stride(),
+        };
+        logger::init_screen(framebuffer.clone(), config.verbosity);
+        debug!("Writer initialized");
+        if let Some(notice) = &notice {
+            warn!("{}", notice);
+        }
+        info!("Booting {} ({})", boot_entry.title, boot_entry.kernel_path);
+        if let Err(status) = boot_state_result {
+            warn!(
+                "Could not save the boot state (status {:#x}); fallback is disabled",
+                status
+            );
         }
 
-        let offset = 0x40_00_00;
-        let offset_page = offset >> 21;
-        println!("Actual 2M pages to allocate:");
-        for (_, (pstart, pend)) in &mut kernel_addresses {
-            *pstart += offset_page;
-            *pend += offset_page;
-            println!("{} .. {}", *pstart, *pend);
+        // The kernel finds its devices itself, starting from the ACPI tables
+        let mut rsdp_address = None;
+        for config_table in st.config_table() {
+            if config_table.guid == ACPI2_GUID {
+                rsdp_address = Some(config_table.address as u64);
+            } else if config_table.guid == ACPI_GUID && rsdp_address.is_none() {
+                // Only use the ACPI 1.0 RSDP if there is no 2.0 one
+                rsdp_address = Some(config_table.address as u64);
+            }
         }
 
-        for (_, (pstart, pend)) in &kernel_addresses {
-            let page_count = *pend - *pstart + 1;
-            st.boot_services()
-                .allocate_pages(
-                    AllocateType::Address((*pstart as usize) << 21),
-                    MemoryType::LOADER_DATA,
-                    (page_count as usize) * 512, /* 2M to 4K pages */
-                )
-                .unwrap()
-                .unwrap();
-
-            // Safety:
-            // - enough memory should have been allocated just above to contain buffer..buffer+size
-            // - size should be exactly how many bytes have been allocated
-            unsafe {
-                st.boot_services().memset(
-                    ((*pstart as usize) << 21) as *mut u8,
-                    page_count as usize * 2 * 1024 * 1024, /* 2M pages */
-                    0,
+        let elf_buffer = match read_file(&mut root_dir, &boot_entry.kernel_path) {
+            Some(buffer) => buffer,
+            None => panic!("Kernel image {} not found", boot_entry.kernel_path),
+        };
+        let kernel_elf = match Elf::parse(&elf_buffer) {
+            Ok(elf) => elf,
+            Err(e) => panic!("{} is not a valid kernel: {}", boot_entry.kernel_path, e),
+        };
+        let slide = if boot_entry.kaslr {
+            loader::choose_slide(&kernel_elf)
+        } else {
+            Ok(0)
+        };
+        let kernel =
+            match slide.and_then(|slide| loader::load(st.boot_services(), &kernel_elf, slide)) {
+                Ok(kernel) => kernel,
+                Err(e) => panic!("Could not load {}: {}", boot_entry.kernel_path, e),
+            };
+        info!(
+            "Kernel loaded at phys {:x}..{:x}, slid by {:x}, entry at {:x}",
+            kernel.phys_range.start, kernel.phys_range.end, kernel.slide, kernel.entry
+        );
+        let symbols = match loader::symbol_map(&kernel_elf, kernel.slide) {
+            Ok(symbols) => symbols,
+            Err(e) => {
+                warn!(
+                    "Kernel symbols unusable, backtraces won't be symbolized: {}",
+                    e
                 );
+                SymbolMap::empty()
             }
-        }
-
-        for segment in &kernel_elf.program_headers {
-            if segment.entry_type == EntryType::Load {
-                let start = segment.virtual_addr;
-                let actual_start = start + offset - lowest_base;
-                let mut align_mask = !0;
-                let mut t = segment.align;
-                while t > 1 {
-                    align_mask <<= 1;
-                    t >>= 1;
-                }
-                println!("Loading segment to {:x}", actual_start);
-
-                // Safety:
-                // - src is trivially valid for data.len() bytes
-                // - dst should be valid for data.len() bytes, as enough pages should have been allocated to contain it
-                unsafe {
-                    core::ptr::copy(
-                        segment.data.as_ptr(),
-                        actual_start as *mut u8,
-                        segment.data.len(),
+        };
+        let initrd = match boot_entry.initrd_path.as_str() {
+            "" => None,
+            path => match read_file(&mut root_dir, path) {
+                Some(data) => {
+                    let initrd = loader::load_initrd(st.boot_services(), &data);
+                    info!(
+                        "Initrd {} loaded at phys {:x}..{:x}",
+                        path, initrd.start, initrd.end
                     );
+                    Some(initrd)
                 }
-            }
-        }
-        let phys_entry = kernel_elf.entry + offset - lowest_base;
-        let virt_entry = kernel_elf.entry;
-        println!("Entry is at phys {:x} virt {:x}", phys_entry, virt_entry);
-
-        println!("Framebuffer: {:x}", framebuffer.ptr as u64);
-        println!("Kernel should have been loaded into memory.");
-        println!("First 8 bytes after jump (phys) are:");
-        unsafe {
-            let base = phys_entry as *const u8;
-            let a = base.read();
-            let b = base.add(1).read();
-            let c = base.add(2).read();
-            let d = base.add(3).read();
-            let e = base.add(4).read();
-            let f = base.add(5).read();
-            let g = base.add(6).read();
-            let h = base.add(7).read();
-            println!(
-                "{:x} {:x} {:x} {:x} {:x} {:x} {:x} {:x}",
-                a, b, c, d, e, f, g, h
-            );
+                None => {
+                    info!("Initrd {} not found, booting without one", path);
+                    None
+                }
+            },
         };
+        debug!("Framebuffer: {:x}", framebuffer.ptr as u64);
 
         // println!("Press any key to view memmap");
         // wait_for_key(&st);
@@ -516,7 +245,6 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
         let memory_map_size = st.boot_services().memory_map_size();
         let mut memory_map_buffer = Vec::new();
         memory_map_buffer.resize(memory_map_size + 256, 0);
-        let mut max_physical = 0;
         for entry in st
             .boot_services()
             .memory_map(&mut memory_map_buffer)
@@ -524,37 +252,67 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
             .unwrap()
             .1
         {
-            println!(
+            debug!(
                 "{:?} phys {:x} virt {:x} page_count {}",
                 entry.ty, entry.phys_start, entry.virt_start, entry.page_count
             );
-            if entry.phys_start + entry.page_count * 4096 > max_physical {
-                max_physical = entry.phys_start + entry.page_count * 4096;
-            }
         }
 
-        let frame_count = max_physical / 4096;
-        let allocated_frames = alloc::vec![0; (frame_count / 8) as usize];
+        // Options given when starting the bootloader by hand take precedence over the configuration
+        let command_line: &'static str = match read_load_options(&st, image_handle) {
+            "" => Box::leak(boot_entry.command_line.clone().into_boxed_str()),
+            options => options,
+        };
+        info!("Command line: {}", command_line);
 
         // println!("Press any key to continue");
         // wait_for_key(&st);
-        // #[cfg(feature = "wait_for_gdb")]
-        // println!("Will wait for GDB after jump");
+        if choice.wait_for_gdb {
+            info!("Will wait for GDB after jump");
+        }
         // println!("Press any key to jump to kernel");
         // wait_for_key(&system_table);
 
         // println!("Jump!");
 
+        let boot_time = match unsafe { runtime_services.get_time() } {
+            Ok(time) => {
+                let seconds = time.unix_seconds();
+                if seconds.is_none() {
+                    warn!("The firmware clock holds an invalid time: {:?}", time);
+                }
+                seconds
+            }
+            Err(status) => {
+                warn!("Could not read the firmware clock (status {:#x})", status);
+                None
+            }
+        };
+
         let machine_info = MachineInfo {
             framebuffer,
-            xhci_base,
-            allocated_frames: allocated_frames.leak(),
+            // Filled in after exiting boot services, as the memory map changes until then
+            memory_map: &[],
+            rsdp_address,
+            kernel_phys_range: kernel.phys_range.clone(),
+            kernel_slide: kernel.slide,
+            initrd,
+            command_line,
+            symbols,
+            // Filled in at the last moment, so it holds everything
+            boot_log: BootLog::empty(),
+            verbosity: config.verbosity,
+            runtime_services_address: Some(runtime_services as *const _ as u64),
+            boot_time,
         };
 
         (
-            unsafe { core::mem::transmute::<_, extern "sysv64" fn(MachineInfoC)>(virt_entry) },
+            unsafe {
+                core::mem::transmute::<_, extern "sysv64" fn(&MachineInfoC) -> !>(kernel.entry)
+            },
             machine_info,
-            kernel_addresses.leak(),
+            kernel.segments,
+            choice.wait_for_gdb,
         )
     };
 
@@ -565,6 +323,9 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
     let mut memmap: Vec<MemoryDescriptor> =
         Vec::with_capacity(vec_size / core::mem::size_of::<MemoryDescriptor>());
     memmapbuffer.resize(vec_size, 0);
+    // No allocations can be made after exiting boot services, so the memory map handed to the kernel
+    // needs all of its space up front.
+    let mut memory_regions: Vec<MemoryRegion> = Vec::with_capacity(memmap.capacity());
     let st = {
         let (st, memmap_iter) = st
             .exit_boot_services(image_handle, &mut memmapbuffer)
@@ -575,6 +336,14 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
 
     memmap.sort_unstable_by_key(|m| m.phys_start);
 
+    memory_regions.extend(memmap.iter().map(|m| MemoryRegion {
+        ty: MemoryRegionType(m.ty.0),
+        base: m.phys_start,
+        pages: m.page_count,
+        attributes: m.att.bits(),
+    }));
+    machine_info.memory_map = memory_regions.leak();
+
     unsafe {
         let code_segment = GDT.add_entry(Descriptor::kernel_code_segment());
         let data_segment = GDT.add_entry(Descriptor::kernel_data_segment());
@@ -630,19 +399,19 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
                 .iter()
                 .map(|m| {
                     if m.phys_start + m.page_count * _4K > 16 * _1G {
-                        println!("mem of type {:?}: {:x}", m.ty, m.phys_start);
+                        debug!("mem of type {:?}: {:x}", m.ty, m.phys_start);
                     }
                     m.phys_start + m.page_count * 4096
                 })
                 .max()
                 .unwrap();
             if machine_info.framebuffer.ptr as u64 > physical_cap {
-                println!("Framebuffer is above physical cap, extending physical cap...");
+                debug!("Framebuffer is above physical cap, extending physical cap...");
                 let framebuffer = &machine_info.framebuffer;
                 physical_cap = framebuffer.ptr as u64
                     + framebuffer.resolution_y as u64 * framebuffer.stride as u64;
             }
-            println!(
+            debug!(
                 "Physical cap: 0x{:x} : {} B, {} KB, {} MB, {} GB",
                 physical_cap,
                 physical_cap,
@@ -651,7 +420,7 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
                 physical_cap >> 30
             );
             let gig_pages = (physical_cap + _1G - 1) / _1G;
-            println!("1G pages: {}", gig_pages);
+            debug!("1G pages: {}", gig_pages);
 
             for (i, entry) in pdpt.iter_mut().take(gig_pages as _).enumerate() {
                 let pdt_addr = PAGE_ALLOCATOR.allocate_frame().unwrap().start_address();
@@ -665,14 +434,14 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
                 }
             }
 
-            println!("mapping used memory");
+            debug!("mapping used memory");
             for entry in &memmap {
                 match entry.ty {
                     MemoryType::CONVENTIONAL | MemoryType::RESERVED | MemoryType::UNUSABLE => {}
                     _ => {
                         let start = entry.phys_start;
                         let page_count = entry.page_count;
-                        println!(
+                        debug!(
                             "Entry to map: {:x}..{:x}",
                             start,
                             start + (page_count << 12)
@@ -683,52 +452,35 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
                                 pml4t,
                                 VirtAddr::new(page << 12),
                                 PhysFrame::from_start_address(PhysAddr::new(page << 12)).unwrap(),
+                                flags,
                             )
                             .unwrap();
-                            machine_info.allocated_frames[(page / 8) as usize] |= 1 << (page % 8);
                         }
                     }
                 }
             }
 
-            // println!("Marking framebuffer frames as allocated");
-            // let start_frame = machine_info.framebuffer.ptr as u64 >> 12;
-            // let last_frame = ((machine_info.framebuffer.resolution_y * machine_info.framebuffer.stride) >> 12) as u64 + start_frame;
-            // for frame in start_frame..=last_frame {
-            //     machine_info.allocated_frames[(frame / 8) as usize] |= 1 << (frame % 8);
-            // }
-
-            println!("mapping kernel");
-            for ((vstart, vend), (pstart, pend)) in kernel_addresses {
-                for (vpage, ppage) in (*vstart..=*vend).zip(*pstart..=*pend) {
-                    let idx4 = (vpage >> 18) as usize & 0x1FF;
-                    let idx3 = (vpage >> 9) as usize & 0x1FF;
-                    let idx2 = vpage as usize & 0x1FF;
-                    if pml4t[idx4].is_unused() {
-                        let pdpt_addr = PAGE_ALLOCATOR.allocate_frame().unwrap().start_address();
-                        pml4t[idx4].set_addr(pdpt_addr, flags);
-                    }
-                    let pdpt = pml4t[idx4].as_page_table_mut().unwrap();
-
-                    println!("mapping {:x} -> {:x}", vpage << 21, ppage << 21);
-
-                    if pdpt[idx3].is_unused() {
-                        let pdpt_addr = PAGE_ALLOCATOR.allocate_frame().unwrap().start_address();
-                        pdpt[idx3].set_addr(pdpt_addr, flags);
-                    }
-                    let pdt = pdpt[idx3].as_page_table_mut().unwrap();
-
-                    if !pdt[idx2].is_unused() {
-                        panic!("Tried mapping to already mapped page: {:x}", vstart);
-                    }
-
-                    pdt[idx2].set_addr(
-                        PhysAddr::new(ppage * _2M),
-                        flags | PageTableFlags::HUGE_PAGE,
-                    );
+            debug!("mapping kernel");
+            for segment in &kernel_segments {
+                let first_page = segment.virt_start & !(_4K - 1);
+                for vpage in (first_page..segment.virt_end).step_by(_4K as usize) {
+                    // The segment shares its offset within the page with its physical copy
+                    let ppage = (segment.phys_start & !(_4K - 1)) + (vpage - first_page);
+                    // Segments sharing a page would need the union of their permissions
+                    map(
+                        pml4t,
+                        VirtAddr::new(vpage),
+                        PhysFrame::containing_address(PhysAddr::new(ppage)),
+                        segment.flags,
+                    )
+                    .expect("Kernel segments share a page; check the alignment in link.ld");
                 }
+                debug!(
+                    "mapped {:x}..{:x} -> {:x} as {:?}",
+                    segment.virt_start, segment.virt_end, segment.phys_start, segment.flags
+                );
             }
-            println!("done mapping kernel");
+            debug!("done mapping kernel");
         }
 
         for entry in &mut memmap {
@@ -762,12 +514,12 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
         );
 
         let addr = PhysAddr::new((&PAGE_TABLE_4) as *const PageTable as u64);
-        println!("address of page table: {:x}", addr.as_u64());
+        debug!("address of page table: {:x}", addr.as_u64());
         // let frame: PhysFrame<Size4KiB> = PhysFrame::from_start_address(addr).unwrap();
         // writer::write_str("\n");
 
-        println!("cr4: {:x}", x86_64::registers::control::Cr4::read_raw());
-        println!(
+        debug!("cr4: {:x}", x86_64::registers::control::Cr4::read_raw());
+        debug!(
             "current cr3: {:x}",
             x86_64::registers::control::Cr3::read()
                 .0
@@ -787,47 +539,53 @@ fn efi_main(image_handle: Handle, st: SystemTable<Boot>) -> Status {
         // pml4t[0] = old_table[0].clone();
         // let frame = PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(old_table as *mut _ as u64)).unwrap();
 
-        println!(
+        debug!(
             "flags of old pml4e0.pdpe0.pde0: {:?}",
             old_table[0].as_page_table().unwrap()[0]
                 .as_page_table()
                 .unwrap()[0]
                 .flags()
         );
-        println!(
+        debug!(
             "flags of new pml4e0.pdpe0: {:?}",
             pml4t[0].as_page_table().unwrap()[0].flags()
         );
 
-        println!("aaaa");
         machine_info.framebuffer.ptr =
             (machine_info.framebuffer.ptr as u64 | idx2virt(511, 0, 0, 0).as_u64()) as _;
         common::writer::update_ptr(machine_info.framebuffer.ptr);
+        // NX would be a reserved bit without NXE, and without WP the kernel could still write to its
+        // read-only pages
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
         x86_64::registers::control::Cr3::write(
             frame,
             x86_64::registers::control::Cr3Flags::empty(),
         );
-        println!("Loading new page table succeeded");
+        debug!("Loading new page table succeeded");
     }
 
-    let machine_info = machine_info.into();
+    machine_info.boot_log = logger::boot_log();
+    let machine_info: MachineInfoC = machine_info.into();
 
-    wait_debug();
+    if wait_for_gdb {
+        wait_debug();
+    }
 
     // loop{}
 
-    entry(machine_info);
-
-    println!("DONE");
-
-    loop {
-        x86_64::instructions::hlt();
-    }
+    entry(&machine_info);
 }
 
-unsafe fn map(pml4: &mut PageTable, virt: VirtAddr, frame: PhysFrame) -> Result<(), &'static str> {
+unsafe fn map(
+    pml4: &mut PageTable,
+    virt: VirtAddr,
+    frame: PhysFrame,
+    page_flags: PageTableFlags,
+) -> Result<(), &'static str> {
     let (idx4, idx3, idx2, idx1) = virt2idx(virt);
 
+    // Permissions are only restricted in the last level
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
     if pml4[idx4].is_unused() {
@@ -853,7 +611,7 @@ unsafe fn map(pml4: &mut PageTable, virt: VirtAddr, frame: PhysFrame) -> Result<
     let pt = pd[idx2].as_page_table_mut().unwrap();
 
     if pt[idx1].is_unused() {
-        pt[idx1].set_frame(frame, flags);
+        pt[idx1].set_frame(frame, page_flags);
     } else {
         return Err("Trying to map to already existing page");
     }
@@ -996,6 +754,95 @@ where
 static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
+/// Returns the load options the image was started with, which serve as the kernel command line.
+/// The returned string is leaked, so it stays around for the kernel.
+fn read_load_options(st: &SystemTable<Boot>, image_handle: Handle) -> &'static str {
+    let loaded_image = st
+        .boot_services()
+        .handle_protocol::<LoadedImage>(image_handle)
+        .unwrap_success();
+    let loaded_image = unsafe { &*loaded_image.get() };
+    let mut buffer = alloc::vec![0; 1024];
+    match loaded_image.load_options(&mut buffer) {
+        Ok(options) => Box::leak(String::from(options.trim()).into_boxed_str()),
+        // Options that aren't text or don't fit aren't meant for us
+        Err(_) => "",
+    }
+}
+
+/// Picks the entry to boot in place of `state.failed`: the one configured for it, otherwise the
+/// most recent other entry that booted, otherwise any other entry.
+fn fallback_entry(config: &Config, state: &BootState) -> Option<usize> {
+    let failed = state.failed as usize;
+    config.entries[failed]
+        .fallback
+        .or_else(|| {
+            state
+                .good_entries(state.failed)
+                .map(|entry| entry as usize)
+                .find(|&entry| entry < config.entries.len())
+        })
+        .or_else(|| (0..config.entries.len()).find(|&entry| entry != failed))
+}
+
+/// Reads the whole file at `path`, or returns `None` if it can't be opened.
+fn read_file(root_dir: &mut Directory, path: &str) -> Option<Vec<u8>> {
+    let file = root_dir
+        .open(path, FileMode::Read, FileAttribute::empty())
+        .ok()?
+        .log();
+    let mut file = match file.into_type().unwrap_success() {
+        FileType::Regular(file) => file,
+        FileType::Dir(_) => return None,
+    };
+
+    file.set_position(RegularFile::END_OF_FILE).unwrap_success();
+    let size = file.get_position().unwrap_success() as usize;
+    file.set_position(0).unwrap_success();
+
+    let mut buffer = alloc::vec![0; size];
+    let mut read = 0;
+    while read < size {
+        match file.read(&mut buffer[read..]).unwrap_success() {
+            0 => panic!("{} ended after {} of {} bytes", path, read, size),
+            n => read += n,
+        }
+    }
+    Some(buffer)
+}
+
+/// Switches to the video mode the configuration asks for, if the firmware has it. Otherwise, the
+/// mode the firmware set up is kept.
+fn select_video_mode(gop: &mut GraphicsOutput, config: &Config) {
+    let usable = |mode: &Mode| mode.info().pixel_format() != PixelFormat::BltOnly;
+    let preferred = config.resolution.and_then(|resolution| {
+        gop.modes()
+            .map(|mode| mode.log())
+            .find(|mode| usable(mode) && mode.info().resolution() == resolution)
+    });
+    let mode = preferred.or_else(|| {
+        let (max_x, max_y) = config.max_resolution?;
+        gop.modes()
+            .map(|mode| mode.log())
+            .filter(|mode| {
+                let (x, y) = mode.info().resolution();
+                usable(mode) && x <= max_x && y <= max_y
+            })
+            .max_by_key(|mode| {
+                let (x, y) = mode.info().resolution();
+                x * y
+            })
+    });
+
+    if let Some(mode) = mode {
+        if mode.info().resolution() != gop.current_mode_info().resolution() {
+            gop.set_mode(&mode).unwrap_success();
+        }
+    } else if config.resolution.is_some() || config.max_resolution.is_some() {
+        warn!("No video mode matches the configuration; keeping the current one");
+    }
+}
+
 fn wait_for_key(st: &SystemTable<Boot>) {
     st.stdin().reset(false).unwrap().unwrap();
     st.boot_services()
@@ -1065,7 +912,7 @@ fn assert_mapped(pml4: &PageTable, addr: VirtAddr, write_success: bool) {
             .contains(PageTableFlags::HUGE_PAGE)
         {
             if write_success {
-                println!("addr {:x} is mapped", addr);
+                debug!("addr {:x} is mapped", addr);
             }
             return;
         }
@@ -1080,7 +927,7 @@ fn assert_mapped(pml4: &PageTable, addr: VirtAddr, write_success: bool) {
             .contains(PageTableFlags::HUGE_PAGE)
         {
             if write_success {
-                println!("addr {:x} is mapped", addr);
+                debug!("addr {:x} is mapped", addr);
             }
             return;
         }
@@ -1091,13 +938,13 @@ fn assert_mapped(pml4: &PageTable, addr: VirtAddr, write_success: bool) {
             panic!("addr {:x} not mapped in lvl1", addr);
         }
         if write_success {
-            println!("addr {:x} is mapped", addr);
+            debug!("addr {:x} is mapped", addr);
         }
     }
 }
 
+/// Spins until a debugger clears the loop register.
 fn wait_debug() {
-    #[cfg(feature = "wait_for_gdb")]
     unsafe {
         asm!(
             "mov {tmp}, 1",
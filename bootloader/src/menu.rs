@@ -0,0 +1,149 @@
+use core::fmt::Write;
+
+use uefi::{
+    prelude::*,
+    proto::console::text::{Key, ScanCode},
+    table::{Boot, SystemTable},
+};
+
+use crate::config::Config;
+
+/// How often per second the keyboard is polled while the menu is up.
+const TICKS_PER_SECOND: u32 = 10;
+
+/// What to boot, as picked in the menu.
+pub struct Choice {
+    pub entry: usize,
+    pub wait_for_gdb: bool,
+}
+
+/// Shows the boot menu on the UEFI text console until an entry is picked or the timeout runs out.
+///
+/// With a timeout of 0, `default` is booted right away unless a key is already waiting. Any key
+/// stops the countdown. `notice` is shown above the entries.
+pub fn run(
+    st: &SystemTable<Boot>,
+    config: &Config,
+    default: usize,
+    notice: Option<&str>,
+) -> Choice {
+    let mut selected = default;
+    let mut wait_for_gdb = config.entries[default].wait_for_gdb;
+    // In ticks; `None` once the user took over
+    let mut remaining = Some(config.timeout * TICKS_PER_SECOND);
+    if config.timeout == 0 {
+        match read_key(st) {
+            Some(_) => remaining = None,
+            None => {
+                return Choice {
+                    entry: selected,
+                    wait_for_gdb,
+                }
+            }
+        }
+    }
+
+    let count = config.entries.len();
+    let mut redraw = true;
+    loop {
+        if redraw {
+            draw(st, config, selected, wait_for_gdb, remaining, notice);
+            redraw = false;
+        }
+        match read_key(st) {
+            Some(key) => {
+                remaining = None;
+                redraw = true;
+                let previous = selected;
+                match key {
+                    Key::Special(code) if code == ScanCode::UP => {
+                        selected = (selected + count - 1) % count
+                    }
+                    Key::Special(code) if code == ScanCode::DOWN => {
+                        selected = (selected + 1) % count
+                    }
+                    Key::Printable(c) => match char::from(c) {
+                        '\r' | '\n' => break,
+                        'g' | 'G' => wait_for_gdb = !wait_for_gdb,
+                        c => match c.to_digit(10) {
+                            Some(digit) if digit >= 1 && digit as usize <= count => {
+                                selected = digit as usize - 1
+                            }
+                            _ => {}
+                        },
+                    },
+                    _ => {}
+                }
+                if selected != previous {
+                    wait_for_gdb = config.entries[selected].wait_for_gdb;
+                }
+            }
+            None => {
+                if remaining == Some(0) {
+                    break;
+                }
+                st.boot_services()
+                    .stall(1_000_000 / TICKS_PER_SECOND as usize);
+                if let Some(ticks) = &mut remaining {
+                    *ticks -= 1;
+                    redraw = *ticks % TICKS_PER_SECOND == 0;
+                }
+            }
+        }
+    }
+
+    st.stdout().clear().unwrap_success();
+    Choice {
+        entry: selected,
+        wait_for_gdb,
+    }
+}
+
+fn read_key(st: &SystemTable<Boot>) -> Option<Key> {
+    st.stdin().read_key().unwrap_success()
+}
+
+fn draw(
+    st: &SystemTable<Boot>,
+    config: &Config,
+    selected: usize,
+    wait_for_gdb: bool,
+    remaining: Option<u32>,
+    notice: Option<&str>,
+) {
+    let out = st.stdout();
+    out.clear().unwrap_success();
+    // The console can't fail in a way worth reporting anywhere else, so write errors are ignored
+    let _ = writeln!(out, "Boot menu\n");
+    if let Some(notice) = notice {
+        let _ = writeln!(out, "{}\n", notice);
+    }
+    for (i, entry) in config.entries.iter().enumerate() {
+        let marker = if i == selected { '>' } else { ' ' };
+        let _ = writeln!(
+            out,
+            "{} {}. {} ({})",
+            marker,
+            i + 1,
+            entry.title,
+            entry.kernel_path
+        );
+    }
+    let _ = writeln!(
+        out,
+        "\nWait for GDB: {}",
+        if wait_for_gdb { "on" } else { "off" }
+    );
+    let _ = writeln!(
+        out,
+        "Up/Down or 1-9 to choose, Enter to boot, G to toggle waiting for GDB"
+    );
+    if let Some(ticks) = remaining {
+        let _ = writeln!(
+            out,
+            "\nBooting {} in {} s",
+            config.entries[selected].title,
+            (ticks + TICKS_PER_SECOND - 1) / TICKS_PER_SECOND
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use core::fmt;
+
+/// Magic number at the start of every boot info block.
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"HHHBOOT\0");
+
+/// Version of the boot protocol, i.e. of the layout of [`MachineInfoC`](crate::MachineInfoC) and
+/// everything it points to. Bump this whenever any of it changes.
+pub const BOOT_PROTOCOL_VERSION: u32 = 9;
+
+/// Header at the start of the boot info the bootloader hands to the kernel, so the kernel can tell
+/// whether it was started by a bootloader speaking the same protocol.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BootInfoHeader {
+    pub magic: u64,
+    pub version: u32,
+    /// Size in bytes of the whole boot info block, header included.
+    pub size: u32,
+    /// FNV-1a hash of the whole boot info block, computed with this field set to zero.
+    pub checksum: u32,
+    _reserved: u32,
+}
+
+impl BootInfoHeader {
+    pub const fn new(size: u32) -> Self {
+        Self {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_PROTOCOL_VERSION,
+            size,
+            checksum: 0,
+            _reserved: 0,
+        }
+    }
+
+    /// Checks magic, version and size; the checksum needs the whole block and is checked by the
+    /// block itself.
+    pub fn validate(&self, expected_size: usize) -> Result<(), BootProtocolError> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err(BootProtocolError::BadMagic(self.magic));
+        }
+        if self.version != BOOT_PROTOCOL_VERSION {
+            return Err(BootProtocolError::VersionMismatch {
+                bootloader: self.version,
+                kernel: BOOT_PROTOCOL_VERSION,
+            });
+        }
+        if self.size as usize != expected_size {
+            return Err(BootProtocolError::SizeMismatch {
+                bootloader: self.size,
+                kernel: expected_size as u32,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum BootProtocolError {
+    BadMagic(u64),
+    VersionMismatch { bootloader: u32, kernel: u32 },
+    SizeMismatch { bootloader: u32, kernel: u32 },
+    BadChecksum { expected: u32, found: u32 },
+}
+
+impl fmt::Display for BootProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootProtocolError::BadMagic(magic) => {
+                write!(f, "bad boot info magic {:#018x}", magic)
+            }
+            BootProtocolError::VersionMismatch { bootloader, kernel } => write!(
+                f,
+                "bootloader speaks boot protocol version {}, kernel expects version {}; \
+                 rebuild and install both",
+                bootloader, kernel
+            ),
+            BootProtocolError::SizeMismatch { bootloader, kernel } => write!(
+                f,
+                "boot info is {} bytes, kernel expects {} bytes; rebuild and install both",
+                bootloader, kernel
+            ),
+            BootProtocolError::BadChecksum { expected, found } => write!(
+                f,
+                "boot info checksum is {:#010x}, but the contents hash to {:#010x}",
+                expected, found
+            ),
+        }
+    }
+}
+
+/// 32-bit FNV-1a hash.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash = 0x811C9DC5u32;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
@@ -0,0 +1,160 @@
+//! The bootloader's log, kept in a buffer that is handed to the kernel, so what scrolled by (or was
+//! never shown) can be looked at later.
+//!
+//! Each record is a level byte, the length of the message as two little-endian bytes, and the
+//! message itself in UTF-8.
+
+use core::fmt;
+
+const HEADER_SIZE: usize = 3;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Level::Error),
+            1 => Some(Level::Warn),
+            2 => Some(Level::Info),
+            3 => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Records messages into a buffer of `N` bytes. Once it is full, further messages are only
+/// counted; a message that only partly fits is cut short.
+pub struct LogBuffer<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    /// Start of the record being written, if it fit.
+    record: Option<usize>,
+    dropped: u64,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+            record: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, level: Level, args: fmt::Arguments) {
+        if N - self.len < HEADER_SIZE + 1 {
+            self.dropped += 1;
+            return;
+        }
+        let start = self.len;
+        self.buffer[start] = level as u8;
+        self.len += HEADER_SIZE;
+        self.record = Some(start);
+        // Writing never fails; whatever doesn't fit is left out
+        let _ = fmt::write(self, args);
+        let length = (self.len - start - HEADER_SIZE) as u16;
+        self.buffer[start + 1..start + HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+        self.record = None;
+    }
+
+    /// The records so far, for handing over to the kernel. The buffer must not move afterwards.
+    pub fn log(&self) -> BootLog<'_> {
+        BootLog {
+            bytes: &self.buffer[..self.len],
+            dropped: self.dropped,
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for LogBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = match self.record {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let room = (N - self.len).min(u16::MAX as usize - (self.len - start - HEADER_SIZE));
+        let mut end = s.len().min(room);
+        // Cut at a character boundary, so the message stays valid UTF-8
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buffer[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// A finished boot log, as the kernel receives it.
+#[derive(Clone, Copy)]
+pub struct BootLog<'a> {
+    pub bytes: &'a [u8],
+    /// Messages that didn't fit in the buffer.
+    pub dropped: u64,
+}
+
+impl<'a> BootLog<'a> {
+    pub const fn empty() -> Self {
+        Self {
+            bytes: &[],
+            dropped: 0,
+        }
+    }
+
+    pub fn records(&self) -> Records<'a> {
+        Records(self.bytes)
+    }
+}
+
+pub struct Records<'a>(&'a [u8]);
+
+impl<'a> Iterator for Records<'a> {
+    type Item = (Level, &'a str);
+
+    fn next(&mut self) -> Option<(Level, &'a str)> {
+        let header = self.0.get(..HEADER_SIZE)?;
+        let level = Level::from_u8(header[0]);
+        let length = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let message = self.0.get(HEADER_SIZE..HEADER_SIZE + length);
+        match (level, message) {
+            (Some(level), Some(message)) => {
+                self.0 = &self.0[HEADER_SIZE + length..];
+                Some((
+                    level,
+                    core::str::from_utf8(message).unwrap_or("<invalid UTF-8>"),
+                ))
+            }
+            // A corrupt log can't be followed any further
+            _ => {
+                self.0 = &[];
+                None
+            }
+        }
+    }
+}
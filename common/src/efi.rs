@@ -0,0 +1,344 @@
+//! Just enough of the raw UEFI runtime services table for the bootloader and the kernel to share
+//! variables. The kernel runs long after boot services are gone, so it can't use the `uefi` crate.
+
+use core::{ffi::c_void, mem::size_of, ptr};
+
+use crate::time::DateTime;
+
+pub type Status = usize;
+
+pub const SUCCESS: Status = 0;
+
+/// The variable survives reboots.
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+/// Vendor of the variables owned by this OS.
+pub const VENDOR_GUID: Guid = Guid {
+    data1: 0x6868_6842,
+    data2: 0x4f4f,
+    data3: 0x5453,
+    data4: [0x97, 0x3d, 0x51, 0x0c, 0x5e, 0x2b, 0x8a, 0x41],
+};
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    _reserved: u32,
+}
+
+/// `EFI_RUNTIME_SERVICES`. Services nobody calls yet are left as bare pointers.
+#[repr(C)]
+pub struct RuntimeServices {
+    pub header: TableHeader,
+    get_time: unsafe extern "efiapi" fn(time: *mut Time, capabilities: *mut c_void) -> Status,
+    _set_time: usize,
+    _get_wakeup_time: usize,
+    _set_wakeup_time: usize,
+    _set_virtual_address_map: usize,
+    _convert_pointer: usize,
+    get_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut c_void,
+    ) -> Status,
+    _get_next_variable_name: usize,
+    set_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: u32,
+        data_size: usize,
+        data: *const c_void,
+    ) -> Status,
+    _get_next_high_monotonic_count: usize,
+    _reset_system: usize,
+}
+
+/// `EFI_TIME`: the firmware's clock, in whatever time zone it keeps.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    /// Offset from UTC in minutes, or [`UNSPECIFIED_TIMEZONE`].
+    pub time_zone: i16,
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+/// The firmware doesn't know which time zone its clock is in.
+pub const UNSPECIFIED_TIMEZONE: i16 = 0x07FF;
+
+impl Time {
+    /// The time in UTC, as seconds since the Unix epoch, or `None` if the firmware returned
+    /// garbage. A time without a time zone is taken to be UTC already. The offset is applied as
+    /// local time = UTC + offset, as current versions of the specification define it.
+    pub fn unix_seconds(&self) -> Option<u64> {
+        let local = DateTime {
+            year: self.year,
+            month: self.month,
+            day: self.day,
+            hour: self.hour,
+            minute: self.minute,
+            second: self.second,
+        };
+        if !local.is_valid() {
+            return None;
+        }
+        let offset = match self.time_zone {
+            UNSPECIFIED_TIMEZONE => 0,
+            minutes if (-1440..=1440).contains(&minutes) => minutes as i64 * 60,
+            _ => return None,
+        };
+        let utc = local.unix_seconds() as i64 - offset;
+        if utc < 0 {
+            None
+        } else {
+            Some(utc as u64)
+        }
+    }
+}
+
+impl RuntimeServices {
+    /// Reads the firmware's clock.
+    ///
+    /// # Safety
+    /// Same as [`get_variable`](Self::get_variable).
+    pub unsafe fn get_time(&self) -> Result<Time, Status> {
+        let mut time = Time::default();
+        match (self.get_time)(&mut time, ptr::null_mut()) {
+            SUCCESS => Ok(time),
+            status => Err(status),
+        }
+    }
+
+    /// Reads the variable `name` (null-terminated UCS-2) into `data`, returning its size.
+    ///
+    /// # Safety
+    /// The table must be reachable at its current address, and the firmware must not be running
+    /// on another CPU.
+    pub unsafe fn get_variable(
+        &self,
+        name: &[u16],
+        vendor: &Guid,
+        data: &mut [u8],
+    ) -> Result<usize, Status> {
+        let mut size = data.len();
+        match (self.get_variable)(
+            name.as_ptr(),
+            vendor,
+            ptr::null_mut(),
+            &mut size,
+            data.as_mut_ptr() as *mut c_void,
+        ) {
+            SUCCESS => Ok(size),
+            status => Err(status),
+        }
+    }
+
+    /// Writes the variable `name` (null-terminated UCS-2). An empty `data` deletes it.
+    ///
+    /// # Safety
+    /// Same as [`get_variable`](Self::get_variable).
+    pub unsafe fn set_variable(
+        &self,
+        name: &[u16],
+        vendor: &Guid,
+        attributes: u32,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        match (self.set_variable)(
+            name.as_ptr(),
+            vendor,
+            attributes,
+            data.len(),
+            data.as_ptr() as *const c_void,
+        ) {
+            SUCCESS => Ok(()),
+            status => Err(status),
+        }
+    }
+}
+
+/// Converts an ASCII string to null-terminated UCS-2; `N` must be its length plus one.
+pub const fn ucs2<const N: usize>(s: &str) -> [u16; N] {
+    let bytes = s.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        out[i] = bytes[i] as u16;
+        i += 1;
+    }
+    out
+}
+
+const BOOT_STATE_NAME: [u16; 13] = ucs2("HhhBootState");
+
+/// Which boot menu entry is being tried and which ones booted all the way.
+///
+/// The bootloader sets `pending` right before jumping to a kernel, and the kernel moves it to
+/// `last_good` once it is up. A `pending` entry found at startup therefore never finished booting;
+/// the bootloader records it as `failed` and stops defaulting to it until it boots again.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootState {
+    pub pending: u32,
+    pub last_good: u32,
+    /// The entry that booted before `last_good`, if it is a different one.
+    pub previous_good: u32,
+    pub failed: u32,
+}
+
+impl BootState {
+    /// Stands in for "no entry".
+    pub const NONE: u32 = u32::MAX;
+
+    pub const fn new() -> Self {
+        Self {
+            pending: Self::NONE,
+            last_good: Self::NONE,
+            previous_good: Self::NONE,
+            failed: Self::NONE,
+        }
+    }
+
+    /// # Safety
+    /// See [`RuntimeServices::get_variable`].
+    pub unsafe fn read(rt: &RuntimeServices) -> Option<Self> {
+        let mut state = Self::new();
+        let data =
+            core::slice::from_raw_parts_mut(&mut state as *mut Self as *mut u8, size_of::<Self>());
+        match rt.get_variable(&BOOT_STATE_NAME, &VENDOR_GUID, data) {
+            Ok(size) if size == size_of::<Self>() => Some(state),
+            _ => None,
+        }
+    }
+
+    /// # Safety
+    /// See [`RuntimeServices::set_variable`].
+    pub unsafe fn write(&self, rt: &RuntimeServices) -> Result<(), Status> {
+        let data = core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>());
+        rt.set_variable(
+            &BOOT_STATE_NAME,
+            &VENDOR_GUID,
+            VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS,
+            data,
+        )
+    }
+
+    /// Whether the last attempt to boot an entry never reported success.
+    pub fn last_boot_failed(&self) -> bool {
+        self.pending != Self::NONE
+    }
+
+    /// Entries that booted, most recent first, leaving out `except`.
+    pub fn good_entries(&self, except: u32) -> impl Iterator<Item = u32> {
+        core::iter::once(self.last_good)
+            .chain(core::iter::once(self.previous_good))
+            .filter(move |&entry| entry != Self::NONE && entry != except)
+    }
+
+    /// Marks the pending entry as booted.
+    pub fn succeeded(self) -> Self {
+        if self.pending == Self::NONE {
+            return self;
+        }
+        Self {
+            pending: Self::NONE,
+            last_good: self.pending,
+            previous_good: if self.pending == self.last_good {
+                self.previous_good
+            } else {
+                self.last_good
+            },
+            failed: if self.failed == self.pending {
+                Self::NONE
+            } else {
+                self.failed
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u8, minute: u8, time_zone: i16) -> Time {
+        Time {
+            year: 2021,
+            month: 1,
+            day: 1,
+            hour,
+            minute,
+            time_zone,
+            ..Time::default()
+        }
+    }
+
+    const NEW_YEAR_2021: u64 = 1_609_459_200;
+
+    #[test]
+    fn unspecified_time_zone_is_utc() {
+        assert_eq!(
+            time(0, 0, UNSPECIFIED_TIMEZONE).unix_seconds(),
+            Some(NEW_YEAR_2021)
+        );
+        assert_eq!(time(0, 0, 0).unix_seconds(), Some(NEW_YEAR_2021));
+    }
+
+    #[test]
+    fn time_zone_offset_is_subtracted() {
+        // 02:00 at UTC+2 and 19:00 the day before at UTC-5 are both midnight UTC
+        assert_eq!(time(2, 0, 120).unix_seconds(), Some(NEW_YEAR_2021));
+        let evening = Time {
+            year: 2020,
+            month: 12,
+            day: 31,
+            ..time(19, 0, -300)
+        };
+        assert_eq!(evening.unix_seconds(), Some(NEW_YEAR_2021));
+        assert_eq!(time(5, 30, 330).unix_seconds(), Some(NEW_YEAR_2021));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(time(0, 0, 1441).unix_seconds(), None);
+        assert_eq!(time(24, 0, 0).unix_seconds(), None);
+    }
+
+    #[test]
+    fn rejects_times_before_the_epoch_in_utc() {
+        let after_epoch = Time {
+            year: 1970,
+            ..time(0, 0, -60)
+        };
+        assert_eq!(after_epoch.unix_seconds(), Some(3600));
+        let before_epoch = Time {
+            year: 1970,
+            ..time(0, 0, 60)
+        };
+        assert_eq!(before_epoch.unix_seconds(), None);
+    }
+}
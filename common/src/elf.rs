@@ -0,0 +1,1059 @@
+//! Parsing and validating ELF64 images, and laying them out in memory with their relocations
+//! applied.
+
+use alloc::vec::Vec;
+use core::{convert::TryInto, fmt, ops::Range};
+
+pub struct Elf<'a> {
+    pub abi: Abi,
+    pub object_type: ObjectType,
+    pub entry: u64,
+    pub program_headers: Vec<HeaderEntry<'a>>,
+    pub section_headers: Vec<SectionEntry<'a>>,
+    pub flags: u32,
+    pub section_name_section_index: usize,
+}
+
+/// Everything that can be wrong with an ELF file. Offsets are file offsets, addresses are the
+/// virtual addresses the file was linked at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// `size` bytes of `what` at `offset` don't fit in the file.
+    OutOfBounds {
+        what: &'static str,
+        offset: u64,
+        size: u64,
+    },
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    UnknownObjectType(u16),
+    WrongMachine(u16),
+    /// A header is smaller than the structure it should hold.
+    BadHeaderSize {
+        what: &'static str,
+        size: u64,
+    },
+    UnknownSegmentType(u32),
+    UnknownSectionType(u32),
+    BadSectionName(u32),
+    /// A segment holds more bytes in the file than in memory.
+    SegmentFileSizeTooBig(u64),
+    /// A segment's alignment isn't a power of two, or its address and file offset disagree on it.
+    BadSegmentAlignment(u64),
+    /// A segment wraps around the end of the address space.
+    SegmentTooBig(u64),
+    SegmentsOverlap(u64, u64),
+    NoLoadableSegments,
+    /// The entry point isn't in an executable segment.
+    EntryOutsideText(u64),
+    /// The dynamic section points at relocations that aren't in the file.
+    BadDynamicSection,
+    UnsupportedRelocation {
+        kind: u32,
+        offset: u64,
+    },
+    /// A relocation would write outside the loaded image.
+    RelocationOutOfBounds(u64),
+    /// The image needs to be moved, but it has no relocation information.
+    NotRelocatable,
+    /// The symbol table or its string table is malformed.
+    BadSymbolTable,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::OutOfBounds { what, offset, size } => write!(
+                f,
+                "{} ({} bytes at offset {:#x}) runs past the end of the file",
+                what, size, offset
+            ),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::NotElf64 => write!(f, "not a 64-bit ELF file"),
+            ElfError::NotLittleEndian => write!(f, "not a little-endian ELF file"),
+            ElfError::BadVersion => write!(f, "unknown ELF version"),
+            ElfError::UnknownObjectType(ty) => write!(f, "unknown object type {:#x}", ty),
+            ElfError::WrongMachine(machine) => {
+                write!(f, "built for machine {:#x} instead of x86_64", machine)
+            }
+            ElfError::BadHeaderSize { what, size } => {
+                write!(f, "{} size of {} bytes is too small", what, size)
+            }
+            ElfError::UnknownSegmentType(ty) => write!(f, "unknown segment type {:#x}", ty),
+            ElfError::UnknownSectionType(ty) => write!(f, "unknown section type {:#x}", ty),
+            ElfError::BadSectionName(offset) => {
+                write!(
+                    f,
+                    "section name at string table offset {:#x} is invalid",
+                    offset
+                )
+            }
+            ElfError::SegmentFileSizeTooBig(addr) => write!(
+                f,
+                "segment at {:#x} is bigger in the file than in memory",
+                addr
+            ),
+            ElfError::BadSegmentAlignment(addr) => {
+                write!(f, "segment at {:#x} is misaligned", addr)
+            }
+            ElfError::SegmentTooBig(addr) => {
+                write!(f, "segment at {:#x} runs past the end of memory", addr)
+            }
+            ElfError::SegmentsOverlap(first, second) => {
+                write!(f, "segments at {:#x} and {:#x} overlap", first, second)
+            }
+            ElfError::NoLoadableSegments => write!(f, "no loadable segments"),
+            ElfError::EntryOutsideText(entry) => write!(
+                f,
+                "entry point {:#x} is not in an executable segment",
+                entry
+            ),
+            ElfError::BadDynamicSection => write!(f, "dynamic section is malformed"),
+            ElfError::UnsupportedRelocation { kind, offset } => write!(
+                f,
+                "relocation of unsupported type {} at {:#x}",
+                kind, offset
+            ),
+            ElfError::RelocationOutOfBounds(offset) => {
+                write!(f, "relocation at {:#x} is outside the image", offset)
+            }
+            ElfError::NotRelocatable => write!(f, "image has no relocation information"),
+            ElfError::BadSymbolTable => write!(f, "symbol table is malformed"),
+        }
+    }
+}
+
+/// Returns `size` bytes of `data` at `offset`, or an error naming `what` if they aren't there.
+fn bytes<'a>(
+    data: &'a [u8],
+    offset: u64,
+    size: u64,
+    what: &'static str,
+) -> Result<&'a [u8], ElfError> {
+    match offset.checked_add(size) {
+        Some(end) if end <= data.len() as u64 => Ok(&data[offset as usize..end as usize]),
+        _ => Err(ElfError::OutOfBounds { what, offset, size }),
+    }
+}
+
+fn u16_at(data: &[u8], offset: u64, what: &'static str) -> Result<u16, ElfError> {
+    Ok(u16::from_le_bytes(
+        bytes(data, offset, 2, what)?.try_into().unwrap(),
+    ))
+}
+
+fn u32_at(data: &[u8], offset: u64, what: &'static str) -> Result<u32, ElfError> {
+    Ok(u32::from_le_bytes(
+        bytes(data, offset, 4, what)?.try_into().unwrap(),
+    ))
+}
+
+fn u64_at(data: &[u8], offset: u64, what: &'static str) -> Result<u64, ElfError> {
+    Ok(u64::from_le_bytes(
+        bytes(data, offset, 8, what)?.try_into().unwrap(),
+    ))
+}
+
+/// Granularity segments are loaded at.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Size of the file header, and the smallest program and section headers we can read.
+const FILE_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 0x38;
+const SECTION_HEADER_SIZE: u64 = 0x40;
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let ident = bytes(data, 0, 16, "identification")?;
+        if ident[0..4] != [0x7F, 0x45, 0x4C, 0x46] {
+            return Err(ElfError::BadMagic);
+        }
+        // 32- or 64-bit
+        if ident[4] != 2 {
+            return Err(ElfError::NotElf64);
+        }
+        if ident[5] != 1 {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if ident[6] != 1 {
+            return Err(ElfError::BadVersion);
+        }
+
+        let abi = if ident[7] == 0 {
+            Abi::SystemV
+        } else {
+            Abi::Other
+        };
+        bytes(data, 0, FILE_HEADER_SIZE, "file header")?;
+        let object_type = match u16_at(data, 0x10, "file header")? {
+            0 => ObjectType::None,
+            1 => ObjectType::Rel,
+            2 => ObjectType::Exec,
+            3 => ObjectType::Dyn,
+            4 => ObjectType::Core,
+            0xFE00..=0xFFFF => ObjectType::Other,
+            ty => return Err(ElfError::UnknownObjectType(ty)),
+        };
+
+        let machine = u16_at(data, 0x12, "file header")?;
+        if machine != 0x3E {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        // Version, again
+        if u32_at(data, 0x14, "file header")? != 1 {
+            return Err(ElfError::BadVersion);
+        }
+
+        let entry = u64_at(data, 0x18, "file header")?;
+        let program_headers_offset = u64_at(data, 0x20, "file header")?;
+        let section_headers_offset = u64_at(data, 0x28, "file header")?;
+        let flags = u32_at(data, 0x30, "file header")?;
+
+        let header_size = u16_at(data, 0x34, "file header")? as u64;
+        if header_size != FILE_HEADER_SIZE {
+            return Err(ElfError::BadHeaderSize {
+                what: "file header",
+                size: header_size,
+            });
+        }
+
+        let program_header_size = u16_at(data, 0x36, "file header")? as u64;
+        let program_header_count = u16_at(data, 0x38, "file header")? as u64;
+        let section_header_size = u16_at(data, 0x3A, "file header")? as u64;
+        let section_header_count = u16_at(data, 0x3C, "file header")? as u64;
+        let section_name_section_index = u16_at(data, 0x3E, "file header")? as usize;
+
+        if program_header_count > 0 && program_header_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadHeaderSize {
+                what: "program header",
+                size: program_header_size,
+            });
+        }
+        if section_header_count > 0 && section_header_size < SECTION_HEADER_SIZE {
+            return Err(ElfError::BadHeaderSize {
+                what: "section header",
+                size: section_header_size,
+            });
+        }
+
+        let mut program_headers = Vec::with_capacity(program_header_count as _);
+        for i in 0..program_header_count {
+            // The product can't overflow, both factors are 16-bit
+            let offset = program_headers_offset
+                .checked_add(program_header_size * i)
+                .ok_or(ElfError::OutOfBounds {
+                    what: "program header",
+                    offset: program_headers_offset,
+                    size: program_header_size,
+                })?;
+            let header = bytes(data, offset, PROGRAM_HEADER_SIZE, "program header")?;
+            let entry_type = match u32_at(header, 0, "program header")? {
+                0 => EntryType::None,
+                1 => EntryType::Load,
+                2 => EntryType::Dynamic,
+                3 => EntryType::Interp,
+                4 => EntryType::Note,
+                5 => EntryType::Shlib,
+                6 => EntryType::Phdr,
+                7 => EntryType::Tls,
+                0x60000000..=0x7FFFFFFF => EntryType::Other,
+                ty => return Err(ElfError::UnknownSegmentType(ty)),
+            };
+            let flags = u32_at(header, 0x4, "program header")?;
+            let data_offset = u64_at(header, 0x8, "program header")?;
+            let virtual_addr = u64_at(header, 0x10, "program header")?;
+            let physical_addr = u64_at(header, 0x18, "program header")?;
+            let file_size = u64_at(header, 0x20, "program header")?;
+            let mem_size = u64_at(header, 0x28, "program header")?;
+            let align = u64_at(header, 0x30, "program header")?;
+
+            if entry_type == EntryType::Load {
+                if file_size > mem_size {
+                    return Err(ElfError::SegmentFileSizeTooBig(virtual_addr));
+                }
+                if virtual_addr.checked_add(mem_size).is_none() {
+                    return Err(ElfError::SegmentTooBig(virtual_addr));
+                }
+                if align > 1
+                    && (!align.is_power_of_two() || virtual_addr % align != data_offset % align)
+                {
+                    return Err(ElfError::BadSegmentAlignment(virtual_addr));
+                }
+            }
+
+            program_headers.push(HeaderEntry {
+                entry_type,
+                flags,
+                data: bytes(data, data_offset, file_size, "segment data")?,
+                virtual_addr,
+                physical_addr,
+                mem_size,
+                align,
+            });
+        }
+
+        let mut section_headers = Vec::with_capacity(section_header_count as _);
+        let mut name_offsets = Vec::with_capacity(section_header_count as _);
+        for i in 0..section_header_count {
+            let offset = section_headers_offset
+                .checked_add(section_header_size * i)
+                .ok_or(ElfError::OutOfBounds {
+                    what: "section header",
+                    offset: section_headers_offset,
+                    size: section_header_size,
+                })?;
+            let header = bytes(data, offset, SECTION_HEADER_SIZE, "section header")?;
+            let section_type = match u32_at(header, 4, "section header")? {
+                0 => SectionType::None,
+                1 => SectionType::Progbits,
+                2 => SectionType::Symtab,
+                3 => SectionType::Strtab,
+                4 => SectionType::Rela,
+                5 => SectionType::Hash,
+                6 => SectionType::Dynamic,
+                7 => SectionType::Note,
+                8 => SectionType::Nobits,
+                9 => SectionType::Rel,
+                11 => SectionType::Dynsym,
+                14 => SectionType::InitArray,
+                15 => SectionType::FiniArray,
+                16 => SectionType::PreinitArray,
+                17 => SectionType::Group,
+                18 => SectionType::SymtabShndx,
+                19 => SectionType::Num,
+                o @ 0x60000000..=0xFFFFFFFF => SectionType::Other(o),
+                ty => return Err(ElfError::UnknownSectionType(ty)),
+            };
+            let flags = u64_at(header, 0x8, "section header")?;
+            let virtual_addr = u64_at(header, 0x10, "section header")?;
+            let data_offset = u64_at(header, 0x18, "section header")?;
+            let file_size = u64_at(header, 0x20, "section header")?;
+            let link = u32_at(header, 0x28, "section header")?;
+            let info = u32_at(header, 0x2C, "section header")?;
+            let align = u64_at(header, 0x30, "section header")?;
+            let entry_size = u64_at(header, 0x38, "section header")?;
+
+            let data = match section_type {
+                // Takes up memory, but no room in the file
+                SectionType::Nobits | SectionType::None => &[],
+                _ => bytes(data, data_offset, file_size, "section data")?,
+            };
+            name_offsets.push(u32_at(header, 0, "section header")?);
+            section_headers.push(SectionEntry {
+                name: "",
+                section_type,
+                flags,
+                data,
+                virtual_addr,
+                link,
+                info,
+                align,
+                entry_size,
+            });
+        }
+
+        if let Some(names) = section_headers.get(section_name_section_index) {
+            let names = names.data;
+            for (section, &offset) in section_headers.iter_mut().zip(&name_offsets) {
+                section.name = string_at(names, offset).ok_or(ElfError::BadSectionName(offset))?;
+            }
+        }
+
+        Ok(Elf {
+            abi,
+            object_type,
+            entry,
+            program_headers,
+            section_headers,
+            flags,
+            section_name_section_index,
+        })
+    }
+
+    /// The segments that get loaded into memory, sorted by address. Checks that there is at least
+    /// one, that they don't overlap and that the entry point is in one of them.
+    pub fn loadable_segments(&self) -> Result<Vec<&HeaderEntry<'a>>, ElfError> {
+        let mut segments: Vec<_> = self
+            .program_headers
+            .iter()
+            .filter(|segment| segment.entry_type == EntryType::Load && segment.mem_size > 0)
+            .collect();
+        segments.sort_unstable_by_key(|segment| segment.virtual_addr);
+        if segments.is_empty() {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        for pair in segments.windows(2) {
+            if pair[0].virtual_addr + pair[0].mem_size > pair[1].virtual_addr {
+                return Err(ElfError::SegmentsOverlap(
+                    pair[0].virtual_addr,
+                    pair[1].virtual_addr,
+                ));
+            }
+        }
+        if !segments
+            .iter()
+            .any(|segment| segment.is_executable() && segment.contains(self.entry))
+        {
+            return Err(ElfError::EntryOutsideText(self.entry));
+        }
+        Ok(segments)
+    }
+
+    /// The page-aligned range of linked addresses the loadable segments take up, gaps included.
+    pub fn image_range(&self) -> Result<Range<u64>, ElfError> {
+        let segments = self.loadable_segments()?;
+        let last = segments[segments.len() - 1];
+        let end = last.virtual_addr + last.mem_size;
+        let end = end
+            .checked_add(PAGE_SIZE - 1)
+            .ok_or(ElfError::SegmentTooBig(last.virtual_addr))?
+            & !(PAGE_SIZE - 1);
+        Ok(segments[0].virtual_addr & !(PAGE_SIZE - 1)..end)
+    }
+
+    /// Lays the loadable segments out in `image`, which stands for [`Elf::image_range`], zeroes
+    /// everything the file doesn't hold and applies the relocations for an image moved by `slide`.
+    pub fn load_into(&self, image: &mut [u8], slide: u64) -> Result<(), ElfError> {
+        let segments = self.loadable_segments()?;
+        let relocations = self.relocations()?;
+        if slide != 0 && relocations.is_empty() {
+            return Err(ElfError::NotRelocatable);
+        }
+        let range = self.image_range()?;
+        assert_eq!(
+            image.len() as u64,
+            range.end - range.start,
+            "Image buffer doesn't match the image size"
+        );
+
+        image.fill(0);
+        for segment in &segments {
+            let start = (segment.virtual_addr - range.start) as usize;
+            image[start..start + segment.data.len()].copy_from_slice(segment.data);
+        }
+
+        for relocation in &relocations {
+            match relocation.kind {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    if !segments.iter().any(|segment| {
+                        segment.contains(relocation.offset)
+                            && segment.contains(relocation.offset.wrapping_add(7))
+                    }) {
+                        return Err(ElfError::RelocationOutOfBounds(relocation.offset));
+                    }
+                    let value = (relocation.addend as u64).wrapping_add(slide);
+                    let start = (relocation.offset - range.start) as usize;
+                    image[start..start + 8].copy_from_slice(&value.to_le_bytes());
+                }
+                kind => {
+                    return Err(ElfError::UnsupportedRelocation {
+                        kind,
+                        offset: relocation.offset,
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the image can be loaded at another address than it was linked at.
+    pub fn is_relocatable(&self) -> bool {
+        self.object_type == ObjectType::Dyn
+    }
+
+    /// The relocations listed in the dynamic section. An image without a dynamic section has none.
+    pub fn relocations(&self) -> Result<Vec<Relocation>, ElfError> {
+        let dynamic = match self
+            .program_headers
+            .iter()
+            .find(|segment| segment.entry_type == EntryType::Dynamic)
+        {
+            Some(dynamic) => dynamic,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut table = None;
+        let mut table_size = 0;
+        let mut entry_size = RELA_ENTRY_SIZE;
+        for entry in dynamic.data.chunks_exact(16) {
+            let tag = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let value = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            match tag {
+                DT_NULL => break,
+                DT_RELA => table = Some(value),
+                DT_RELASZ => table_size = value,
+                DT_RELAENT => entry_size = value,
+                _ => {}
+            }
+        }
+        let table = match table {
+            Some(address) => self
+                .file_data_at(address, table_size)
+                .ok_or(ElfError::BadDynamicSection)?,
+            None => return Ok(Vec::new()),
+        };
+        if entry_size < RELA_ENTRY_SIZE {
+            return Err(ElfError::BadDynamicSection);
+        }
+
+        let mut relocations = Vec::with_capacity(table.len() / entry_size as usize);
+        for entry in table.chunks_exact(entry_size as usize) {
+            let info = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            relocations.push(Relocation {
+                offset: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                kind: info as u32,
+                symbol: (info >> 32) as u32,
+                addend: i64::from_le_bytes(entry[16..24].try_into().unwrap()),
+            });
+        }
+        Ok(relocations)
+    }
+
+    /// The entries of the symbol table. An image without one (a stripped one, say) has none.
+    pub fn symbols(&self) -> Result<Vec<ElfSymbol<'a>>, ElfError> {
+        let table = match self
+            .section_headers
+            .iter()
+            .find(|section| matches!(section.section_type, SectionType::Symtab))
+        {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        let names = self
+            .section_headers
+            .get(table.link as usize)
+            .ok_or(ElfError::BadSymbolTable)?
+            .data;
+        if table.entry_size < SYMBOL_ENTRY_SIZE {
+            return Err(ElfError::BadSymbolTable);
+        }
+
+        let mut symbols = Vec::with_capacity(table.data.len() / table.entry_size as usize);
+        for entry in table.data.chunks_exact(table.entry_size as usize) {
+            let name = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            symbols.push(ElfSymbol {
+                name: string_at(names, name).ok_or(ElfError::BadSymbolTable)?,
+                kind: entry[4] & 0xF,
+                section: u16::from_le_bytes(entry[6..8].try_into().unwrap()),
+                value: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                size: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+            });
+        }
+        Ok(symbols)
+    }
+
+    /// The `size` bytes the file holds for the loaded address `address`.
+    fn file_data_at(&self, address: u64, size: u64) -> Option<&'a [u8]> {
+        let segment = self
+            .program_headers
+            .iter()
+            .find(|segment| segment.entry_type == EntryType::Load && segment.contains(address))?;
+        let start = (address - segment.virtual_addr) as usize;
+        segment.data.get(start..start.checked_add(size as usize)?)
+    }
+}
+
+/// The null-terminated string at `offset` in a string table.
+fn string_at(table: &[u8], offset: u32) -> Option<&str> {
+    let bytes = table.get(offset as usize..)?;
+    let end = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..end]).ok()
+}
+
+pub struct HeaderEntry<'a> {
+    pub entry_type: EntryType,
+    pub flags: u32,
+    /// The part of the segment stored in the file; the rest, up to `mem_size`, is zeroed.
+    pub data: &'a [u8],
+    pub virtual_addr: u64,
+    pub physical_addr: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+/// `p_flags` bits of a program header.
+pub const SEGMENT_EXECUTE: u32 = 0x1;
+pub const SEGMENT_WRITE: u32 = 0x2;
+pub const SEGMENT_READ: u32 = 0x4;
+
+impl HeaderEntry<'_> {
+    pub fn is_writable(&self) -> bool {
+        self.flags & SEGMENT_WRITE != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & SEGMENT_EXECUTE != 0
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        self.virtual_addr <= address && address - self.virtual_addr < self.mem_size
+    }
+}
+
+pub struct SectionEntry<'a> {
+    pub name: &'a str,
+    pub section_type: SectionType,
+    pub flags: u64,
+    pub data: &'a [u8],
+    pub virtual_addr: u64,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    pub entry_size: u64,
+}
+
+pub enum SectionType {
+    None,
+    Progbits,
+    Symtab,
+    Strtab,
+    Rela,
+    Hash,
+    Dynamic,
+    Note,
+    Nobits,
+    Rel,
+    Dynsym,
+    InitArray,
+    FiniArray,
+    PreinitArray,
+    Group,
+    SymtabShndx,
+    Num,
+    Other(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    None,
+    Load,
+    Dynamic,
+    Interp,
+    Note,
+    Shlib,
+    Phdr,
+    Tls,
+    Other,
+}
+
+pub enum Abi {
+    SystemV,
+    Other,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    None,
+    Rel,
+    Exec,
+    Dyn,
+    Core,
+    Other,
+}
+
+/// Dynamic section tags.
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const RELA_ENTRY_SIZE: u64 = 24;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// An `Elf64_Rela` entry.
+#[derive(Clone, Copy, Debug)]
+pub struct Relocation {
+    /// Address to patch.
+    pub offset: u64,
+    pub kind: u32,
+    pub symbol: u32,
+    pub addend: i64,
+}
+
+const SYMBOL_ENTRY_SIZE: u64 = 24;
+
+/// Symbol types.
+pub const STT_FUNC: u8 = 2;
+
+/// An `Elf64_Sym` entry.
+pub struct ElfSymbol<'a> {
+    pub name: &'a str,
+    pub kind: u8,
+    /// Index of the section the symbol is defined in.
+    pub section: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const ET_EXEC: u16 = 2;
+    const ET_DYN: u16 = 3;
+    const PT_LOAD: u32 = 1;
+    const PT_DYNAMIC: u32 = 2;
+
+    struct Segment {
+        kind: u32,
+        flags: u32,
+        address: u64,
+        data: Vec<u8>,
+        mem_size: u64,
+    }
+
+    fn load(flags: u32, address: u64, data: Vec<u8>, mem_size: u64) -> Segment {
+        Segment {
+            kind: PT_LOAD,
+            flags,
+            address,
+            data,
+            mem_size,
+        }
+    }
+
+    /// Builds an x86_64 ELF file with the given segments. Each segment's data sits on a page of
+    /// its own, so their file offsets and addresses agree on alignment.
+    fn build(object_type: u16, entry: u64, segments: &[Segment]) -> Vec<u8> {
+        let mut file = vec![0; FILE_HEADER_SIZE as usize];
+        file[0..4].copy_from_slice(b"\x7FELF");
+        file[4] = 2;
+        file[5] = 1;
+        file[6] = 1;
+        file[0x10..0x12].copy_from_slice(&object_type.to_le_bytes());
+        file[0x12..0x14].copy_from_slice(&0x3Eu16.to_le_bytes());
+        file[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+        file[0x18..0x20].copy_from_slice(&entry.to_le_bytes());
+        file[0x20..0x28].copy_from_slice(&FILE_HEADER_SIZE.to_le_bytes());
+        file[0x34..0x36].copy_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+        file[0x36..0x38].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        file[0x38..0x3A].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        for (i, segment) in segments.iter().enumerate() {
+            let offset = PAGE_SIZE * (i as u64 + 1);
+            let mut header = Vec::new();
+            header.extend_from_slice(&segment.kind.to_le_bytes());
+            header.extend_from_slice(&segment.flags.to_le_bytes());
+            header.extend_from_slice(&offset.to_le_bytes());
+            header.extend_from_slice(&segment.address.to_le_bytes());
+            header.extend_from_slice(&segment.address.to_le_bytes());
+            header.extend_from_slice(&(segment.data.len() as u64).to_le_bytes());
+            header.extend_from_slice(&segment.mem_size.to_le_bytes());
+            header.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+            file.extend_from_slice(&header);
+        }
+        for (i, segment) in segments.iter().enumerate() {
+            file.resize(PAGE_SIZE as usize * (i + 1), 0);
+            file.extend_from_slice(&segment.data);
+        }
+        file
+    }
+
+    fn text() -> Segment {
+        load(SEGMENT_READ | SEGMENT_EXECUTE, 0x1000, vec![0xC3; 16], 16)
+    }
+
+    #[test]
+    fn parses_a_minimal_executable() {
+        let file = build(ET_EXEC, 0x1000, &[text()]);
+        let elf = Elf::parse(&file).unwrap();
+        assert_eq!(elf.entry, 0x1000);
+        assert_eq!(elf.loadable_segments().unwrap().len(), 1);
+        assert_eq!(elf.image_range().unwrap(), 0x1000..0x2000);
+        assert!(!elf.is_relocatable());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut file = build(ET_EXEC, 0x1000, &[text()]);
+        file[1] = b'X';
+        assert_eq!(Elf::parse(&file).err(), Some(ElfError::BadMagic));
+    }
+
+    #[test]
+    fn rejects_32_bit_files() {
+        let mut file = build(ET_EXEC, 0x1000, &[text()]);
+        file[4] = 1;
+        assert_eq!(Elf::parse(&file).err(), Some(ElfError::NotElf64));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let file = build(ET_EXEC, 0x1000, &[text()]);
+        assert!(matches!(
+            Elf::parse(&file[..40]),
+            Err(ElfError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_program_headers_past_the_end() {
+        let mut file = build(ET_EXEC, 0x1000, &[text()]);
+        let offset = file.len() as u64 - 8;
+        file[0x20..0x28].copy_from_slice(&offset.to_le_bytes());
+        assert_eq!(
+            Elf::parse(&file).err(),
+            Some(ElfError::OutOfBounds {
+                what: "program header",
+                offset,
+                size: PROGRAM_HEADER_SIZE,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_segment_data_past_the_end() {
+        let mut file = build(ET_EXEC, 0x1000, &[text()]);
+        file.truncate(file.len() - 1);
+        assert!(matches!(
+            Elf::parse(&file),
+            Err(ElfError::OutOfBounds {
+                what: "segment data",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_file_size_above_memory_size() {
+        let file = build(
+            ET_EXEC,
+            0x1000,
+            &[load(
+                SEGMENT_READ | SEGMENT_EXECUTE,
+                0x1000,
+                vec![0; 32],
+                16,
+            )],
+        );
+        assert_eq!(
+            Elf::parse(&file).err(),
+            Some(ElfError::SegmentFileSizeTooBig(0x1000))
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let file = build(
+            ET_EXEC,
+            0x1000,
+            &[
+                text(),
+                load(SEGMENT_READ, 0x1000 + PAGE_SIZE, vec![], 0x2000),
+                load(SEGMENT_READ | SEGMENT_WRITE, 0x2000 + PAGE_SIZE, vec![], 16),
+            ],
+        );
+        let elf = Elf::parse(&file).unwrap();
+        assert_eq!(
+            elf.loadable_segments().err(),
+            Some(ElfError::SegmentsOverlap(0x2000, 0x3000))
+        );
+    }
+
+    #[test]
+    fn rejects_entry_outside_text() {
+        let file = build(ET_EXEC, 0x5000, &[text()]);
+        let elf = Elf::parse(&file).unwrap();
+        assert_eq!(
+            elf.loadable_segments().err(),
+            Some(ElfError::EntryOutsideText(0x5000))
+        );
+    }
+
+    #[test]
+    fn zeroes_bss() {
+        let data = vec![0x11, 0x22, 0x33, 0x44];
+        let file = build(
+            ET_EXEC,
+            0x1000,
+            &[
+                text(),
+                load(SEGMENT_READ | SEGMENT_WRITE, 0x2000, data.clone(), 0x1800),
+            ],
+        );
+        let elf = Elf::parse(&file).unwrap();
+        let range = elf.image_range().unwrap();
+        assert_eq!(range, 0x1000..0x4000);
+
+        let mut image = vec![0xAA; (range.end - range.start) as usize];
+        elf.load_into(&mut image, 0).unwrap();
+        assert_eq!(&image[..16], &[0xC3; 16][..]);
+        assert!(image[16..0x1000].iter().all(|&b| b == 0));
+        assert_eq!(&image[0x1000..0x1004], &data[..]);
+        assert!(image[0x1004..].iter().all(|&b| b == 0));
+    }
+
+    /// A relocatable image with a pointer slot at 0x2000 that a relative relocation fills with
+    /// `target`.
+    fn relocatable(target: u64) -> Vec<u8> {
+        const SLOT: u64 = 0x2000;
+        const TABLE: u64 = 0x2008;
+        let mut data = vec![0; 8];
+        data.extend_from_slice(&SLOT.to_le_bytes());
+        data.extend_from_slice(&(R_X86_64_RELATIVE as u64).to_le_bytes());
+        data.extend_from_slice(&target.to_le_bytes());
+
+        let mut dynamic = Vec::new();
+        for &(tag, value) in &[
+            (DT_RELA, TABLE),
+            (DT_RELASZ, RELA_ENTRY_SIZE),
+            (DT_RELAENT, RELA_ENTRY_SIZE),
+            (DT_NULL, 0),
+        ] {
+            dynamic.extend_from_slice(&tag.to_le_bytes());
+            dynamic.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let data_size = data.len() as u64;
+        build(
+            ET_DYN,
+            0x1000,
+            &[
+                text(),
+                load(SEGMENT_READ | SEGMENT_WRITE, SLOT, data, data_size),
+                Segment {
+                    kind: PT_DYNAMIC,
+                    flags: SEGMENT_READ,
+                    address: 0,
+                    mem_size: dynamic.len() as u64,
+                    data: dynamic,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn applies_relative_relocations() {
+        let file = relocatable(0x1000);
+        let elf = Elf::parse(&file).unwrap();
+        assert!(elf.is_relocatable());
+        let relocations = elf.relocations().unwrap();
+        assert_eq!(relocations.len(), 1);
+        assert_eq!(relocations[0].offset, 0x2000);
+
+        let range = elf.image_range().unwrap();
+        let slide = 0x20_0000;
+        let mut image = vec![0; (range.end - range.start) as usize];
+        elf.load_into(&mut image, slide).unwrap();
+        let slot = (0x2000 - range.start) as usize;
+        assert_eq!(
+            u64::from_le_bytes(image[slot..slot + 8].try_into().unwrap()),
+            0x1000 + slide
+        );
+    }
+
+    #[test]
+    fn rejects_relocations_outside_the_image() {
+        let mut file = relocatable(0x1000);
+        // Point the relocation's slot past the end of the data segment
+        let offset = 2 * PAGE_SIZE as usize + 8;
+        file[offset..offset + 8].copy_from_slice(&0x9000u64.to_le_bytes());
+        let elf = Elf::parse(&file).unwrap();
+        let range = elf.image_range().unwrap();
+        let mut image = vec![0; (range.end - range.start) as usize];
+        assert_eq!(
+            elf.load_into(&mut image, 0),
+            Err(ElfError::RelocationOutOfBounds(0x9000))
+        );
+    }
+
+    #[test]
+    fn refuses_to_slide_without_relocations() {
+        let file = build(ET_EXEC, 0x1000, &[text()]);
+        let elf = Elf::parse(&file).unwrap();
+        let mut image = vec![0; PAGE_SIZE as usize];
+        assert_eq!(
+            elf.load_into(&mut image, PAGE_SIZE),
+            Err(ElfError::NotRelocatable)
+        );
+    }
+
+    /// A static PIE built by GCC and GNU ld from tests/pie.c.
+    const PIE: &[u8] = include_bytes!("../tests/pie.elf");
+
+    fn u64_in(image: &[u8], offset: u64) -> u64 {
+        let offset = offset as usize;
+        u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn lays_out_a_linked_pie() {
+        let elf = Elf::parse(PIE).unwrap();
+        assert!(elf.is_relocatable());
+        assert_eq!(elf.entry, 0x1000);
+        assert!(elf
+            .program_headers
+            .iter()
+            .any(|segment| segment.entry_type == EntryType::Dynamic));
+
+        let segments: Vec<_> = elf
+            .loadable_segments()
+            .unwrap()
+            .iter()
+            .map(|segment| {
+                (
+                    segment.virtual_addr,
+                    segment.data.len() as u64,
+                    segment.mem_size,
+                    segment.flags,
+                )
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                (0x0, 0x218, 0x218, SEGMENT_READ),
+                (0x1000, 0x11, 0x11, SEGMENT_READ | SEGMENT_EXECUTE),
+                (0x2000, 0x8, 0x8, SEGMENT_READ),
+                (0x3008, 0x120, 0x128, SEGMENT_READ | SEGMENT_WRITE),
+            ]
+        );
+        assert_eq!(elf.image_range().unwrap(), 0..0x4000);
+
+        let start = elf
+            .symbols()
+            .unwrap()
+            .into_iter()
+            .find(|s| s.name == "_start");
+        let start = start.unwrap();
+        assert_eq!((start.kind, start.value), (STT_FUNC, 0x1000));
+    }
+
+    #[test]
+    fn relocates_a_linked_pie() {
+        let elf = Elf::parse(PIE).unwrap();
+        let relocations: Vec<_> = elf
+            .relocations()
+            .unwrap()
+            .iter()
+            .map(|relocation| (relocation.offset, relocation.kind, relocation.addend))
+            .collect();
+        // `table` points at `counter`, `message` and `_start`
+        assert_eq!(
+            relocations,
+            [
+                (0x3110, R_X86_64_RELATIVE, 0x3128),
+                (0x3118, R_X86_64_RELATIVE, 0x2000),
+                (0x3120, R_X86_64_RELATIVE, 0x1000),
+            ]
+        );
+
+        let slide = 0xFFFF_8000_0020_0000;
+        let mut image = vec![0xAA; 0x4000];
+        elf.load_into(&mut image, slide).unwrap();
+        assert_eq!(u64_in(&image, 0x3110), 0x3128 + slide);
+        assert_eq!(u64_in(&image, 0x3118), 0x2000 + slide);
+        assert_eq!(u64_in(&image, 0x3120), 0x1000 + slide);
+        assert_eq!(&image[0x2000..0x2006], b"hello\0");
+        assert_eq!(&image[0x1000..0x1011], &PIE[0x1000..0x1011]);
+        // .bss, and the gap between the read-only data and the writable segment
+        assert_eq!(u64_in(&image, 0x3128), 0);
+        assert!(image[0x2008..0x3008].iter().all(|&b| b == 0));
+    }
+}
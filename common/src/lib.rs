@@ -1,47 +1,235 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![feature(abi_efiapi)]
+
+extern crate alloc;
 
 #[macro_use]
 pub mod writer;
 
+pub mod boot_info;
+pub mod boot_log;
+pub mod efi;
+pub mod elf;
+pub mod memory_map;
+pub mod symbols;
+pub mod time;
+
+use core::{mem::size_of, ops::Range};
+
+pub use boot_info::{BootInfoHeader, BootProtocolError, BOOT_PROTOCOL_VERSION};
+pub use boot_log::BootLog;
+pub use memory_map::{MemoryRegion, MemoryRegionType, MEMORY_MAP_VERSION};
+pub use symbols::{Symbol, SymbolMap};
+
+/// The boot info block the bootloader passes to the kernel entry point by reference.
+///
+/// The header and the framebuffer stay at the start in every protocol version, so a kernel can
+/// always report a mismatch on screen. The struct is hashed byte for byte, so it must not contain
+/// any padding.
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct MachineInfoC {
+    header: BootInfoHeader,
     framebuffer: Framebuffer,
-    xhci_base: u64,
-    allocated_frames_len: usize,
-    allocated_frames_ptr: *mut u8,
+    memory_map_version: u32,
+    memory_region_size: u32,
+    memory_regions_ptr: *const MemoryRegion,
+    memory_regions_len: usize,
+    rsdp_address: u64,
+    kernel_phys_start: u64,
+    kernel_phys_end: u64,
+    kernel_slide: u64,
+    initrd_start: u64,
+    initrd_end: u64,
+    command_line_ptr: *const u8,
+    command_line_len: usize,
+    symbols_ptr: *const Symbol,
+    symbols_len: usize,
+    symbol_names_ptr: *const u8,
+    symbol_names_len: usize,
+    boot_log_ptr: *const u8,
+    boot_log_len: usize,
+    boot_log_dropped: u64,
+    verbosity: u32,
+    _padding: u32,
+    runtime_services_address: u64,
+    boot_time: u64,
 }
 
 pub struct MachineInfo {
     pub framebuffer: Framebuffer,
-    pub xhci_base: u64,
-    pub allocated_frames: &'static mut [u8],
+    /// The UEFI memory map as it was after exiting boot services, sorted by address.
+    pub memory_map: &'static [MemoryRegion],
+    /// Physical address of the ACPI RSDP, if the firmware provided one.
+    pub rsdp_address: Option<u64>,
+    /// Physical memory the kernel image was loaded into.
+    pub kernel_phys_range: Range<u64>,
+    /// How far the kernel was moved from the address it was linked at.
+    pub kernel_slide: u64,
+    /// Physical memory the initial ramdisk archive was loaded into, if there is one.
+    pub initrd: Option<Range<u64>>,
+    pub command_line: &'static str,
+    /// Function symbols of the kernel, for backtraces. Empty if the image had no symbol table.
+    pub symbols: SymbolMap<'static>,
+    /// Everything the bootloader logged, whether it was shown or not.
+    pub boot_log: BootLog<'static>,
+    /// How much the kernel should print while booting, as set in the bootloader configuration.
+    pub verbosity: Verbosity,
+    /// Physical address of the UEFI runtime services table. The firmware was told the direct
+    /// physical mapping is its virtual address map.
+    pub runtime_services_address: Option<u64>,
+    /// Unix time in seconds when the bootloader read the firmware's clock, if it could.
+    pub boot_time: Option<u64>,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Verbosity {
+    Quiet = 0,
+    Normal = 1,
+    Verbose = 2,
+}
+
+impl MachineInfoC {
+    /// Checks that the block was created by a bootloader speaking the same boot protocol, and that
+    /// it arrived intact.
+    pub fn validate(&self) -> Result<(), BootProtocolError> {
+        self.header.validate(size_of::<Self>())?;
+        let found = self.compute_checksum();
+        if found != self.header.checksum {
+            return Err(BootProtocolError::BadChecksum {
+                expected: self.header.checksum,
+                found,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the block at least looks like boot info, so that the framebuffer can be trusted.
+    pub fn has_valid_magic(&self) -> bool {
+        self.header.magic == boot_info::BOOT_INFO_MAGIC
+    }
+
+    pub fn framebuffer(&self) -> Framebuffer {
+        self.framebuffer
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let mut copy = *self;
+        copy.header.checksum = 0;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&copy as *const Self as *const u8, size_of::<Self>())
+        };
+        boot_info::checksum(bytes)
+    }
 }
 
-impl From<MachineInfoC> for MachineInfo {
-    fn from(machine_info: MachineInfoC) -> Self {
+impl From<&MachineInfoC> for MachineInfo {
+    fn from(machine_info: &MachineInfoC) -> Self {
+        assert_eq!(
+            machine_info.memory_map_version, MEMORY_MAP_VERSION,
+            "Memory map version mismatch between bootloader and kernel"
+        );
+        assert_eq!(
+            machine_info.memory_region_size as usize,
+            size_of::<MemoryRegion>(),
+            "Memory region size mismatch between bootloader and kernel"
+        );
+        let command_line = unsafe {
+            core::slice::from_raw_parts(
+                machine_info.command_line_ptr,
+                machine_info.command_line_len,
+            )
+        };
         Self {
             framebuffer: machine_info.framebuffer,
-            xhci_base: machine_info.xhci_base,
-            allocated_frames: unsafe {
-                core::slice::from_raw_parts_mut(
-                    machine_info.allocated_frames_ptr,
-                    machine_info.allocated_frames_len,
+            memory_map: unsafe {
+                core::slice::from_raw_parts(
+                    machine_info.memory_regions_ptr,
+                    machine_info.memory_regions_len,
                 )
             },
+            rsdp_address: match machine_info.rsdp_address {
+                0 => None,
+                address => Some(address),
+            },
+            kernel_phys_range: machine_info.kernel_phys_start..machine_info.kernel_phys_end,
+            kernel_slide: machine_info.kernel_slide,
+            initrd: match machine_info.initrd_start..machine_info.initrd_end {
+                range if range.is_empty() => None,
+                range => Some(range),
+            },
+            command_line: core::str::from_utf8(command_line).unwrap_or(""),
+            symbols: unsafe {
+                SymbolMap {
+                    symbols: core::slice::from_raw_parts(
+                        machine_info.symbols_ptr,
+                        machine_info.symbols_len,
+                    ),
+                    names: core::slice::from_raw_parts(
+                        machine_info.symbol_names_ptr,
+                        machine_info.symbol_names_len,
+                    ),
+                }
+            },
+            boot_log: BootLog {
+                bytes: unsafe {
+                    core::slice::from_raw_parts(
+                        machine_info.boot_log_ptr,
+                        machine_info.boot_log_len,
+                    )
+                },
+                dropped: machine_info.boot_log_dropped,
+            },
+            verbosity: match machine_info.verbosity {
+                0 => Verbosity::Quiet,
+                2 => Verbosity::Verbose,
+                _ => Verbosity::Normal,
+            },
+            runtime_services_address: match machine_info.runtime_services_address {
+                0 => None,
+                address => Some(address),
+            },
+            boot_time: match machine_info.boot_time {
+                0 => None,
+                seconds => Some(seconds),
+            },
         }
     }
 }
 
 impl From<MachineInfo> for MachineInfoC {
     fn from(machine_info: MachineInfo) -> Self {
-        let ptr = machine_info.allocated_frames.as_mut_ptr();
-        let len = machine_info.allocated_frames.len();
-        Self {
+        let initrd = machine_info.initrd.clone().unwrap_or(0..0);
+        let mut machine_info = Self {
+            header: BootInfoHeader::new(size_of::<Self>() as u32),
             framebuffer: machine_info.framebuffer,
-            xhci_base: machine_info.xhci_base,
-            allocated_frames_len: len,
-            allocated_frames_ptr: ptr,
-        }
+            memory_map_version: MEMORY_MAP_VERSION,
+            memory_region_size: size_of::<MemoryRegion>() as u32,
+            memory_regions_ptr: machine_info.memory_map.as_ptr(),
+            memory_regions_len: machine_info.memory_map.len(),
+            rsdp_address: machine_info.rsdp_address.unwrap_or(0),
+            kernel_phys_start: machine_info.kernel_phys_range.start,
+            kernel_phys_end: machine_info.kernel_phys_range.end,
+            kernel_slide: machine_info.kernel_slide,
+            initrd_start: initrd.start,
+            initrd_end: initrd.end,
+            command_line_ptr: machine_info.command_line.as_ptr(),
+            command_line_len: machine_info.command_line.len(),
+            symbols_ptr: machine_info.symbols.symbols.as_ptr(),
+            symbols_len: machine_info.symbols.symbols.len(),
+            symbol_names_ptr: machine_info.symbols.names.as_ptr(),
+            symbol_names_len: machine_info.symbols.names.len(),
+            boot_log_ptr: machine_info.boot_log.bytes.as_ptr(),
+            boot_log_len: machine_info.boot_log.bytes.len(),
+            boot_log_dropped: machine_info.boot_log.dropped,
+            verbosity: machine_info.verbosity as u32,
+            _padding: 0,
+            runtime_services_address: machine_info.runtime_services_address.unwrap_or(0),
+            boot_time: machine_info.boot_time.unwrap_or(0),
+        };
+        machine_info.header.checksum = machine_info.compute_checksum();
+        machine_info
     }
 }
 
@@ -0,0 +1,90 @@
+use core::fmt;
+
+/// Version of the memory map layout handed from the bootloader to the kernel. Bump this whenever
+/// [`MemoryRegion`] changes.
+pub const MEMORY_MAP_VERSION: u32 = 1;
+
+/// One entry of the physical memory map, as reported by the firmware.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub ty: MemoryRegionType,
+    /// Physical start address; always 4 KiB aligned.
+    pub base: u64,
+    /// Length of the region in 4 KiB pages.
+    pub pages: u64,
+    /// The UEFI memory attributes (cacheability, runtime, ...) of the region.
+    pub attributes: u64,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u64 {
+        self.base + self.pages * 4096
+    }
+}
+
+/// The UEFI memory type of a region. Kept as a plain number, as firmware is free to use types
+/// outside the ones the specification defines.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegionType(pub u32);
+
+impl MemoryRegionType {
+    pub const RESERVED: Self = Self(0);
+    pub const LOADER_CODE: Self = Self(1);
+    pub const LOADER_DATA: Self = Self(2);
+    pub const BOOT_SERVICES_CODE: Self = Self(3);
+    pub const BOOT_SERVICES_DATA: Self = Self(4);
+    pub const RUNTIME_SERVICES_CODE: Self = Self(5);
+    pub const RUNTIME_SERVICES_DATA: Self = Self(6);
+    pub const CONVENTIONAL: Self = Self(7);
+    pub const UNUSABLE: Self = Self(8);
+    pub const ACPI_RECLAIM: Self = Self(9);
+    pub const ACPI_NON_VOLATILE: Self = Self(10);
+    pub const MMIO: Self = Self(11);
+    pub const MMIO_PORT_SPACE: Self = Self(12);
+    pub const PAL_CODE: Self = Self(13);
+    pub const PERSISTENT_MEMORY: Self = Self(14);
+
+    /// Whether the region is RAM that the kernel may use once the bootloader is done with it.
+    pub fn is_ram(self) -> bool {
+        matches!(
+            self,
+            Self::LOADER_CODE
+                | Self::LOADER_DATA
+                | Self::BOOT_SERVICES_CODE
+                | Self::BOOT_SERVICES_DATA
+                | Self::CONVENTIONAL
+        )
+    }
+
+    fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::RESERVED => "reserved",
+            Self::LOADER_CODE => "loader code",
+            Self::LOADER_DATA => "loader data",
+            Self::BOOT_SERVICES_CODE => "boot services code",
+            Self::BOOT_SERVICES_DATA => "boot services data",
+            Self::RUNTIME_SERVICES_CODE => "runtime services code",
+            Self::RUNTIME_SERVICES_DATA => "runtime services data",
+            Self::CONVENTIONAL => "conventional",
+            Self::UNUSABLE => "unusable",
+            Self::ACPI_RECLAIM => "ACPI reclaim",
+            Self::ACPI_NON_VOLATILE => "ACPI NVS",
+            Self::MMIO => "MMIO",
+            Self::MMIO_PORT_SPACE => "MMIO port space",
+            Self::PAL_CODE => "PAL code",
+            Self::PERSISTENT_MEMORY => "persistent",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for MemoryRegionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown ({:#x})", self.0),
+        }
+    }
+}
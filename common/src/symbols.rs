@@ -0,0 +1,47 @@
+/// A function of the kernel, as listed in the symbol map the bootloader hands over.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol {
+    /// Address the function was loaded at, slide included.
+    pub address: u64,
+    pub size: u64,
+    /// Where the (mangled) name lies in the name blob of the map.
+    pub name_offset: u32,
+    pub name_len: u32,
+}
+
+/// The kernel's function symbols, sorted by address, and the names they point into.
+#[derive(Clone, Copy)]
+pub struct SymbolMap<'a> {
+    pub symbols: &'a [Symbol],
+    pub names: &'a [u8],
+}
+
+impl<'a> SymbolMap<'a> {
+    pub const fn empty() -> Self {
+        Self {
+            symbols: &[],
+            names: &[],
+        }
+    }
+
+    /// The name of the function containing `address`, and the offset of `address` into it.
+    pub fn lookup(&self, address: u64) -> Option<(&'a str, u64)> {
+        let index = match self
+            .symbols
+            .binary_search_by_key(&address, |symbol| symbol.address)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        let start = symbol.name_offset as usize;
+        let name = self.names.get(start..start + symbol.name_len as usize)?;
+        Some((core::str::from_utf8(name).ok()?, offset))
+    }
+}
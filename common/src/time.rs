@@ -0,0 +1,210 @@
+//! Calendar dates and times in UTC, and converting them to and from Unix time.
+
+use core::fmt;
+
+/// A point in time in UTC, to the second.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Whether every field is in range, with the day checked against the length of the month.
+    /// Dates before the Unix epoch are rejected as well.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC. The date must be valid.
+    pub fn unix_seconds(&self) -> u64 {
+        let days = days_from_civil(self.year as u64, self.month as u64, self.day as u64);
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> Self {
+        let (year, month, day) = civil_from_days(seconds / 86400);
+        let time = seconds % 86400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Both conversions count in 400-year eras of 146097 days starting on March 1st, so that the leap
+// day falls at the end of the year. Only dates from 1970 on are handled, so nothing goes negative.
+
+/// Days since 1970-01-01.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Year, month and day of the date `days` after 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+
+    #[test]
+    fn epoch_is_zero() {
+        assert_eq!(date(1970, 1, 1).unix_seconds(), 0);
+        assert_eq!(DateTime::from_unix_seconds(0), date(1970, 1, 1));
+    }
+
+    #[test]
+    fn known_dates() {
+        let cases = [
+            (date(1999, 12, 31), 946_598_400),
+            (date(2000, 1, 1), 946_684_800),
+            (date(2000, 2, 29), 951_782_400),
+            (date(2000, 3, 1), 951_868_800),
+            (date(2100, 2, 28), 4_107_456_000),
+            (date(2100, 3, 1), 4_107_542_400),
+        ];
+        for &(date, seconds) in cases.iter() {
+            assert_eq!(date.unix_seconds(), seconds, "{}", date);
+            assert_eq!(DateTime::from_unix_seconds(seconds), date);
+        }
+    }
+
+    #[test]
+    fn year_boundary() {
+        let last_second = DateTime {
+            year: 1999,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert_eq!(
+            DateTime::from_unix_seconds(last_second.unix_seconds() + 1),
+            date(2000, 1, 1)
+        );
+    }
+
+    #[test]
+    fn every_day_round_trips() {
+        // 1970 into 2498, across the leap year 2000 and the non-leap years 2100, 2200 and 2300
+        for days in 0..193_000 {
+            let date = DateTime::from_unix_seconds(days * 86400 + 45_296);
+            assert!(date.is_valid(), "{}", date);
+            assert_eq!((date.hour, date.minute, date.second), (12, 34, 56));
+            assert_eq!(date.unix_seconds(), days * 86400 + 45_296);
+        }
+    }
+
+    #[test]
+    fn leap_days() {
+        assert!(date(2000, 2, 29).is_valid());
+        assert!(date(2024, 2, 29).is_valid());
+        assert!(!date(2100, 2, 29).is_valid());
+        assert!(!date(2023, 2, 29).is_valid());
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        let valid = DateTime {
+            year: 2021,
+            month: 4,
+            day: 30,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert!(valid.is_valid());
+        let invalid = [
+            DateTime {
+                year: 1969,
+                ..valid
+            },
+            DateTime { month: 0, ..valid },
+            DateTime { month: 13, ..valid },
+            DateTime { day: 0, ..valid },
+            DateTime { day: 31, ..valid },
+            DateTime { hour: 24, ..valid },
+            DateTime {
+                minute: 60,
+                ..valid
+            },
+            DateTime {
+                second: 60,
+                ..valid
+            },
+        ];
+        for date in invalid.iter() {
+            assert!(!date.is_valid(), "{:?}", date);
+        }
+    }
+}
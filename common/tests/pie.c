@@ -0,0 +1,21 @@
+// Source of pie.elf, a linker-produced position-independent executable that the ELF loader in
+// common/src/elf.rs is tested against. Built with GCC 12.2 and GNU ld 2.40:
+//
+//   gcc -Os -fPIE -static-pie -nostdlib -fno-asynchronous-unwind-tables -Wl,--build-id=none \
+//       -Wl,-z,max-page-size=0x1000 -Wl,-z,norelro -Wl,--hash-style=gnu -o pie.elf pie.c
+//
+// `table` holds three absolute addresses, so the linker emits an R_X86_64_RELATIVE relocation for
+// each of them, and `counter` lands in .bss at the end of the writable segment.
+
+static long counter;
+static const char message[] = "hello";
+void _start(void);
+
+void *table[] = {&counter, (void *)message, (void *)_start};
+
+void _start(void)
+{
+    counter = (long)table[0];
+    for (;;)
+        __asm__("hlt");
+}
@@ -0,0 +1,232 @@
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::{phys_to_virt, read_le, GenericAddress, Register, SdtHeader};
+
+/// The parts of the FADT the kernel uses.
+pub struct Fadt {
+    pub revision: u8,
+    /// ISA IRQ the SCI is wired to.
+    pub sci_interrupt: u16,
+    pub pm1a_control: Option<Register>,
+    pub pm1b_control: Option<Register>,
+    pub pm_timer: Option<PmTimer>,
+    /// Register and value that reset the machine when written.
+    pub reset: Option<(Register, u8)>,
+    /// Index of the CMOS RTC register holding the century, if there is one.
+    pub century_register: Option<u8>,
+    /// `IAPC_BOOT_ARCH` flags; not present before revision 2.
+    pub boot_architecture: Option<u16>,
+    /// SLP_TYPa and SLP_TYPb for the S5 (soft off) sleep state, from the DSDT.
+    pub sleep_types_s5: Option<(u8, u8)>,
+    smi_command: u16,
+    acpi_enable: u8,
+}
+
+/// The ACPI power management timer, a free-running counter at [`PmTimer::FREQUENCY`].
+#[derive(Clone, Copy, Debug)]
+pub struct PmTimer {
+    pub register: Register,
+    /// Whether the counter has 32 bits rather than 24.
+    pub is_32_bit: bool,
+}
+
+impl PmTimer {
+    pub const FREQUENCY: u64 = 3_579_545;
+
+    pub fn read(&self) -> u32 {
+        let value = unsafe { self.register.read() } as u32;
+        if self.is_32_bit {
+            value
+        } else {
+            value & 0xFF_FFFF
+        }
+    }
+}
+
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const BOOT_ARCH_MSI_NOT_SUPPORTED: u16 = 1 << 3;
+const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+const PM1_SCI_EN: u64 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0x7 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u64 = 1 << 13;
+
+impl Fadt {
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture
+            .map_or(true, |flags| flags & BOOT_ARCH_8042 != 0)
+    }
+
+    pub fn has_vga(&self) -> bool {
+        self.boot_architecture
+            .map_or(true, |flags| flags & BOOT_ARCH_VGA_NOT_PRESENT == 0)
+    }
+
+    pub fn msi_supported(&self) -> bool {
+        self.boot_architecture
+            .map_or(true, |flags| flags & BOOT_ARCH_MSI_NOT_SUPPORTED == 0)
+    }
+
+    pub fn has_cmos_rtc(&self) -> bool {
+        self.boot_architecture
+            .map_or(true, |flags| flags & BOOT_ARCH_CMOS_RTC_NOT_PRESENT == 0)
+    }
+
+    /// Resets the machine through the reset register. Only returns if that didn't work.
+    pub unsafe fn reset(&self) -> Result<(), &'static str> {
+        let (register, value) = self.reset.ok_or("No reset register")?;
+        interrupts::disable();
+        register.write(value as u64);
+        wait();
+        Err("Still running after writing the reset register")
+    }
+
+    /// Turns the machine off by entering sleep state S5. Only returns if that didn't work.
+    pub unsafe fn power_off(&self) -> Result<(), &'static str> {
+        let (type_a, type_b) = self
+            .sleep_types_s5
+            .ok_or("No \\_S5 sleep state in the DSDT")?;
+        let pm1a = self.pm1a_control.ok_or("No PM1a control register")?;
+        self.enable_acpi_mode(pm1a)?;
+
+        interrupts::disable();
+        for (register, sleep_type) in [(Some(pm1a), type_a), (self.pm1b_control, type_b)].iter() {
+            if let Some(register) = register {
+                let value = register.read() & !PM1_SLP_TYP_MASK;
+                register.write(value | (*sleep_type as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+            }
+        }
+        wait();
+        Err("Still running after entering S5")
+    }
+
+    /// Takes the fixed hardware over from the firmware, unless that already happened.
+    unsafe fn enable_acpi_mode(&self, pm1a: Register) -> Result<(), &'static str> {
+        // Without an SMI command port the machine is always in ACPI mode
+        if pm1a.read() & PM1_SCI_EN != 0 || self.smi_command == 0 || self.acpi_enable == 0 {
+            return Ok(());
+        }
+        Port::<u8>::new(self.smi_command).write(self.acpi_enable);
+        for _ in 0..1_000_000 {
+            if pm1a.read() & PM1_SCI_EN != 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err("Firmware didn't hand over to ACPI mode")
+    }
+}
+
+/// Gives the hardware time to act on a write that should stop the machine.
+fn wait() {
+    for _ in 0..100_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+pub(super) fn parse() -> Option<Fadt> {
+    // Offsets are from the start of the table, as in the specification
+    let table = super::find_table(b"FACP")?;
+    let bytes = table.bytes();
+    let field = |offset, size| read_le(bytes, offset, size);
+    let flags = field(112, 4).unwrap_or(0) as u32;
+
+    let pm_timer = register(bytes, 208, 76, 32).map(|register| PmTimer {
+        register,
+        is_32_bit: flags & FLAG_TMR_VAL_EXT != 0,
+    });
+    let reset = match (bytes.get(116..128), field(128, 1)) {
+        (Some(gas), Some(value)) if flags & FLAG_RESET_REG_SUP != 0 => GenericAddress::parse(gas)
+            .and_then(|gas| gas.register())
+            .map(|register| (register, value as u8)),
+        _ => None,
+    };
+    let dsdt = match field(140, 8) {
+        Some(address) if address != 0 => address,
+        _ => field(40, 4)?,
+    };
+
+    Some(Fadt {
+        revision: table.revision,
+        sci_interrupt: field(46, 2)? as u16,
+        pm1a_control: register(bytes, 172, 64, 16),
+        pm1b_control: register(bytes, 184, 68, 16),
+        pm_timer,
+        reset,
+        century_register: match field(108, 1) {
+            Some(0) | None => None,
+            Some(index) => Some(index as u8),
+        },
+        boot_architecture: match table.revision {
+            0 | 1 => None,
+            _ => field(109, 2).map(|flags| flags as u16),
+        },
+        sleep_types_s5: sleep_types_s5(dsdt),
+        smi_command: field(48, 4)? as u16,
+        acpi_enable: field(52, 1)? as u8,
+    })
+}
+
+/// The register in the extended (GAS) field at `extended`, or else the I/O port in the 32-bit
+/// field at `legacy`.
+fn register(bytes: &[u8], extended: usize, legacy: usize, width: u8) -> Option<Register> {
+    let gas = bytes
+        .get(extended..extended + 12)
+        .and_then(GenericAddress::parse);
+    match gas {
+        Some(gas) => gas.register(),
+        None => match read_le(bytes, legacy, 4)? {
+            0 => None,
+            port => Some(Register::Io {
+                port: port as u16,
+                width,
+            }),
+        },
+    }
+}
+
+/// Finds the `\_S5` package in the DSDT at physical address `dsdt` without interpreting AML. The
+/// package is almost always a plain `Name(_S5, Package() { a, b, ... })`, which this handles.
+fn sleep_types_s5(dsdt: u64) -> Option<(u8, u8)> {
+    let dsdt = unsafe { &*(phys_to_virt(dsdt) as *const SdtHeader) };
+    if &dsdt.signature != b"DSDT" || !dsdt.is_valid() {
+        return None;
+    }
+    let aml = dsdt.body();
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    let position = aml.windows(4).enumerate().position(|(i, name)| {
+        name == b"_S5_"
+            && (i >= 1 && aml[i - 1] == NAME_OP
+                || i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == b'\\')
+    })?;
+    let mut rest = aml.get(position + 4..)?;
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength takes one to four bytes, counted by the top two bits of the first
+    let length_bytes = 1 + (*rest.get(1)? >> 6) as usize;
+    // Then comes the element count
+    rest = rest.get(1 + length_bytes + 1..)?;
+    let (type_a, rest) = aml_integer(rest)?;
+    let (type_b, _) = aml_integer(rest)?;
+    Some((type_a, type_b))
+}
+
+/// Parses a small integer constant at the start of `aml`, and returns it with what follows it.
+fn aml_integer(aml: &[u8]) -> Option<(u8, &[u8])> {
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0A;
+    match *aml.first()? {
+        ZERO_OP => Some((0, &aml[1..])),
+        ONE_OP => Some((1, &aml[1..])),
+        BYTE_PREFIX => Some((*aml.get(1)?, &aml[2..])),
+        _ => None,
+    }
+}
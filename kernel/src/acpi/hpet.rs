@@ -0,0 +1,35 @@
+use super::{read_le, AddressSpace, GenericAddress};
+
+/// The first HPET block, as described by the HPET table.
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    /// Physical address of the registers.
+    pub address: u64,
+    pub hpet_number: u8,
+    pub comparator_count: u8,
+    pub counter_is_64_bit: bool,
+    /// Whether the comparators can take over IRQ 0 and 8 from the PIT and RTC.
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// Smallest tick count periodic mode can be programmed with without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+pub(super) fn parse() -> Option<Hpet> {
+    let body = super::find_table(b"HPET")?.body();
+    let id = read_le(body, 0, 4)? as u32;
+    let base = GenericAddress::parse(body.get(4..16)?)?;
+    if base.space != AddressSpace::SystemMemory {
+        println!("HPET registers are not memory-mapped; ignoring the HPET");
+        return None;
+    }
+    Some(Hpet {
+        address: base.address,
+        hpet_number: read_le(body, 16, 1)? as u8,
+        comparator_count: (id >> 8 & 0x1F) as u8 + 1,
+        counter_is_64_bit: id & 1 << 13 != 0,
+        legacy_replacement: id & 1 << 15 != 0,
+        pci_vendor_id: (id >> 16) as u16,
+        minimum_tick: read_le(body, 17, 2)? as u16,
+    })
+}
@@ -0,0 +1,183 @@
+use alloc::vec::Vec;
+
+use super::read_le;
+
+/// What the MADT says about the processors and interrupt controllers.
+pub struct Madt {
+    /// Physical address of every processor's local APIC.
+    pub local_apic_address: u64,
+    /// Whether the two 8259 PICs are present as well, and need masking if the APICs are used.
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Processor {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// Whether the processor can be started now; if not, it may only be brought online later.
+    pub enabled: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    /// The first global system interrupt the I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Polarity {
+    /// Whatever the bus specifies: active high for ISA.
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TriggerMode {
+    /// Whatever the bus specifies: edge for ISA.
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// An ISA IRQ that isn't wired to the global system interrupt of the same number, or not with ISA
+/// polarity and trigger mode.
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// A local APIC input wired to the NMI line.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApicNmi {
+    /// `None` for all processors.
+    pub processor_uid: Option<u32>,
+    /// LINT0 or LINT1.
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+const PCAT_COMPAT: u32 = 1 << 0;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+const ENTRY_LOCAL_X2APIC_NMI: u8 = 0xA;
+
+pub(super) fn parse() -> Option<Madt> {
+    let body = super::find_table(b"APIC")?.body();
+    let mut madt = Madt {
+        local_apic_address: read_le(body, 0, 4)?,
+        has_legacy_pics: read_le(body, 4, 4)? as u32 & PCAT_COMPAT != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+    };
+
+    let mut entries = &body[8..];
+    while entries.len() >= 2 {
+        let (kind, length) = (entries[0], entries[1] as usize);
+        if length < 2 || length > entries.len() {
+            println!("MADT entry of type {} has a bad length", kind);
+            break;
+        }
+        let entry = &entries[..length];
+        entries = &entries[length..];
+        let field = |offset, size| read_le(entry, offset, size);
+
+        match kind {
+            ENTRY_LOCAL_APIC => {
+                let flags = field(4, 4)?;
+                // Neither enabled nor online capable means the processor is unusable
+                if flags & 0b11 != 0 {
+                    madt.processors.push(Processor {
+                        processor_uid: field(2, 1)? as u32,
+                        apic_id: field(3, 1)? as u32,
+                        enabled: flags & 1 != 0,
+                    });
+                }
+            }
+            ENTRY_LOCAL_X2APIC => {
+                let flags = field(8, 4)?;
+                if flags & 0b11 != 0 {
+                    madt.processors.push(Processor {
+                        processor_uid: field(12, 4)? as u32,
+                        apic_id: field(4, 4)? as u32,
+                        enabled: flags & 1 != 0,
+                    });
+                }
+            }
+            ENTRY_IO_APIC => madt.io_apics.push(IoApic {
+                id: field(2, 1)? as u8,
+                address: field(4, 4)?,
+                gsi_base: field(8, 4)? as u32,
+            }),
+            ENTRY_INTERRUPT_OVERRIDE => {
+                let flags = field(8, 2)? as u16;
+                madt.overrides.push(InterruptOverride {
+                    isa_irq: field(3, 1)? as u8,
+                    gsi: field(4, 4)? as u32,
+                    polarity: polarity(flags),
+                    trigger_mode: trigger_mode(flags),
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                let flags = field(3, 2)? as u16;
+                madt.nmis.push(LocalApicNmi {
+                    processor_uid: match field(2, 1)? {
+                        0xFF => None,
+                        uid => Some(uid as u32),
+                    },
+                    lint: field(5, 1)? as u8,
+                    polarity: polarity(flags),
+                    trigger_mode: trigger_mode(flags),
+                });
+            }
+            ENTRY_LOCAL_X2APIC_NMI => {
+                let flags = field(2, 2)? as u16;
+                madt.nmis.push(LocalApicNmi {
+                    processor_uid: match field(4, 4)? {
+                        0xFFFF_FFFF => None,
+                        uid => Some(uid as u32),
+                    },
+                    lint: field(8, 1)? as u8,
+                    polarity: polarity(flags),
+                    trigger_mode: trigger_mode(flags),
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS => madt.local_apic_address = field(4, 8)?,
+            _ => {}
+        }
+    }
+    Some(madt)
+}
+
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    }
+}
+
+fn trigger_mode(flags: u16) -> TriggerMode {
+    match flags >> 2 & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    }
+}
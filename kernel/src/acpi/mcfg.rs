@@ -0,0 +1,31 @@
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// An area of memory-mapped PCI configuration space (ECAM), as listed in the MCFG table.
+#[derive(Clone, Debug)]
+pub struct EcamRegion {
+    /// Physical address of the configuration space of the first bus in `buses`.
+    pub base: u64,
+    pub segment: u16,
+    pub buses: RangeInclusive<u8>,
+}
+
+/// The ECAM regions from the MCFG table; empty if there is no such table, in which case
+/// configuration space can only be reached through I/O ports.
+pub fn regions() -> Vec<EcamRegion> {
+    let mcfg = match super::find_table(b"MCFG") {
+        Some(mcfg) => mcfg,
+        None => return Vec::new(),
+    };
+    // Eight reserved bytes follow the header, then 16 bytes per region
+    mcfg.body()
+        .get(8..)
+        .unwrap_or(&[])
+        .chunks_exact(16)
+        .map(|entry| EcamRegion {
+            base: super::read_le(entry, 0, 8).unwrap(),
+            segment: super::read_le(entry, 8, 2).unwrap() as u16,
+            buses: entry[10]..=entry[11],
+        })
+        .collect()
+}
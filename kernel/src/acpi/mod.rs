@@ -0,0 +1,340 @@
+//! Just enough ACPI to find the hardware: the tables are located through the RSDP the bootloader
+//! passes on and read in place through the physical memory mapping. There is no AML interpreter.
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::memory::PHYSICAL_MEMORY_OFFSET;
+use fadt::Fadt;
+use hpet::Hpet;
+use madt::Madt;
+
+/// Physical addresses of the tables listed in the RSDT or XSDT.
+static TABLES: Once<Vec<u64>> = Once::new();
+static MADT: Once<Madt> = Once::new();
+static HPET: Once<Hpet> = Once::new();
+static FADT: Once<Fadt> = Once::new();
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only in ACPI 2.0 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// The header every system description table starts with.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, self.length as usize)
+        }
+    }
+
+    /// The table after the header.
+    pub fn body(&self) -> &[u8] {
+        &self.bytes()[size_of::<Self>()..]
+    }
+
+    fn is_valid(&self) -> bool {
+        self.length as usize >= size_of::<Self>() && checksum(self.bytes()) == 0
+    }
+}
+
+/// Finds the RSDT or XSDT through the RSDP at physical address `rsdp_address`, and parses the
+/// tables the rest of the kernel needs.
+pub fn init(rsdp_address: u64) -> Result<(), &'static str> {
+    let rsdp = unsafe { &*(phys_to_virt(rsdp_address) as *const Rsdp) };
+    if &rsdp.signature != b"RSD PTR " {
+        return Err("Bad RSDP signature");
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(rsdp as *const Rsdp as *const u8, 20) };
+    if checksum(bytes) != 0 {
+        return Err("Bad RSDP checksum");
+    }
+
+    // ACPI 1.0 only has the RSDT, with 32-bit pointers
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(rsdp as *const Rsdp as *const u8, size_of::<Rsdp>())
+        };
+        if checksum(bytes) != 0 {
+            return Err("Bad extended RSDP checksum");
+        }
+        (rsdp.xsdt_address, 8)
+    } else {
+        (rsdp.rsdt_address as u64, 4)
+    };
+    let root = unsafe { &*(phys_to_virt(root) as *const SdtHeader) };
+    if !root.is_valid() {
+        return Err("Bad RSDT/XSDT checksum");
+    }
+
+    let tables = root
+        .body()
+        .chunks_exact(entry_size)
+        .filter_map(|entry| read_le(entry, 0, entry_size))
+        .collect();
+    TABLES.call_once(|| tables);
+
+    if let Some(madt) = madt::parse() {
+        MADT.call_once(|| madt);
+    }
+    if let Some(hpet) = hpet::parse() {
+        HPET.call_once(|| hpet);
+    }
+    if let Some(fadt) = fadt::parse() {
+        FADT.call_once(|| fadt);
+    }
+    Ok(())
+}
+
+/// Interrupt controllers and processors. `None` before [`init`] or if there is no MADT.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    FADT.get()
+}
+
+/// The first valid table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature)
+}
+
+/// All tables with valid checksums; ones that fail it are skipped.
+pub fn tables() -> impl Iterator<Item = &'static SdtHeader> {
+    TABLES
+        .get()
+        .map_or(&[][..], |tables| &tables[..])
+        .iter()
+        .map(|&address| unsafe { &*(phys_to_virt(address) as *const SdtHeader) })
+        .filter(|table| table.is_valid())
+}
+
+/// Prints the signature and OEM of every table.
+pub fn print_tables() {
+    for table in tables() {
+        let oem_id = table.oem_id;
+        let oem_table_id = table.oem_table_id;
+        println!(
+            "  {} {} {} rev {} ({} bytes)",
+            core::str::from_utf8(&table.signature).unwrap_or("????"),
+            core::str::from_utf8(&oem_id).unwrap_or("?"),
+            core::str::from_utf8(&oem_table_id).unwrap_or("?"),
+            table.revision,
+            { table.length }
+        );
+    }
+}
+
+/// Prints what the parsed tables say about the machine.
+pub fn print_summary() {
+    if let Some(madt) = madt() {
+        println!(
+            "  {} processors, {} I/O APICs, {} interrupt overrides{}",
+            madt.processors.len(),
+            madt.io_apics.len(),
+            madt.overrides.len(),
+            if madt.has_legacy_pics {
+                ", legacy PICs"
+            } else {
+                ""
+            }
+        );
+    }
+    if let Some(hpet) = hpet() {
+        println!(
+            "  HPET at {:#x} with {} comparators",
+            hpet.address, hpet.comparator_count
+        );
+    }
+    if let Some(fadt) = fadt() {
+        println!(
+            "  SCI on IRQ {}, PM timer: {}, reset register: {}, power-off: {}",
+            fadt.sci_interrupt,
+            if fadt.pm_timer.is_some() { "yes" } else { "no" },
+            if fadt.reset.is_some() { "yes" } else { "no" },
+            if fadt.sleep_types_s5.is_some() {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+    }
+}
+
+/// The address space a generic address structure points into.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// A register location as ACPI describes it, in a 12-byte generic address structure (GAS).
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1 to 4 for byte to qword accesses, 0 for whatever `bit_width` implies.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parses the structure at the start of `bytes`. An all-zero address means the register
+    /// doesn't exist.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let address = read_le(bytes, 4, 8)?;
+        if address == 0 {
+            return None;
+        }
+        Some(Self {
+            space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address,
+        })
+    }
+
+    /// Makes the register accessible, mapping it if it is memory-mapped. Registers in other
+    /// address spaces aren't supported.
+    pub fn register(&self) -> Option<Register> {
+        let width = match self.access_size {
+            1 => 8,
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            _ => self.bit_width,
+        };
+        if !matches!(width, 8 | 16 | 32 | 64) {
+            return None;
+        }
+        match self.space {
+            AddressSpace::SystemIo if self.address <= 0xFFFF => Some(Register::Io {
+                port: self.address as u16,
+                width,
+            }),
+            AddressSpace::SystemMemory => Some(Register::Memory {
+                address: crate::memory::map_mmio(
+                    "acpi register",
+                    PhysAddr::new(self.address),
+                    width as u64 / 8,
+                ),
+                width,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A fixed hardware register, as given by a [`GenericAddress`] or one of the older FADT fields.
+#[derive(Clone, Copy, Debug)]
+pub enum Register {
+    Io { port: u16, width: u8 },
+    Memory { address: VirtAddr, width: u8 },
+}
+
+impl Register {
+    pub unsafe fn read(&self) -> u64 {
+        match *self {
+            Register::Io { port, width } => match width {
+                8 => Port::<u8>::new(port).read() as u64,
+                16 => Port::<u16>::new(port).read() as u64,
+                // There are no 64-bit port accesses
+                _ => Port::<u32>::new(port).read() as u64,
+            },
+            Register::Memory { address, width } => {
+                let address = address.as_u64();
+                match width {
+                    8 => (address as *const u8).read_volatile() as u64,
+                    16 => (address as *const u16).read_volatile() as u64,
+                    32 => (address as *const u32).read_volatile() as u64,
+                    _ => (address as *const u64).read_volatile(),
+                }
+            }
+        }
+    }
+
+    pub unsafe fn write(&self, value: u64) {
+        match *self {
+            Register::Io { port, width } => match width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                16 => Port::<u16>::new(port).write(value as u16),
+                _ => Port::<u32>::new(port).write(value as u32),
+            },
+            Register::Memory { address, width } => {
+                let address = address.as_u64();
+                match width {
+                    8 => (address as *mut u8).write_volatile(value as u8),
+                    16 => (address as *mut u16).write_volatile(value as u16),
+                    32 => (address as *mut u32).write_volatile(value as u32),
+                    _ => (address as *mut u64).write_volatile(value),
+                }
+            }
+        }
+    }
+}
+
+/// Reads the `size`-byte little-endian value at `offset`, if `bytes` is long enough. Tables grew
+/// over ACPI revisions, so fields near the end may be missing.
+fn read_le(bytes: &[u8], offset: usize, size: usize) -> Option<u64> {
+    let field = bytes.get(offset..offset.checked_add(size)?)?;
+    Some(
+        field
+            .iter()
+            .rev()
+            .fold(0u64, |value, &byte| value << 8 | byte as u64),
+    )
+}
+
+fn phys_to_virt(address: u64) -> u64 {
+    address + PHYSICAL_MEMORY_OFFSET
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
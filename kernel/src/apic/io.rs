@@ -0,0 +1,88 @@
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+use crate::acpi::madt::{Polarity, TriggerMode};
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+/// An I/O APIC, routing a range of global system interrupts to local APICs.
+pub struct IoApic {
+    /// Register accesses select a register and then read or write it, so they must not interleave.
+    base: Mutex<VirtAddr>,
+    pub gsis: Range<u32>,
+}
+
+impl IoApic {
+    /// Maps the I/O APIC at `address` and masks all of its inputs.
+    pub unsafe fn init(address: PhysAddr, gsi_base: u32) -> Self {
+        let base = crate::memory::map_mmio("I/O APIC", address, 0x20);
+        let mut apic = Self {
+            base: Mutex::new(base),
+            gsis: gsi_base..gsi_base,
+        };
+        let entries = (apic.read(REG_VERSION) >> 16 & 0xFF) + 1;
+        apic.gsis.end = gsi_base + entries;
+        for input in 0..entries {
+            apic.write_entry(input, ENTRY_MASKED);
+        }
+        apic
+    }
+
+    /// Routes `gsi` to `vector` on the local APIC with ID `destination`, and unmasks it.
+    pub unsafe fn route(
+        &self,
+        gsi: u32,
+        vector: u8,
+        destination: u32,
+        polarity: Polarity,
+        trigger_mode: TriggerMode,
+    ) {
+        let mut entry = vector as u64 | (destination as u64) << 56;
+        if polarity == Polarity::ActiveLow {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            entry |= ENTRY_LEVEL;
+        }
+        self.write_entry(gsi - self.gsis.start, entry);
+    }
+
+    pub unsafe fn mask(&self, gsi: u32) {
+        let register = REG_REDIRECTION + 2 * (gsi - self.gsis.start);
+        let low = self.read(register);
+        self.write(register, low | ENTRY_MASKED as u32);
+    }
+
+    unsafe fn write_entry(&self, input: u32, entry: u64) {
+        // Mask while the halves disagree, and write the low half with the mask bit last
+        self.write(REG_REDIRECTION + 2 * input, ENTRY_MASKED as u32);
+        self.write(REG_REDIRECTION + 2 * input + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + 2 * input, entry as u32);
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        interrupts::without_interrupts(|| {
+            let base = self.base.lock();
+            ((*base + IOREGSEL).as_u64() as *mut u32).write_volatile(register);
+            ((*base + IOWIN).as_u64() as *const u32).read_volatile()
+        })
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        interrupts::without_interrupts(|| {
+            let base = self.base.lock();
+            ((*base + IOREGSEL).as_u64() as *mut u32).write_volatile(register);
+            ((*base + IOWIN).as_u64() as *mut u32).write_volatile(value);
+        })
+    }
+}
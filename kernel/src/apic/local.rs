@@ -0,0 +1,111 @@
+use core::arch::x86_64::__cpuid;
+
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+use crate::acpi::madt::{LocalApicNmi, Polarity, TriggerMode};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+const REG_ID: u64 = 0x20;
+const REG_TASK_PRIORITY: u64 = 0x80;
+const REG_EOI: u64 = 0xB0;
+const REG_SPURIOUS: u64 = 0xF0;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_LVT_LINT0: u64 = 0x350;
+const REG_TIMER_INITIAL: u64 = 0x380;
+const REG_TIMER_CURRENT: u64 = 0x390;
+const REG_TIMER_DIVIDE: u64 = 0x3E0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// The timer counts at the bus clock divided by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Vector the local APIC delivers spurious interrupts on. They need no end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The current processor's local APIC, reached through its memory-mapped registers.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    /// Enables the local APIC at `address` in xAPIC mode and lets it accept every priority.
+    pub unsafe fn init(address: PhysAddr) -> Result<Self, &'static str> {
+        if __cpuid(1).edx & (1 << 9) == 0 {
+            return Err("the processor has no local APIC");
+        }
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        if value & APIC_BASE_X2APIC != 0 {
+            return Err("the local APIC is in x2APIC mode");
+        }
+        apic_base.write(value | APIC_BASE_ENABLE);
+
+        let apic = Self {
+            base: crate::memory::map_mmio("local APIC", address, 4096),
+        };
+        apic.write(REG_TASK_PRIORITY, 0);
+        apic.write(REG_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+        Ok(apic)
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(REG_ID) >> 24 }
+    }
+
+    /// Wires the LINT pin `nmi` describes to deliver NMIs.
+    pub unsafe fn set_nmi(&self, nmi: &LocalApicNmi) {
+        let mut entry = LVT_DELIVERY_NMI;
+        if nmi.polarity == Polarity::ActiveLow {
+            entry |= LVT_ACTIVE_LOW;
+        }
+        if nmi.trigger_mode == TriggerMode::Level {
+            entry |= LVT_LEVEL;
+        }
+        self.write(REG_LVT_LINT0 + 0x10 * nmi.lint as u64, entry);
+    }
+
+    /// Starts the timer counting down from the top without raising an interrupt, so that its rate
+    /// can be measured with [`timer_count`](Self::timer_count).
+    pub unsafe fn start_timer_measurement(&self) {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL, u32::MAX);
+    }
+
+    pub fn timer_count(&self) -> u32 {
+        unsafe { self.read(REG_TIMER_CURRENT) }
+    }
+
+    /// Raises `vector` every `count` timer ticks.
+    pub unsafe fn start_periodic_timer(&self, vector: u8, count: u32) {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        self.write(REG_TIMER_INITIAL, count);
+    }
+
+    pub unsafe fn stop_timer(&self) {
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL, 0);
+    }
+
+    pub unsafe fn end_of_interrupt(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    unsafe fn read(&self, register: u64) -> u32 {
+        ((self.base + register).as_u64() as *const u32).read_volatile()
+    }
+
+    unsafe fn write(&self, register: u64, value: u32) {
+        ((self.base + register).as_u64() as *mut u32).write_volatile(value)
+    }
+}
@@ -0,0 +1,130 @@
+mod io;
+mod local;
+
+pub use local::{LocalApic, SPURIOUS_VECTOR};
+
+use alloc::vec::Vec;
+
+use spin::Once;
+use x86_64::{structures::idt::InterruptStackFrame, PhysAddr};
+
+use self::io::IoApic;
+use crate::{
+    acpi::madt::{InterruptOverride, Madt, Polarity, TriggerMode},
+    idt,
+    irq::{InterruptController, ISA_VECTOR_BASE},
+};
+
+/// The boot processor's local APIC and every I/O APIC, delivering ISA IRQs to the boot processor.
+pub struct Apic {
+    local: LocalApic,
+    io_apics: Vec<IoApic>,
+    overrides: &'static [InterruptOverride],
+}
+
+static APIC: Once<Apic> = Once::new();
+
+/// Enables the local APIC and masks every I/O APIC input. The 8259 PICs are left to the caller.
+pub fn init(madt: &'static Madt) -> Result<&'static Apic, &'static str> {
+    if madt.io_apics.is_empty() {
+        return Err("the MADT lists no I/O APIC");
+    }
+    let local = unsafe { LocalApic::init(PhysAddr::new(madt.local_apic_address))? };
+
+    let id = local.id();
+    let uid = madt
+        .processors
+        .iter()
+        .find(|processor| processor.apic_id == id)
+        .map(|processor| processor.processor_uid);
+    for nmi in &madt.nmis {
+        if nmi.processor_uid.is_none() || nmi.processor_uid == uid {
+            unsafe { local.set_nmi(nmi) };
+        }
+    }
+
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|io_apic| unsafe { IoApic::init(PhysAddr::new(io_apic.address), io_apic.gsi_base) })
+        .collect();
+    unsafe { idt::register_isr(SPURIOUS_VECTOR as usize, spurious) };
+
+    Ok(APIC.call_once(|| Apic {
+        local,
+        io_apics,
+        overrides: &madt.overrides,
+    }))
+}
+
+/// ID of the boot processor's local APIC, which message-signaled interrupts are sent to. `None` if
+/// the APICs aren't in use.
+pub fn local_apic_id() -> Option<u32> {
+    local_apic().map(LocalApic::id)
+}
+
+/// The boot processor's local APIC, if the APICs are in use.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    APIC.get().map(|apic| &apic.local)
+}
+
+impl Apic {
+    /// The global system interrupt ISA IRQ `irq` arrives on, and how it signals.
+    fn isa_route(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        let (gsi, polarity, trigger_mode) = match self.overrides.iter().find(|o| o.isa_irq == irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger_mode),
+            None => (irq as u32, Polarity::BusDefault, TriggerMode::BusDefault),
+        };
+        // ISA interrupts are active high and edge triggered
+        let polarity = match polarity {
+            Polarity::BusDefault => Polarity::ActiveHigh,
+            polarity => polarity,
+        };
+        let trigger_mode = match trigger_mode {
+            TriggerMode::BusDefault => TriggerMode::Edge,
+            trigger_mode => trigger_mode,
+        };
+        (gsi, polarity, trigger_mode)
+    }
+
+    fn io_apic(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .find(|io_apic| io_apic.gsis.contains(&gsi))
+    }
+}
+
+impl InterruptController for Apic {
+    fn name(&self) -> &'static str {
+        "APIC"
+    }
+
+    unsafe fn enable_irq(&self, irq: u8) -> Result<(), &'static str> {
+        let (gsi, polarity, trigger_mode) = self.isa_route(irq);
+        let io_apic = self
+            .io_apic(gsi)
+            .ok_or("no I/O APIC handles the IRQ's interrupt")?;
+        io_apic.route(
+            gsi,
+            ISA_VECTOR_BASE + irq,
+            self.local.id(),
+            polarity,
+            trigger_mode,
+        );
+        Ok(())
+    }
+
+    unsafe fn disable_irq(&self, irq: u8) {
+        let (gsi, _, _) = self.isa_route(irq);
+        if let Some(io_apic) = self.io_apic(gsi) {
+            io_apic.mask(gsi);
+        }
+    }
+
+    unsafe fn end_of_interrupt(&self, _vector: u8) {
+        self.local.end_of_interrupt();
+    }
+}
+
+/// Spurious interrupts are not in service, so they must not be acknowledged.
+extern "x86-interrupt" fn spurious(_stack_frame: InterruptStackFrame) {}
@@ -0,0 +1,210 @@
+use core::fmt;
+
+use common::SymbolMap;
+use spin::Once;
+use x86_64::VirtAddr;
+
+use crate::memory;
+
+static SYMBOLS: Once<SymbolMap<'static>> = Once::new();
+
+/// Most frames printed in a backtrace.
+const MAX_FRAMES: usize = 32;
+/// Frames further apart than this are assumed to be garbage rather than a caller's frame.
+const MAX_FRAME_SIZE: usize = 0x10000;
+
+/// Makes `symbols` available for symbolizing addresses. They must not point into memory that is
+/// going to be reclaimed.
+pub fn init(symbols: SymbolMap<'static>) {
+    SYMBOLS.call_once(|| symbols);
+}
+
+/// The frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    rbp
+}
+
+/// The frame pointer of the code an exception interrupted. Must be called from the handler itself:
+/// the handler's prologue pushed the interrupted frame pointer, and its own points there.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> usize {
+    unsafe { *(frame_pointer() as *const usize) }
+}
+
+/// Return addresses found by following the chain of saved frame pointers, innermost first.
+pub struct Frames {
+    rbp: usize,
+    remaining: usize,
+}
+
+impl Frames {
+    pub fn new(rbp: usize) -> Self {
+        Self {
+            rbp,
+            remaining: MAX_FRAMES,
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.remaining == 0 || self.rbp == 0 || self.rbp % 8 != 0 {
+            return None;
+        }
+        // A corrupted chain must not make the backtrace fault itself, so only frames that lie
+        // entirely on a mapped kernel stack are read
+        let stack = memory::kernel_stack_containing(VirtAddr::try_new(self.rbp as u64).ok()?)?;
+        if (self.rbp + 2 * 8) as u64 > stack.end().as_u64() {
+            return None;
+        }
+        self.remaining -= 1;
+        let frame = self.rbp as *const usize;
+        let (next, return_address) = unsafe { (*frame, *frame.add(1)) };
+        // Callers' frames lie above ours on the same stack; anything else ends the chain
+        self.rbp = if next > self.rbp && next - self.rbp <= MAX_FRAME_SIZE {
+            next
+        } else {
+            0
+        };
+        match return_address {
+            0 => None,
+            address => Some(address),
+        }
+    }
+}
+
+/// Prints a backtrace starting at the frame `rbp` points to. If the backtrace is for an exception,
+/// `rip` is the faulting instruction, which has no frame of its own.
+pub fn print(rip: Option<usize>, rbp: usize) {
+    println!("Backtrace:");
+    let addresses = rip.into_iter().chain(Frames::new(rbp));
+    for (i, address) in addresses.enumerate() {
+        println!("  {:2}: {}", i, Symbolized(address));
+    }
+}
+
+/// Formats a code address as the address and the function it lies in.
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match SYMBOLS
+            .get()
+            .and_then(|symbols| symbols.lookup(self.0 as u64))
+        {
+            Some((name, offset)) => write!(f, " {}+{:#x}", Demangled(name), offset),
+            None => write!(f, " ??"),
+        }
+    }
+}
+
+/// Formats a legacy Rust symbol name (`_ZN...E`) as a path, without the hash. Other names are
+/// printed as they are. Nothing is allocated, so this works when the heap is broken.
+struct Demangled<'a>(&'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = match self.0.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+            Some(inner) if Components(inner).all(|c| c.is_some()) => inner,
+            _ => return f.write_str(self.0),
+        };
+        let count = Components(inner).count();
+        for (i, component) in Components(inner).flatten().enumerate() {
+            if i == count - 1 && is_hash(component) {
+                break;
+            }
+            if i > 0 {
+                f.write_str("::")?;
+            }
+            write_component(f, component)?;
+        }
+        Ok(())
+    }
+}
+
+/// The length-prefixed components of a mangled path; `None` if the name is malformed.
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Option<&'a str>;
+
+    fn next(&mut self) -> Option<Option<&'a str>> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let digits = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        let component = self.0[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|len| self.0.get(digits..digits + len));
+        match component {
+            Some(component) => self.0 = &self.0[digits + component.len()..],
+            None => self.0 = "",
+        }
+        Some(component)
+    }
+}
+
+/// Whether `component` is the `h` + 16 hex digits hash rustc appends to every path.
+fn is_hash(component: &str) -> bool {
+    component.len() == 17
+        && component.starts_with('h')
+        && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn write_component(f: &mut fmt::Formatter<'_>, component: &str) -> fmt::Result {
+    // A leading `_` only keeps an escape at the start from looking like a number
+    let mut rest = if component.starts_with("_$") {
+        &component[1..]
+    } else {
+        component
+    };
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('$') {
+            let end = match after.find('$') {
+                Some(end) => end,
+                None => return f.write_str(rest),
+            };
+            match unescape(&after[..end]) {
+                Some(c) => write!(f, "{}", c)?,
+                None => f.write_str(&rest[..end + 2])?,
+            }
+            rest = &after[end + 1..];
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| c == '$' || c == '.')
+                .map_or(rest.len(), |(end, _)| end);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+fn unescape(escape: &str) -> Option<char> {
+    Some(match escape {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => {
+            let code = escape.strip_prefix('u')?;
+            core::char::from_u32(u32::from_str_radix(code, 16).ok()?)?
+        }
+    })
+}
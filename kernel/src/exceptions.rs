@@ -1,4 +1,13 @@
-use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    backtrace::{self, Symbolized},
+    memory::{self, PageFault, Region},
+};
 
 pub extern "x86-interrupt" fn alignment_check(_stack_frame: InterruptStackFrame, _error_code: u64) {
     println!("\n");
@@ -29,15 +38,17 @@ pub extern "x86-interrupt" fn general_protection_fault(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    let rbp = backtrace::interrupted_frame_pointer();
     println!("\ngeneral_protection_fault\n");
     println!("ss: {:x}", error_code);
     let ip = stack_frame.instruction_pointer;
-    println!("ip: {:x}", ip);
+    println!("ip: {}", Symbolized(ip.as_u64() as usize));
     let code = ip.as_ptr::<u8>();
     for i in 0..32 {
         print!("{:x} ", unsafe { code.add(i).read_volatile() });
     }
     println!();
+    backtrace::print(Some(ip.as_u64() as usize), rbp);
     loop {}
 }
 
@@ -111,23 +122,102 @@ pub extern "x86-interrupt" fn breakpoint(_stack_frame: InterruptStackFrame) {
 
 pub extern "x86-interrupt" fn page_fault(
     stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: PageFaultErrorCode,
 ) {
+    let addr = Cr2::read();
+    let rbp = backtrace::interrupted_frame_pointer();
+    let rip = stack_frame.instruction_pointer.as_u64() as usize;
+    let region = match memory::handle_page_fault(addr, error_code) {
+        PageFault::Resolved => return,
+        PageFault::StackOverflow(region) => report_stack_overflow(&stack_frame, &region, addr, rbp),
+        PageFault::OutOfMemory(region) => {
+            println!(
+                "\nOut of physical memory backing 0x{:x} in region '{}'",
+                addr, region.name
+            );
+            println!("Caused by instruction at {}", Symbolized(rip));
+            backtrace::print(Some(rip), rbp);
+            loop {}
+        }
+        PageFault::Unhandled(region) => region,
+    };
+
+    println!("\nPage fault while trying to access 0x{:x}", addr);
+    println!("Caused by instruction at {}", Symbolized(rip));
     println!(
-        "\nPage fault while trying to access 0x{:x}",
-        x86_64::registers::control::Cr2::read()
-    );
-    println!(
-        "Caused by instruction at 0x{:x}",
-        stack_frame.instruction_pointer
+        "Error code {:#x}: {} {} in {} mode{}",
+        error_code.bits(),
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation on"
+        } else {
+            "non-present page on"
+        },
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        },
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        },
+        if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            ", reserved bit set in page table"
+        } else {
+            ""
+        },
     );
+    match region {
+        Some(region) => println!(
+            "Address lies in region '{}' ({:?})",
+            region.name, region.kind
+        ),
+        None => println!("Address lies in no known region"),
+    }
+    match memory::walk(addr) {
+        Some(walk) => print!("Page-table walk:\n{}", walk),
+        None => println!("Page tables are locked; no walk available"),
+    }
+    backtrace::print(Some(rip), rbp);
     loop {}
 }
 
 pub extern "x86-interrupt" fn double_fault(
-    _stack_frame: InterruptStackFrame,
+    stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    println!("\ndouble fault");
+    let rbp = backtrace::interrupted_frame_pointer();
+    // Overflowing a stack faults again when the CPU pushes the page fault's frame onto the guard
+    // page, which ends up here rather than in `page_fault`
+    let addr = Cr2::read();
+    if let Some(region) = memory::stack_guard_hit(addr) {
+        report_stack_overflow(&stack_frame, &region, addr, rbp);
+    }
+    let rip = stack_frame.instruction_pointer.as_u64() as usize;
+    println!("\ndouble fault at {}", Symbolized(rip));
+    backtrace::print(Some(rip), rbp);
+    loop {}
+}
+
+fn report_stack_overflow(
+    stack_frame: &InterruptStackFrame,
+    region: &Region,
+    addr: VirtAddr,
+    rbp: usize,
+) -> ! {
+    let rip = stack_frame.instruction_pointer.as_u64() as usize;
+    println!(
+        "\nStack overflow on '{}': hit guard page at 0x{:x}",
+        region.name, addr
+    );
+    println!(
+        "Caused by instruction at {}, stack pointer 0x{:x}",
+        Symbolized(rip),
+        stack_frame.stack_pointer
+    );
+    backtrace::print(Some(rip), rbp);
     loop {}
 }
@@ -0,0 +1,25 @@
+//! Filesystems. So far there is only the read-only archive the bootloader loads as initial ramdisk.
+
+pub mod tar;
+
+use core::ops::Range;
+
+use spin::Once;
+
+use crate::memory::PHYSICAL_MEMORY_OFFSET;
+pub use tar::{EntryKind, TarError, TarFs};
+
+static INITRD: Once<TarFs> = Once::new();
+
+/// Mounts the archive at the physical range `range` as the initial ramdisk. The range must have
+/// been kept from being reclaimed.
+pub fn mount_initrd(range: Range<u64>) -> Result<&'static TarFs, TarError> {
+    let data = unsafe {
+        core::slice::from_raw_parts(
+            (range.start + PHYSICAL_MEMORY_OFFSET) as *const u8,
+            (range.end - range.start) as usize,
+        )
+    };
+    let fs = TarFs::parse(data)?;
+    Ok(INITRD.call_once(|| fs))
+}
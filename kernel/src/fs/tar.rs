@@ -0,0 +1,215 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+pub struct Entry {
+    /// Path relative to the root of the archive, without leading `./` or `/` and without a
+    /// trailing `/`.
+    pub path: String,
+    pub kind: EntryKind,
+    pub data: &'static [u8],
+}
+
+/// Something wrong with the header at the given offset of the archive.
+#[derive(Clone, Copy, Debug)]
+pub enum TarError {
+    BadChecksum(usize),
+    BadHeader(usize),
+    Truncated(usize),
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TarError::BadChecksum(offset) => {
+                write!(f, "header at offset {:#x} has a bad checksum", offset)
+            }
+            TarError::BadHeader(offset) => write!(f, "header at offset {:#x} is malformed", offset),
+            TarError::Truncated(offset) => write!(
+                f,
+                "contents of the file at offset {:#x} run past the end of the archive",
+                offset
+            ),
+        }
+    }
+}
+
+/// A read-only filesystem over a ustar archive in memory. File contents are not copied; they stay
+/// where the archive is.
+///
+/// Regular files and directories are supported, with long paths from GNU and pax extension headers;
+/// links, devices and the other extension headers are skipped.
+pub struct TarFs {
+    entries: Vec<Entry>,
+}
+
+impl TarFs {
+    pub fn parse(data: &'static [u8]) -> Result<Self, TarError> {
+        let mut entries = Vec::new();
+        // Set by an extension header for the entry that follows it
+        let mut long_path = None;
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= data.len() {
+            let header = &data[offset..offset + BLOCK_SIZE];
+            // The archive ends with zeroed blocks
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            let checksum = octal(&header[148..156]).ok_or(TarError::BadHeader(offset))?;
+            if checksum != header_checksum(header) {
+                return Err(TarError::BadChecksum(offset));
+            }
+            let size = octal(&header[124..136]).ok_or(TarError::BadHeader(offset))? as usize;
+            let start = offset + BLOCK_SIZE;
+            let contents = start
+                .checked_add(size)
+                .and_then(|end| data.get(start..end))
+                .ok_or(TarError::Truncated(offset))?;
+
+            let kind = match header[156] {
+                b'0' | 0 => Some(EntryKind::File),
+                b'5' => Some(EntryKind::Directory),
+                // GNU long name: the contents are the path of the next entry
+                b'L' => {
+                    let path = c_string(contents).ok_or(TarError::BadHeader(offset))?;
+                    long_path = Some(String::from(normalize(path)));
+                    None
+                }
+                // pax extended header for the next entry, which may hold its path
+                b'x' => {
+                    if let Some(path) = pax_path(contents).ok_or(TarError::BadHeader(offset))? {
+                        long_path = Some(String::from(normalize(path)));
+                    }
+                    None
+                }
+                _ => {
+                    long_path = None;
+                    None
+                }
+            };
+            if let Some(kind) = kind {
+                let path = match long_path.take() {
+                    Some(path) => path,
+                    None => path(header).ok_or(TarError::BadHeader(offset))?,
+                };
+                // The root directory itself
+                if !path.is_empty() {
+                    entries.push(Entry {
+                        path,
+                        kind,
+                        data: contents,
+                    });
+                }
+            }
+            offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn find(&self, path: &str) -> Option<&Entry> {
+        let path = normalize(path);
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// The contents of the file at `path`.
+    pub fn read(&self, path: &str) -> Option<&'static [u8]> {
+        match self.find(path) {
+            Some(entry) if entry.kind == EntryKind::File => Some(entry.data),
+            _ => None,
+        }
+    }
+}
+
+/// Sum of the header bytes, with the checksum field counted as spaces.
+fn header_checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum()
+}
+
+/// Parses a NUL- or space-terminated octal field.
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+    let mut value = 0u64;
+    let mut any = false;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return None;
+        }
+        value = value.checked_mul(8)? + (digit - b'0') as u64;
+        any = true;
+    }
+    if any {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// The path of an entry, with the ustar prefix joined in front of the name.
+fn path(header: &[u8]) -> Option<String> {
+    let name = c_string(&header[0..100])?;
+    let mut path = String::new();
+    if &header[257..262] == b"ustar" {
+        let prefix = c_string(&header[345..500])?;
+        if !prefix.is_empty() {
+            path.push_str(prefix);
+            path.push('/');
+        }
+    }
+    path.push_str(name);
+    Some(String::from(normalize(&path)))
+}
+
+/// The `path` record of a pax extended header, if it has one. Records are `<length> <key>=<value>\n`,
+/// the length counting the whole record.
+fn pax_path(mut records: &[u8]) -> Option<Option<&str>> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|&b| b == b' ')?;
+        let length: usize = core::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        let record = records.get(space + 1..length)?.strip_suffix(b"\n")?;
+        let equals = record.iter().position(|&b| b == b'=')?;
+        if &record[..equals] == b"path" {
+            path = Some(core::str::from_utf8(&record[equals + 1..]).ok()?);
+        }
+        records = &records[length..];
+    }
+    Some(path)
+}
+
+fn c_string(field: &[u8]) -> Option<&str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).ok()
+}
+
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_end_matches('/');
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if path == "." {
+            return "";
+        } else {
+            return path;
+        }
+    }
+}
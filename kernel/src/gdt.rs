@@ -0,0 +1,63 @@
+use spin::Once;
+use x86_64::{
+    instructions::{segmentation, tables},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+};
+
+use crate::memory;
+
+/// Interrupt stack table slots. Exceptions that can happen while the current stack is unusable, such
+/// as a double fault caused by a stack overflow, switch to one of these.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Size of each interrupt stack. Handlers on them print backtraces, which needs some room.
+const IST_STACK_SIZE: u64 = 32 * 1024;
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// Replaces the bootloader's GDT with one that has a TSS, with guarded stacks allocated for each
+/// interrupt stack table slot. Must run before the IDT refers to those slots.
+pub fn init() {
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        let stacks = [
+            (DOUBLE_FAULT_IST_INDEX, "double fault stack"),
+            (NMI_IST_INDEX, "NMI stack"),
+            (MACHINE_CHECK_IST_INDEX, "machine check stack"),
+        ];
+        for &(index, name) in &stacks {
+            let stack = memory::allocate_kernel_stack(name, IST_STACK_SIZE)
+                .expect("Failed allocating interrupt stack");
+            tss.interrupt_stack_table[index as usize] = stack.end();
+        }
+        tss
+    });
+    let (gdt, selectors) = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+        (gdt, Selectors { code, data, tss })
+    });
+
+    gdt.load();
+    unsafe {
+        segmentation::set_cs(selectors.code);
+        segmentation::load_ss(selectors.data);
+        segmentation::load_ds(selectors.data);
+        segmentation::load_es(selectors.data);
+        tables::load_tss(selectors.tss);
+    }
+}
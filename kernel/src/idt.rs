@@ -1,9 +1,12 @@
 use crate::exceptions::*;
+use crate::gdt;
 use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 static IDT: Mutex<InterruptDescriptorTable> = Mutex::new(InterruptDescriptorTable::new());
 
+/// Must be called after [`gdt::init`], as the double fault, NMI and machine check handlers run on
+/// the interrupt stacks it sets up.
 pub unsafe fn initialize_idt() {
     let mut idt = IDT.lock();
     idt.alignment_check.set_handler_fn(alignment_check);
@@ -14,14 +17,19 @@ pub unsafe fn initialize_idt() {
     idt.device_not_available
         .set_handler_fn(device_not_available);
     idt.divide_error.set_handler_fn(divide_error);
-    idt.double_fault.set_handler_fn(double_fault);
+    idt.double_fault
+        .set_handler_fn(double_fault)
+        .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     idt.general_protection_fault
         .set_handler_fn(general_protection_fault);
     idt.invalid_opcode.set_handler_fn(invalid_opcode);
     idt.invalid_tss.set_handler_fn(invalid_tss);
-    idt.machine_check.set_handler_fn(machine_check);
+    idt.machine_check
+        .set_handler_fn(machine_check)
+        .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
     idt.non_maskable_interrupt
-        .set_handler_fn(non_maskable_interrupt);
+        .set_handler_fn(non_maskable_interrupt)
+        .set_stack_index(gdt::NMI_IST_INDEX);
     idt.overflow.set_handler_fn(overflow);
     idt.page_fault.set_handler_fn(page_fault);
     idt.security_exception.set_handler_fn(security_exception);
@@ -38,7 +46,8 @@ pub unsafe fn initialize_idt() {
     idt.load_unsafe();
 }
 
-/// Note that IRQ's start at index 32 (0x20)
+/// For exceptions and system vectors; device interrupts are registered through [`crate::irq`],
+/// which owns vectors 0x20 to 0xEF.
 pub unsafe fn register_isr(index: usize, handler: extern "x86-interrupt" fn(InterruptStackFrame)) {
     let mut idt = IDT.lock();
     idt[index].set_handler_fn(handler);
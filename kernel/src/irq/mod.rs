@@ -0,0 +1,259 @@
+mod stubs;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::{acpi, apic, idt, pic};
+
+/// ISA IRQ `n` is delivered on vector `ISA_VECTOR_BASE + n`, whichever controller is in use.
+pub const ISA_VECTOR_BASE: u8 = 0x20;
+const ISA_IRQS: u8 = 16;
+
+/// Device interrupts use vectors `FIRST_VECTOR..FIRST_VECTOR + VECTOR_COUNT`: the ISA IRQs, then
+/// the ones handed out by [`allocate_vector`]. The vectors above are left for the system, like the
+/// APIC's spurious vector.
+const FIRST_VECTOR: u8 = ISA_VECTOR_BASE;
+const VECTOR_COUNT: usize = 0xD0;
+
+/// Whatever delivers device interrupts to the processor: the 8259 PICs or the APICs. IRQ numbers are
+/// ISA IRQs; the controller takes care of where they are actually wired.
+pub trait InterruptController: Sync {
+    fn name(&self) -> &'static str;
+    /// Unmasks `irq`, delivering it on vector `ISA_VECTOR_BASE + irq`.
+    unsafe fn enable_irq(&self, irq: u8) -> Result<(), &'static str>;
+    unsafe fn disable_irq(&self, irq: u8);
+    /// Acknowledges the interrupt on `vector`. [`dispatch`] does this after running the handlers.
+    unsafe fn end_of_interrupt(&self, vector: u8);
+}
+
+/// What a handler made of an interrupt. Lines can be shared, so a handler that finds its device
+/// didn't interrupt says so.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IrqReturn {
+    Handled,
+    NotMine,
+}
+
+/// Identifies a registered handler, for [`unregister`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HandlerId {
+    vector: u8,
+    id: u32,
+}
+
+/// A registered handler. Handlers are closures, so whatever state they need comes along with them.
+struct Action {
+    id: u32,
+    name: &'static str,
+    handler: Box<dyn Fn() -> IrqReturn + Send + Sync>,
+}
+
+struct Vector {
+    actions: Mutex<Vec<Action>>,
+    count: AtomicU64,
+}
+
+const UNUSED_VECTOR: Vector = Vector {
+    actions: Mutex::new(Vec::new()),
+    count: AtomicU64::new(0),
+};
+
+static VECTORS: [Vector; VECTOR_COUNT] = [UNUSED_VECTOR; VECTOR_COUNT];
+/// Which vectors [`allocate_vector`] has handed out, indexed like `VECTORS`.
+static ALLOCATED: Mutex<[bool; VECTOR_COUNT]> = Mutex::new([false; VECTOR_COUNT]);
+static NEXT_HANDLER_ID: AtomicU32 = AtomicU32::new(0);
+/// Interrupts no handler claimed.
+static UNCLAIMED: AtomicU64 = AtomicU64::new(0);
+
+static CONTROLLER: Once<&'static dyn InterruptController> = Once::new();
+
+/// Installs the entry points for all device vectors, and sets up the APICs if the MADT describes
+/// them and `use_apic` is set, or the 8259 PICs otherwise. Must run after [`acpi::init`] and before
+/// interrupts are enabled.
+pub fn init(use_apic: bool) -> &'static dyn InterruptController {
+    for vector in FIRST_VECTOR..FIRST_VECTOR + VECTOR_COUNT as u8 {
+        unsafe { idt::register_isr(vector as usize, stubs::stub_for(vector)) };
+    }
+
+    let madt = acpi::madt();
+    let apic = match madt {
+        Some(madt) if use_apic => match apic::init(madt) {
+            Ok(apic) => Some(apic),
+            Err(e) => {
+                println!("APIC unusable, falling back to the 8259 PIC: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    // The PICs are remapped even when masked, so that anything they still raise doesn't land on an
+    // exception vector
+    let controller: &'static dyn InterruptController = match apic {
+        Some(apic) => {
+            if madt.map_or(false, |madt| madt.has_legacy_pics) {
+                unsafe {
+                    pic::initialize();
+                    pic::disable();
+                }
+            }
+            apic
+        }
+        None => {
+            unsafe { pic::initialize() };
+            &pic::Pic
+        }
+    };
+    *CONTROLLER.call_once(|| controller)
+}
+
+pub fn controller() -> &'static dyn InterruptController {
+    *CONTROLLER
+        .get()
+        .expect("Interrupt controller used before irq::init")
+}
+
+/// Hands out an unused vector above the ISA ones, for interrupts that aren't wired to an IRQ line,
+/// like MSIs.
+pub fn allocate_vector() -> Option<u8> {
+    allocate_vectors(1)
+}
+
+/// Hands out `count` consecutive unused vectors and returns the first. It is aligned to `count`
+/// rounded up to a power of two, as multi-message MSI needs.
+pub fn allocate_vectors(count: usize) -> Option<u8> {
+    if count == 0 {
+        return None;
+    }
+    let align = count.next_power_of_two();
+    interrupts::without_interrupts(|| {
+        let mut allocated = ALLOCATED.lock();
+        let first = (ISA_IRQS as usize..VECTOR_COUNT.checked_sub(count)? + 1).find(|&i| {
+            (FIRST_VECTOR as usize + i) % align == 0 && !allocated[i..i + count].contains(&true)
+        })?;
+        for slot in &mut allocated[first..first + count] {
+            *slot = true;
+        }
+        Some(FIRST_VECTOR + first as u8)
+    })
+}
+
+/// Returns `count` vectors starting at `first` to the pool. Their handlers should be unregistered
+/// first.
+pub fn free_vectors(first: u8, count: usize) {
+    let first = (first - FIRST_VECTOR) as usize;
+    interrupts::without_interrupts(|| {
+        for slot in &mut ALLOCATED.lock()[first..first + count] {
+            *slot = false;
+        }
+    });
+}
+
+/// Adds `handler` to the handlers run for `vector`. Handlers run with interrupts disabled, and must
+/// not register or unregister handlers themselves.
+pub fn register(
+    vector: u8,
+    name: &'static str,
+    handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+) -> HandlerId {
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    let action = Action {
+        id,
+        name,
+        handler: Box::new(handler),
+    };
+    interrupts::without_interrupts(|| vector_slot(vector).actions.lock().push(action));
+    HandlerId { vector, id }
+}
+
+/// Adds `handler` to the handlers of ISA IRQ `irq`, which is unmasked when it gets its first one.
+/// Other drivers may share the line.
+pub fn register_isa(
+    irq: u8,
+    name: &'static str,
+    handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+) -> Result<HandlerId, &'static str> {
+    if irq >= ISA_IRQS {
+        return Err("not an ISA IRQ");
+    }
+    let id = register(ISA_VECTOR_BASE + irq, name, handler);
+    if handler_count(id.vector) == 1 {
+        if let Err(e) = unsafe { controller().enable_irq(irq) } {
+            unregister(id);
+            return Err(e);
+        }
+    }
+    Ok(id)
+}
+
+/// Removes a handler. An ISA IRQ left without handlers is masked again.
+pub fn unregister(id: HandlerId) {
+    interrupts::without_interrupts(|| {
+        vector_slot(id.vector)
+            .actions
+            .lock()
+            .retain(|action| action.id != id.id)
+    });
+    let irq = id.vector - ISA_VECTOR_BASE;
+    if irq < ISA_IRQS && handler_count(id.vector) == 0 {
+        unsafe { controller().disable_irq(irq) };
+    }
+}
+
+fn handler_count(vector: u8) -> usize {
+    interrupts::without_interrupts(|| vector_slot(vector).actions.lock().len())
+}
+
+fn vector_slot(vector: u8) -> &'static Vector {
+    assert!(
+        (FIRST_VECTOR..FIRST_VECTOR + VECTOR_COUNT as u8).contains(&vector),
+        "Vector {:#x} is not a device vector",
+        vector
+    );
+    &VECTORS[(vector - FIRST_VECTOR) as usize]
+}
+
+/// Runs every handler of `vector`, since any of the devices sharing it may have interrupted, and
+/// acknowledges the interrupt.
+fn dispatch(vector: u8) {
+    let slot = &VECTORS[(vector - FIRST_VECTOR) as usize];
+    slot.count.fetch_add(1, Ordering::Relaxed);
+    let claimed = slot.actions.lock().iter().fold(false, |claimed, action| {
+        (action.handler)() == IrqReturn::Handled || claimed
+    });
+    if !claimed {
+        UNCLAIMED.fetch_add(1, Ordering::Relaxed);
+    }
+    unsafe { controller().end_of_interrupt(vector) };
+}
+
+/// Prints how often each vector that has handlers or has fired was raised.
+pub fn print_stats() {
+    println!("Interrupts ({}):", controller().name());
+    for (i, slot) in VECTORS.iter().enumerate() {
+        let vector = FIRST_VECTOR + i as u8;
+        let count = slot.count.load(Ordering::Relaxed);
+        let names = interrupts::without_interrupts(|| {
+            slot.actions
+                .lock()
+                .iter()
+                .map(|action| action.name)
+                .collect::<Vec<_>>()
+        });
+        if count == 0 && names.is_empty() {
+            continue;
+        }
+        match vector - ISA_VECTOR_BASE {
+            irq if irq < ISA_IRQS => print!("  {:#04x} IRQ {:2}", vector, irq),
+            _ => print!("  {:#04x}       ", vector),
+        }
+        println!(" {:8} {}", count, names.join(", "));
+    }
+    println!(
+        "  {} not claimed by any handler",
+        UNCLAIMED.load(Ordering::Relaxed)
+    );
+}
@@ -0,0 +1,55 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{FIRST_VECTOR, VECTOR_COUNT};
+
+pub type Stub = extern "x86-interrupt" fn(InterruptStackFrame);
+
+/// Every device vector gets its own entry point, which only passes the vector number on.
+extern "x86-interrupt" fn stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    super::dispatch(VECTOR);
+}
+
+macro_rules! stub_row {
+    ($high:literal) => {
+        [
+            stub::<{ $high * 16 }>,
+            stub::<{ $high * 16 + 1 }>,
+            stub::<{ $high * 16 + 2 }>,
+            stub::<{ $high * 16 + 3 }>,
+            stub::<{ $high * 16 + 4 }>,
+            stub::<{ $high * 16 + 5 }>,
+            stub::<{ $high * 16 + 6 }>,
+            stub::<{ $high * 16 + 7 }>,
+            stub::<{ $high * 16 + 8 }>,
+            stub::<{ $high * 16 + 9 }>,
+            stub::<{ $high * 16 + 10 }>,
+            stub::<{ $high * 16 + 11 }>,
+            stub::<{ $high * 16 + 12 }>,
+            stub::<{ $high * 16 + 13 }>,
+            stub::<{ $high * 16 + 14 }>,
+            stub::<{ $high * 16 + 15 }>,
+        ]
+    };
+}
+
+/// Entry points for vectors `FIRST_VECTOR..FIRST_VECTOR + VECTOR_COUNT`, sixteen to a row.
+static STUBS: [[Stub; 16]; VECTOR_COUNT / 16] = [
+    stub_row!(0x2),
+    stub_row!(0x3),
+    stub_row!(0x4),
+    stub_row!(0x5),
+    stub_row!(0x6),
+    stub_row!(0x7),
+    stub_row!(0x8),
+    stub_row!(0x9),
+    stub_row!(0xA),
+    stub_row!(0xB),
+    stub_row!(0xC),
+    stub_row!(0xD),
+    stub_row!(0xE),
+];
+
+pub fn stub_for(vector: u8) -> Stub {
+    let index = (vector - FIRST_VECTOR) as usize;
+    STUBS[index / 16][index % 16]
+}
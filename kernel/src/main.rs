@@ -17,12 +17,22 @@
 #![feature(const_option)]
 #![feature(const_precise_live_drops)]
 
+mod acpi;
+mod apic;
+mod backtrace;
 mod exceptions;
+mod fs;
+mod gdt;
 mod graphics;
 mod idt;
+mod irq;
 mod pata;
+mod pci;
 mod pic;
 mod ps2;
+mod rtc;
+mod time;
+mod timer;
 mod usb;
 
 use graphics::{Pixel, Rect};
@@ -39,52 +49,158 @@ use ps2::Ps2Driver;
 
 use core::panic::PanicInfo;
 
-use common::{Framebuffer, MachineInfo, MachineInfoC};
-use x86_64::structures::{idt::InterruptStackFrame, paging::PageTable};
+use common::{
+    boot_log::Level,
+    efi::{BootState, RuntimeServices},
+    BootLog, Framebuffer, MachineInfo, MachineInfoC, SymbolMap, Verbosity,
+};
+use x86_64::{
+    instructions::interrupts,
+    structures::{idt::InterruptStackFrame, paging::PageTable},
+};
 
 extern crate rlibc;
 mod memory;
 
 #[no_mangle]
-pub extern "sysv64" fn _start(machine_info: MachineInfoC) -> ! {
+pub extern "sysv64" fn _start(machine_info: &'static MachineInfoC) -> ! {
+    if let Err(e) = machine_info.validate() {
+        // Nothing in the block can be trusted unless it at least carries the magic number
+        if !machine_info.has_valid_magic() {
+            halt();
+        }
+        unsafe { common::writer::init(machine_info.framebuffer()) };
+        common::writer::clear();
+        panic!("Incompatible bootloader: {}", e);
+    }
     let machine_info: MachineInfo = machine_info.into();
     let page_table = x86_64::registers::control::Cr3::read()
         .0
         .start_address()
         .as_u64() as *mut PageTable;
     let page_table = unsafe { page_table.as_mut() }.unwrap();
-    memory::init(page_table, machine_info.allocated_frames);
+    memory::init(page_table, machine_info.memory_map);
 
     unsafe { common::writer::init(machine_info.framebuffer) };
     common::writer::clear();
 
+    // The bootloader left us on the firmware's stack, which has no guard page and is about to be
+    // reclaimed, so move to one of our own before doing anything else
+    let stack = memory::allocate_kernel_stack("boot stack", BOOT_STACK_SIZE)
+        .expect("Failed allocating the boot stack");
+    unsafe {
+        asm!(
+            "mov rsp, {}",
+            "xor ebp, ebp",
+            "call {}",
+            in(reg) stack.end().as_u64(),
+            in(reg) kernel_main as usize,
+            in("rdi") &machine_info as *const MachineInfo,
+            options(noreturn)
+        )
+    }
+}
+
+/// Size of the stack the kernel runs on after leaving the bootloader's.
+const BOOT_STACK_SIZE: u64 = 128 * 1024;
+
+/// Continues booting on the kernel's own stack. `machine_info` lives on the old stack, so it is
+/// moved out before that gets reclaimed.
+extern "sysv64" fn kernel_main(machine_info: &MachineInfo) -> ! {
+    let mut machine_info = unsafe { core::ptr::read(machine_info) };
+
+    // The heap is backed on demand by the page-fault handler, so the IDT must be up before anything
+    // allocates
+    gdt::init();
     unsafe {
         idt::initialize_idt();
     }
 
-    // println!("xhci_base: {:x}", machine_info.xhci_base);
+    // Everything the bootloader allocated is about to be handed to the frame allocator, so keep
+    // copies of what the boot info points to
+    machine_info.memory_map = machine_info.memory_map.to_vec().leak();
+    machine_info.command_line = Box::leak(machine_info.command_line.into());
+    machine_info.symbols = SymbolMap {
+        symbols: machine_info.symbols.symbols.to_vec().leak(),
+        names: machine_info.symbols.names.to_vec().leak(),
+    };
+    machine_info.boot_log.bytes = machine_info.boot_log.bytes.to_vec().leak();
+    backtrace::init(machine_info.symbols);
+    if let Some(rsdp) = machine_info.rsdp_address {
+        memory::preserve_boot_range(rsdp..rsdp + 36);
+    }
+    if let Some(initrd) = &machine_info.initrd {
+        memory::preserve_boot_range(initrd.clone());
+    }
+    let reclaimed = memory::reclaim_boot_memory(
+        machine_info.memory_map,
+        machine_info.kernel_phys_range.clone(),
+    );
+    println!("Reclaimed {} KiB of bootloader memory", reclaimed >> 10);
+
+    println!("Command line: {}", machine_info.command_line);
+    print_boot_log(machine_info.boot_log, machine_info.command_line);
+    println!(
+        "{} MiB of physical memory free",
+        memory::free_frames() * 4096 >> 20
+    );
+    if machine_info.verbosity >= Verbosity::Verbose {
+        println!("Kernel slide: {:#x}", machine_info.kernel_slide);
+        memory::print_layout();
+    }
+    if let Some(initrd) = machine_info.initrd.clone() {
+        mount_initrd(initrd, machine_info.verbosity);
+    }
+    match machine_info.rsdp_address.map(acpi::init) {
+        Some(Ok(())) if machine_info.verbosity >= Verbosity::Verbose => {
+            println!("ACPI tables:");
+            acpi::print_tables();
+            acpi::print_summary();
+        }
+        Some(Ok(())) => {}
+        Some(Err(e)) => println!("ACPI unusable: {}", e),
+        None => println!("No ACPI tables"),
+    }
+
+    let use_apic = !machine_info
+        .command_line
+        .split_whitespace()
+        .any(|option| option == "noapic");
+    let controller = irq::init(use_apic);
+    println!("Interrupt controller: {}", controller.name());
+    if let Err(e) = timer::init() {
+        println!("No timer interrupts: {}", e);
+    }
+    time::init(machine_info.boot_time);
 
     // Enable interrupts
     unsafe { asm!("sti", options(nostack, nomem)) }
 
-    // let driver = unsafe {
-    //     usb::xhci::XhciDriver::new(machine_info.xhci_base as _)
-    // };
-
     let mut ps2_driver = Ps2Driver::new();
     unsafe {
-        pic::initialize();
         ps2_driver.initialize();
     }
 
+    pci::register_driver(&pata::DRIVER);
+    pci::register_driver(&usb::xhci::DRIVER);
+    pci::init();
+    println!("PCI: {} functions", pci::devices().len());
+    if machine_info.verbosity >= Verbosity::Verbose {
+        pci::print_devices();
+    }
+
     let mut first_sector = [0; 512];
 
     unsafe {
-        pata::init();
-        pata::read_sectors(DiskSelect::Master, 0, &mut first_sector).unwrap();
-        println!("First sectors of master: {:x?}", first_sector);
+        match pata::read_sectors(DiskSelect::Master, 0, &mut first_sector) {
+            Ok(()) => println!("First sectors of master: {:x?}", first_sector),
+            Err(e) => println!("Could not read from the master disk: {}", e),
+        }
     }
 
+    if let Some(address) = machine_info.runtime_services_address {
+        report_boot_success(address);
+    }
 
     // unsafe { graphics::init(machine_info.framebuffer); }
 
@@ -96,6 +212,82 @@ pub extern "sysv64" fn _start(machine_info: MachineInfoC) -> ! {
     loop {}
 }
 
+/// Replays the bootloader's log if the command line asks for it: `bootlog` shows everything,
+/// `bootlog=<level>` only messages at least that severe. Otherwise only says whether there were
+/// problems.
+fn print_boot_log(boot_log: BootLog, command_line: &str) {
+    let level = command_line
+        .split_whitespace()
+        .find_map(|option| match option {
+            "bootlog" => Some(Level::Debug),
+            _ => option.strip_prefix("bootlog=").and_then(Level::from_name),
+        });
+    let level = match level {
+        Some(level) => level,
+        None => {
+            let problems = boot_log
+                .records()
+                .filter(|&(level, _)| level <= Level::Warn)
+                .count();
+            if problems > 0 {
+                println!(
+                    "Bootloader logged {} warnings or errors; boot with `bootlog=warn` to see them",
+                    problems
+                );
+            }
+            return;
+        }
+    };
+    println!("Boot log:");
+    for (record_level, message) in boot_log.records() {
+        if record_level <= level {
+            println!("  [{:5}] {}", record_level.name(), message);
+        }
+    }
+    if boot_log.dropped > 0 {
+        println!("  ({} more messages didn't fit)", boot_log.dropped);
+    }
+}
+
+fn mount_initrd(range: core::ops::Range<u64>, verbosity: Verbosity) {
+    let initrd = match fs::mount_initrd(range) {
+        Ok(initrd) => initrd,
+        Err(e) => {
+            println!("Initrd unusable: {}", e);
+            return;
+        }
+    };
+    println!("Initrd: {} entries", initrd.entries().len());
+    if verbosity >= Verbosity::Verbose {
+        for entry in initrd.entries() {
+            match entry.kind {
+                fs::EntryKind::File => println!("  /{} ({} bytes)", entry.path, entry.data.len()),
+                fs::EntryKind::Directory => println!("  /{}/", entry.path),
+            }
+        }
+    }
+    if let Some(motd) = initrd.read("motd.txt") {
+        print!("{}", core::str::from_utf8(motd).unwrap_or(""));
+    }
+}
+
+/// Tells the bootloader this boot menu entry works, so it stops falling back from it.
+fn report_boot_success(runtime_services_address: u64) {
+    let runtime_services = unsafe {
+        &*((runtime_services_address + memory::PHYSICAL_MEMORY_OFFSET) as *const RuntimeServices)
+    };
+    // The firmware isn't reentrant
+    let result = interrupts::without_interrupts(|| unsafe {
+        match BootState::read(runtime_services) {
+            Some(state) if state.last_boot_failed() => state.succeeded().write(runtime_services),
+            _ => Ok(()),
+        }
+    });
+    if let Err(status) = result {
+        println!("Could not record the successful boot (status {:#x})", status);
+    }
+}
+
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
     let loc = info.location().unwrap();
@@ -109,6 +301,7 @@ fn panic_handler(info: &PanicInfo) -> ! {
             println!("{}: Panic: '{}'", loc, msg);
         }
     }
+    backtrace::print(None, backtrace::frame_pointer());
 
     halt()
 }
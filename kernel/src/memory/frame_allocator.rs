@@ -0,0 +1,201 @@
+use common::{MemoryRegion, MemoryRegionType};
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+use super::{align_up, PHYSICAL_MEMORY_OFFSET};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Frames below 1 MiB are only handed out when asked for explicitly through
+/// [`FrameAllocator::allocate_contiguous`], as they are the only memory some legacy devices and the
+/// AP startup code can use.
+const LOW_MEMORY_FRAMES: usize = (0x10_0000 / FRAME_SIZE) as usize;
+
+/// Physical frame allocator backed by a bitmap with one bit per 4 KiB frame. A set bit means the
+/// frame is either allocated or not usable RAM at all.
+///
+/// Single frames are searched for starting where the last search left off. Contiguous runs are found
+/// by sliding a window over the bitmap and skipping past the last allocated frame in it.
+pub struct FrameAllocator {
+    bitmap: &'static mut [u8],
+    free_frames: usize,
+    /// Frame number to start the next single-frame search at.
+    next_frame: usize,
+}
+
+impl FrameAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            bitmap: &mut [],
+            free_frames: 0,
+            next_frame: LOW_MEMORY_FRAMES,
+        }
+    }
+
+    /// Creates an allocator for `bitmap`, where every frame that may not be handed out is already set.
+    pub fn new(bitmap: &'static mut [u8]) -> Self {
+        let mut allocator = Self {
+            bitmap,
+            free_frames: 0,
+            next_frame: LOW_MEMORY_FRAMES,
+        };
+        // Physical address 0 looks too much like a null pointer to ever hand out
+        allocator.set_used(0);
+        allocator.free_frames = allocator
+            .bitmap
+            .iter()
+            .map(|b| b.count_zeros() as usize)
+            .sum();
+        allocator
+    }
+
+    /// Creates an allocator covering all RAM in `memory_map`, with only conventional memory free.
+    /// The bitmap itself is carved out of the first conventional region above 1 MiB big enough to
+    /// hold it.
+    pub fn from_memory_map(memory_map: &[MemoryRegion]) -> Self {
+        let ram_end = memory_map
+            .iter()
+            .filter(|r| r.ty.is_ram())
+            .map(|r| r.end())
+            .max()
+            .unwrap_or(0);
+        let bitmap_size = ((ram_end / FRAME_SIZE + 7) / 8) as usize;
+        let bitmap_frames = (bitmap_size as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+        let bitmap_region = memory_map
+            .iter()
+            .find(|r| {
+                r.ty == MemoryRegionType::CONVENTIONAL
+                    && r.base >= LOW_MEMORY_FRAMES as u64 * FRAME_SIZE
+                    && r.pages >= bitmap_frames
+            })
+            .expect("No conventional memory region can hold the frame bitmap");
+
+        // Safety: the region is conventional memory nobody else uses yet, and all of physical
+        // memory is mapped at `PHYSICAL_MEMORY_OFFSET`.
+        let bitmap = unsafe {
+            core::slice::from_raw_parts_mut(
+                (bitmap_region.base + PHYSICAL_MEMORY_OFFSET) as *mut u8,
+                bitmap_size,
+            )
+        };
+        for byte in bitmap.iter_mut() {
+            *byte = 0xFF;
+        }
+        for region in memory_map {
+            if region.ty == MemoryRegionType::CONVENTIONAL {
+                let first = region.base / FRAME_SIZE;
+                for frame in first..first + region.pages {
+                    bitmap[frame as usize / 8] &= !(1 << (frame % 8));
+                }
+            }
+        }
+        let first = bitmap_region.base / FRAME_SIZE;
+        for frame in first..first + bitmap_frames {
+            bitmap[frame as usize / 8] |= 1 << (frame % 8);
+        }
+
+        Self::new(bitmap)
+    }
+
+    /// Number of frames the bitmap covers, usable or not.
+    pub fn total_frames(&self) -> usize {
+        self.bitmap.len() * 8
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn allocate_frame(&mut self) -> PhysFrame {
+        self.try_allocate_frame()
+            .expect("No physical frames left to allocate")
+    }
+
+    pub fn try_allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let start_byte = self.next_frame / 8;
+        let bytes = self.bitmap.len();
+        for i in (start_byte..bytes).chain(LOW_MEMORY_FRAMES / 8..start_byte) {
+            let byte = self.bitmap[i];
+            if byte != 0xFF {
+                let frame = i * 8 + byte.trailing_ones() as usize;
+                self.set_used(frame);
+                self.free_frames -= 1;
+                self.next_frame = frame + 1;
+                return Some(frame_at(frame));
+            }
+        }
+
+        // Only low memory is left
+        self.allocate_contiguous(1, FRAME_SIZE as usize, u64::MAX)
+    }
+
+    /// Allocates `count` physically contiguous frames. The first frame is aligned to `align` bytes
+    /// (a power of two), and the last frame ends at or below `max_phys`.
+    ///
+    /// Returns the first frame of the run.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_phys: u64,
+    ) -> Option<PhysFrame> {
+        assert!(count > 0, "Tried allocating zero frames");
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        if count > self.free_frames {
+            return None;
+        }
+
+        let align = (align / FRAME_SIZE as usize).max(1);
+        let limit = ((max_phys / FRAME_SIZE) as usize).min(self.total_frames());
+
+        let mut start = 0;
+        while start + count <= limit {
+            match (start..start + count).rev().find(|&f| self.is_used(f)) {
+                Some(used) => start = align_up(used + 1, align),
+                None => {
+                    for frame in start..start + count {
+                        self.set_used(frame);
+                    }
+                    self.free_frames -= count;
+                    return Some(frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1);
+    }
+
+    /// Frees `count` frames starting at `start`, as returned by
+    /// [`allocate_contiguous`](Self::allocate_contiguous).
+    pub fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        for frame in first..first + count {
+            if !self.is_used(frame) {
+                panic!(
+                    "Tried deallocating free frame {:#x}",
+                    frame as u64 * FRAME_SIZE
+                );
+            }
+            self.bitmap[frame / 8] &= !(1 << (frame % 8));
+        }
+        self.free_frames += count;
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 8] & 1 << (frame % 8) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.bitmap[frame / 8] |= 1 << (frame % 8);
+    }
+}
+
+fn frame_at(frame: usize) -> PhysFrame {
+    PhysFrame::from_start_address(PhysAddr::new(frame as u64 * FRAME_SIZE)).unwrap()
+}
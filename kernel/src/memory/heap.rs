@@ -0,0 +1,528 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::NonNull,
+};
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts, structures::paging::PhysFrame, VirtAddr};
+
+#[cfg(feature = "heap_debug")]
+use super::heap_debug;
+use super::{
+    align_down, align_up,
+    vma::{HEAP_BASE, HEAP_SIZE},
+    FRAME_ALLOCATOR, MAPPER,
+};
+
+const PAGE_SIZE: usize = 4096;
+/// Size of a slab. Slabs are aligned to their size, so the slab an object belongs to can be found by
+/// rounding the object's address down.
+const SLAB_SIZE: usize = 4 * PAGE_SIZE;
+
+const MIN_CLASS_SHIFT: usize = 3; // 8 bytes
+const MAX_CLASS_SHIFT: usize = 11; // 2048 bytes
+const CLASS_COUNT: usize = MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1;
+
+/// Maximum number of freed virtual ranges remembered for reuse.
+const MAX_FREE_RANGES: usize = 64;
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator {
+    heap: Mutex::new(Heap::new()),
+};
+
+/// The virtual ranges the heap has handed out. Kept apart from [`Heap`], whose lock may be held
+/// while a page fault comes in; this lock is only ever held while the ranges are updated, which
+/// touches nothing but the structure itself.
+static PAGES: Mutex<VirtualPages> = Mutex::new(VirtualPages::new(
+    HEAP_BASE as usize,
+    (HEAP_BASE + HEAP_SIZE) as usize,
+));
+
+/// Small allocations (up to 2 KiB) are served from slabs, one list of slabs per power-of-two size
+/// class. Anything larger takes the large-object path, which hands out whole pages for the allocation
+/// and unmaps them again when it is freed.
+///
+/// The heap region is backed lazily: the allocator only hands out virtual ranges, and the page-fault
+/// handler maps a frame the first time a page of one of them is touched (see [`is_handed_out`]).
+pub struct Allocator {
+    heap: Mutex<Heap>,
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Interrupt handlers allocate too (the keyboard driver pushes key events), so the heap lock
+        // must never be held while an interrupt can come in.
+        interrupts::without_interrupts(|| {
+            let mut heap = self.heap.lock();
+            #[cfg(feature = "heap_debug")]
+            let ptr = heap_debug::alloc(&mut heap, layout);
+            #[cfg(not(feature = "heap_debug"))]
+            let ptr = heap.alloc(layout);
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            let mut heap = self.heap.lock();
+            #[cfg(feature = "heap_debug")]
+            heap_debug::dealloc(&mut heap, ptr, layout);
+            #[cfg(not(feature = "heap_debug"))]
+            heap.dealloc(ptr, layout);
+        })
+    }
+}
+
+/// Prints every live allocation along with the return addresses of the code that made it.
+#[cfg(feature = "heap_debug")]
+pub fn dump_allocations() {
+    interrupts::without_interrupts(|| heap_debug::dump(&ALLOCATOR.heap.lock()))
+}
+
+/// Returns a snapshot of the heap statistics.
+pub fn heap_stats() -> HeapStats {
+    interrupts::without_interrupts(|| ALLOCATOR.heap.lock().stats)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Sum of the sizes of all live allocations, as requested by their layouts. With `heap_debug`,
+    /// this includes the debugging overhead.
+    pub bytes_in_use: usize,
+    /// Highest value `bytes_in_use` has had.
+    pub peak_bytes_in_use: usize,
+    pub large_allocations: usize,
+    pub large_pages: usize,
+    pub classes: [SizeClassStats; CLASS_COUNT],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SizeClassStats {
+    pub object_size: usize,
+    pub objects_in_use: usize,
+    pub slabs: usize,
+}
+
+impl HeapStats {
+    const fn new() -> Self {
+        let mut classes = [SizeClassStats {
+            object_size: 0,
+            objects_in_use: 0,
+            slabs: 0,
+        }; CLASS_COUNT];
+        let mut i = 0;
+        while i < CLASS_COUNT {
+            classes[i].object_size = class_size(i);
+            i += 1;
+        }
+        Self {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            large_allocations: 0,
+            large_pages: 0,
+            classes,
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} B in use, {} B peak",
+            self.bytes_in_use, self.peak_bytes_in_use
+        )?;
+        for class in &self.classes {
+            writeln!(
+                f,
+                "  {:>4} B: {} objects in {} slabs",
+                class.object_size, class.objects_in_use, class.slabs
+            )?;
+        }
+        write!(
+            f,
+            "  large: {} allocations in {} pages",
+            self.large_allocations, self.large_pages
+        )
+    }
+}
+
+pub(super) struct Heap {
+    /// Slabs with at least one free object, per size class.
+    partial_slabs: [Option<NonNull<Slab>>; CLASS_COUNT],
+    stats: HeapStats,
+    #[cfg(feature = "heap_debug")]
+    pub(super) tracker: heap_debug::Tracker,
+}
+
+// Safety: the raw pointers in `Heap` point into the heap's own virtual range, which is only ever
+// touched with the heap lock held.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            partial_slabs: [None; CLASS_COUNT],
+            stats: HeapStats::new(),
+            #[cfg(feature = "heap_debug")]
+            tracker: heap_debug::Tracker::new(),
+        }
+    }
+
+    pub(super) unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match size_class(&layout) {
+            Some(class) => self.alloc_small(class),
+            None => self.alloc_large(layout),
+        };
+
+        match ptr {
+            Some(ptr) => {
+                self.stats.bytes_in_use += layout.size();
+                self.stats.peak_bytes_in_use =
+                    self.stats.peak_bytes_in_use.max(self.stats.bytes_in_use);
+                ptr.as_ptr()
+            }
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    pub(super) unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).expect("Tried deallocating null pointer");
+        match size_class(&layout) {
+            Some(class) => self.dealloc_small(ptr, class),
+            None => self.dealloc_large(ptr, layout),
+        }
+        self.stats.bytes_in_use -= layout.size();
+    }
+
+    unsafe fn alloc_small(&mut self, class: usize) -> Option<NonNull<u8>> {
+        #[cfg(feature = "heap_debug")]
+        self.validate_class(class);
+        let object_size = class_size(class);
+        let mut slab = match self.partial_slabs[class] {
+            Some(slab) => slab,
+            None => {
+                let slab = self.new_slab(class)?;
+                self.push_partial(class, slab);
+                slab
+            }
+        };
+
+        let object = slab.as_mut().take_object(object_size);
+        if slab.as_ref().is_full(object_size) {
+            self.unlink_partial(class, slab);
+        }
+        self.stats.classes[class].objects_in_use += 1;
+        Some(object)
+    }
+
+    unsafe fn dealloc_small(&mut self, ptr: NonNull<u8>, class: usize) {
+        #[cfg(feature = "heap_debug")]
+        self.validate_class(class);
+        let object_size = class_size(class);
+        let mut slab =
+            NonNull::new_unchecked(align_down(ptr.as_ptr() as usize, SLAB_SIZE) as *mut Slab);
+
+        let was_full = slab.as_ref().is_full(object_size);
+        slab.as_mut().put_object(ptr);
+        self.stats.classes[class].objects_in_use -= 1;
+
+        if was_full {
+            self.push_partial(class, slab);
+        }
+
+        // Give empty slabs back, but keep the last one of the class around so a class that keeps
+        // allocating and freeing a single object doesn't map and unmap a slab every time.
+        let slab_ref = slab.as_ref();
+        if slab_ref.in_use == 0 && (slab_ref.prev.is_some() || slab_ref.next.is_some()) {
+            self.unlink_partial(class, slab);
+            self.release_pages(slab.as_ptr() as usize, SLAB_SIZE);
+            self.stats.classes[class].slabs -= 1;
+        }
+    }
+
+    unsafe fn new_slab(&mut self, class: usize) -> Option<NonNull<Slab>> {
+        let base = PAGES.lock().allocate(SLAB_SIZE, SLAB_SIZE)?;
+
+        let slab = base as *mut Slab;
+        slab.write(Slab {
+            prev: None,
+            next: None,
+            free: None,
+            unused_offset: align_up(core::mem::size_of::<Slab>(), class_size(class)),
+            in_use: 0,
+        });
+        self.stats.classes[class].slabs += 1;
+        NonNull::new(slab)
+    }
+
+    unsafe fn alloc_large(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let size = align_up(layout.size(), PAGE_SIZE);
+        let base = PAGES.lock().allocate(size, layout.align().max(PAGE_SIZE))?;
+
+        self.stats.large_allocations += 1;
+        self.stats.large_pages += size / PAGE_SIZE;
+        NonNull::new(base as *mut u8)
+    }
+
+    unsafe fn dealloc_large(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let size = align_up(layout.size(), PAGE_SIZE);
+        self.release_pages(ptr.as_ptr() as usize, size);
+        self.stats.large_allocations -= 1;
+        self.stats.large_pages -= size / PAGE_SIZE;
+    }
+
+    unsafe fn release_pages(&mut self, base: usize, size: usize) {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for page in (base..base + size).step_by(PAGE_SIZE) {
+            let virt = VirtAddr::new(page as u64);
+            // Pages that were never touched were never mapped
+            if mapper.is_mapped(virt) {
+                let (phys, _) = mapper.unmap(&mut frame_allocator, virt).unwrap();
+                frame_allocator.deallocate_frame(PhysFrame::containing_address(phys));
+            }
+        }
+        drop(frame_allocator);
+        drop(mapper);
+        PAGES.lock().release(base, size);
+    }
+
+    /// Checks the links of the partial slab list of `class`, and the free list of every slab on it.
+    #[cfg(feature = "heap_debug")]
+    unsafe fn validate_class(&self, class: usize) {
+        let object_size = class_size(class);
+        let first_offset = align_up(core::mem::size_of::<Slab>(), object_size);
+        let capacity = (SLAB_SIZE - first_offset) / object_size;
+        let heap_range = HEAP_BASE as usize..(HEAP_BASE + HEAP_SIZE) as usize;
+
+        let mut prev = None;
+        let mut next = self.partial_slabs[class];
+        while let Some(slab) = next {
+            let base = slab.as_ptr() as usize;
+            assert!(
+                base % SLAB_SIZE == 0 && heap_range.contains(&base),
+                "Corrupt slab link {:#x} in {} B class",
+                base,
+                object_size
+            );
+            let slab_ref = slab.as_ref();
+            assert!(
+                slab_ref.prev == prev,
+                "Slab {:#x} has a broken back link",
+                base
+            );
+            assert!(
+                slab_ref.in_use < capacity && slab_ref.unused_offset <= SLAB_SIZE,
+                "Slab {:#x} has a corrupt header",
+                base
+            );
+
+            let mut free = slab_ref.free;
+            let mut free_count = 0;
+            while let Some(object) = free {
+                let addr = object.as_ptr() as usize;
+                assert!(
+                    addr >= base + first_offset
+                        && addr < base + slab_ref.unused_offset
+                        && (addr - base) % object_size == 0,
+                    "Corrupt free list entry {:#x} in slab {:#x}",
+                    addr,
+                    base
+                );
+                free_count += 1;
+                assert!(
+                    free_count <= capacity,
+                    "Free list of slab {:#x} loops",
+                    base
+                );
+                free = object.as_ref().next;
+            }
+
+            prev = Some(slab);
+            next = slab_ref.next;
+        }
+    }
+
+    unsafe fn push_partial(&mut self, class: usize, mut slab: NonNull<Slab>) {
+        let head = self.partial_slabs[class];
+        slab.as_mut().prev = None;
+        slab.as_mut().next = head;
+        if let Some(mut head) = head {
+            head.as_mut().prev = Some(slab);
+        }
+        self.partial_slabs[class] = Some(slab);
+    }
+
+    unsafe fn unlink_partial(&mut self, class: usize, mut slab: NonNull<Slab>) {
+        let prev = slab.as_ref().prev;
+        let next = slab.as_ref().next;
+        match prev {
+            Some(mut prev) => prev.as_mut().next = next,
+            None => self.partial_slabs[class] = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().prev = prev;
+        }
+        slab.as_mut().prev = None;
+        slab.as_mut().next = None;
+    }
+}
+
+/// Header placed at the start of every slab. The objects follow it, starting at the first offset
+/// aligned to the object size.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    /// Objects that have been freed and can be handed out again.
+    free: Option<NonNull<FreeObject>>,
+    /// Offset of the first object that has never been handed out. Objects are carved from here
+    /// when `free` is empty, so a new slab doesn't have to build its free list up front.
+    unused_offset: usize,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+impl Slab {
+    fn is_full(&self, object_size: usize) -> bool {
+        self.free.is_none() && self.unused_offset + object_size > SLAB_SIZE
+    }
+
+    unsafe fn take_object(&mut self, object_size: usize) -> NonNull<u8> {
+        self.in_use += 1;
+        if let Some(object) = self.free {
+            self.free = object.as_ref().next;
+            return object.cast();
+        }
+        let object = self as *mut Self as usize + self.unused_offset;
+        self.unused_offset += object_size;
+        NonNull::new_unchecked(object as *mut u8)
+    }
+
+    unsafe fn put_object(&mut self, object: NonNull<u8>) {
+        let object = object.cast::<FreeObject>();
+        object.as_ptr().write(FreeObject { next: self.free });
+        self.free = Some(object);
+        self.in_use -= 1;
+    }
+}
+
+/// Whether `addr` lies in a range the heap has handed out and not taken back, so the page-fault
+/// handler may back it. Ranges dropped because the free list was full count as handed out.
+pub(super) fn is_handed_out(addr: VirtAddr) -> bool {
+    // Only contended if the fault came from the bookkeeping itself, which would be a bug
+    match PAGES.try_lock() {
+        Some(pages) => pages.is_handed_out(addr.as_u64() as usize),
+        None => false,
+    }
+}
+
+/// Hands out page-aligned virtual ranges from the heap's address range.
+struct VirtualPages {
+    start: usize,
+    /// Everything from here to `end` has never been handed out.
+    next: usize,
+    end: usize,
+    /// Freed ranges, as `(start, end)`. If this overflows, the range is simply not reused; the heap
+    /// has far more virtual address space than physical memory to back it.
+    free: [(usize, usize); MAX_FREE_RANGES],
+    free_count: usize,
+}
+
+impl VirtualPages {
+    const fn new(start: usize, end: usize) -> Self {
+        Self {
+            start,
+            next: start,
+            end,
+            free: [(0, 0); MAX_FREE_RANGES],
+            free_count: 0,
+        }
+    }
+
+    fn allocate(&mut self, size: usize, align: usize) -> Option<usize> {
+        for i in 0..self.free_count {
+            let (start, end) = self.free[i];
+            let aligned = align_up(start, align);
+            if aligned + size <= end {
+                self.remove(i);
+                self.insert(start, aligned);
+                self.insert(aligned + size, end);
+                return Some(aligned);
+            }
+        }
+
+        let aligned = align_up(self.next, align);
+        if aligned + size > self.end {
+            return None;
+        }
+        let gap = self.next;
+        self.next = aligned + size;
+        self.insert(gap, aligned);
+        Some(aligned)
+    }
+
+    fn is_handed_out(&self, addr: usize) -> bool {
+        (self.start..self.next).contains(&addr)
+            && !self.free[..self.free_count]
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&addr))
+    }
+
+    fn release(&mut self, start: usize, size: usize) {
+        self.insert(start, start + size);
+    }
+
+    fn insert(&mut self, mut start: usize, mut end: usize) {
+        if start == end {
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.free_count {
+            let (s, e) = self.free[i];
+            if e == start {
+                start = s;
+                self.remove(i);
+            } else if s == end {
+                end = e;
+                self.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        if end == self.next {
+            self.next = start;
+        } else if self.free_count < MAX_FREE_RANGES {
+            self.free[self.free_count] = (start, end);
+            self.free_count += 1;
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.free_count -= 1;
+        self.free[i] = self.free[self.free_count];
+    }
+}
+
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(1 << MIN_CLASS_SHIFT)
+        .next_power_of_two();
+    if size > 1 << MAX_CLASS_SHIFT {
+        return None;
+    }
+    Some(size.trailing_zeros() as usize - MIN_CLASS_SHIFT)
+}
+
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_SHIFT)
+}
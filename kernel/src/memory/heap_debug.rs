@@ -0,0 +1,212 @@
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+};
+
+use super::{align_up, heap::Heap};
+use crate::backtrace::{frame_pointer, Frames, Symbolized};
+
+/// Bytes of canary on each side of an allocation.
+const CANARY_SIZE: usize = 16;
+const CANARY: u8 = 0xA5;
+/// Fresh allocations are filled with this, so reads of uninitialized memory stand out.
+const UNINIT_POISON: u8 = 0xCD;
+/// Freed allocations are filled with this, so use-after-free reads stand out.
+const FREED_POISON: u8 = 0xDD;
+
+const LIVE: u32 = u32::from_le_bytes(*b"LIVE");
+const FREED: u32 = u32::from_le_bytes(*b"FREE");
+
+/// Number of return addresses recorded per allocation.
+const CALLER_FRAMES: usize = 6;
+
+/// Placed in front of every allocation, right before the front canary:
+///
+/// `[padding][Header][canary][payload][canary]`
+///
+/// Live allocations are kept in a doubly linked list through their headers.
+#[repr(C)]
+struct Header {
+    prev: Option<NonNull<Header>>,
+    next: Option<NonNull<Header>>,
+    state: u32,
+    /// Offset from the start of the underlying block to the payload.
+    prefix: usize,
+    size: usize,
+    callers: [usize; CALLER_FRAMES],
+}
+
+pub struct Tracker {
+    live: Option<NonNull<Header>>,
+    live_count: usize,
+}
+
+impl Tracker {
+    pub const fn new() -> Self {
+        Self {
+            live: None,
+            live_count: 0,
+        }
+    }
+}
+
+pub unsafe fn alloc(heap: &mut Heap, layout: Layout) -> *mut u8 {
+    let callers = callers();
+    validate(&heap.tracker);
+
+    let (outer, prefix) = outer_layout(layout);
+    let block = heap.alloc(outer);
+    if block.is_null() {
+        return block;
+    }
+    let payload = block.add(prefix);
+    let header = header_of(payload);
+    header.write(Header {
+        prev: None,
+        next: heap.tracker.live,
+        state: LIVE,
+        prefix,
+        size: layout.size(),
+        callers,
+    });
+    if let Some(mut next) = heap.tracker.live {
+        next.as_mut().prev = Some(NonNull::new_unchecked(header));
+    }
+    heap.tracker.live = Some(NonNull::new_unchecked(header));
+    heap.tracker.live_count += 1;
+
+    ptr::write_bytes(payload.sub(CANARY_SIZE), CANARY, CANARY_SIZE);
+    ptr::write_bytes(payload, UNINIT_POISON, layout.size());
+    ptr::write_bytes(payload.add(layout.size()), CANARY, CANARY_SIZE);
+    payload
+}
+
+pub unsafe fn dealloc(heap: &mut Heap, payload: *mut u8, layout: Layout) {
+    let header = &mut *header_of(payload);
+    match header.state {
+        LIVE => {}
+        FREED => {
+            println!("Double free of {:p} ({} bytes)", payload, header.size);
+            print_callers("allocated from", &header.callers);
+            print_callers("freed again from", &callers());
+            panic!("Double free");
+        }
+        _ => panic!(
+            "Tried freeing {:p}, which is not a heap allocation or has a corrupt header",
+            payload
+        ),
+    }
+    if header.size != layout.size() {
+        panic!(
+            "Allocation {:p} of {} bytes freed as {} bytes",
+            payload,
+            header.size,
+            layout.size()
+        );
+    }
+    validate(&heap.tracker);
+
+    match header.prev {
+        Some(mut prev) => prev.as_mut().next = header.next,
+        None => heap.tracker.live = header.next,
+    }
+    if let Some(mut next) = header.next {
+        next.as_mut().prev = header.prev;
+    }
+    heap.tracker.live_count -= 1;
+    header.state = FREED;
+
+    ptr::write_bytes(
+        payload.sub(CANARY_SIZE),
+        FREED_POISON,
+        layout.size() + 2 * CANARY_SIZE,
+    );
+    let (outer, prefix) = outer_layout(layout);
+    heap.dealloc(payload.sub(prefix), outer);
+}
+
+pub fn dump(heap: &Heap) {
+    println!("{} live allocations:", heap.tracker.live_count);
+    let mut next = heap.tracker.live;
+    while let Some(header) = next {
+        let header = unsafe { header.as_ref() };
+        let payload = header as *const Header as usize + size_of::<Header>() + CANARY_SIZE;
+        println!("  {:#x}: {} bytes", payload, header.size);
+        print_callers("    from", &header.callers);
+        next = header.next;
+    }
+}
+
+/// Checks the links of the live list and the canaries of every live allocation.
+unsafe fn validate(tracker: &Tracker) {
+    let mut prev = None;
+    let mut next = tracker.live;
+    let mut count = 0;
+    while let Some(header) = next {
+        let header_ref = header.as_ref();
+        let payload = header.as_ptr() as *const u8 as usize + size_of::<Header>() + CANARY_SIZE;
+        assert!(
+            header_ref.state == LIVE && header_ref.prev == prev,
+            "Corrupt header for allocation {:#x}",
+            payload
+        );
+        check_canaries(header_ref, payload as *const u8);
+        count += 1;
+        assert!(count <= tracker.live_count, "Live allocation list loops");
+        prev = Some(header);
+        next = header_ref.next;
+    }
+    assert!(
+        count == tracker.live_count,
+        "Live allocation list lost entries"
+    );
+}
+
+unsafe fn check_canaries(header: &Header, payload: *const u8) {
+    let front = core::slice::from_raw_parts(payload.sub(CANARY_SIZE), CANARY_SIZE);
+    let back = core::slice::from_raw_parts(payload.add(header.size), CANARY_SIZE);
+    let which = if front.iter().any(|&b| b != CANARY) {
+        "underflow"
+    } else if back.iter().any(|&b| b != CANARY) {
+        "overflow"
+    } else {
+        return;
+    };
+    println!(
+        "Heap buffer {} in allocation {:p} of {} bytes",
+        which, payload, header.size
+    );
+    print_callers("allocated from", &header.callers);
+    panic!("Heap corruption");
+}
+
+/// The layout actually requested from the heap for `layout`, and the offset of the payload in it.
+fn outer_layout(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(align_of::<Header>());
+    let prefix = align_up(size_of::<Header>() + CANARY_SIZE, align);
+    let size = prefix + layout.size() + CANARY_SIZE;
+    (Layout::from_size_align(size, align).unwrap(), prefix)
+}
+
+fn header_of(payload: *mut u8) -> *mut Header {
+    (payload as usize - CANARY_SIZE - size_of::<Header>()) as *mut Header
+}
+
+/// Return addresses of the innermost frames.
+#[inline(always)]
+fn callers() -> [usize; CALLER_FRAMES] {
+    let mut callers = [0; CALLER_FRAMES];
+    for (caller, address) in callers.iter_mut().zip(Frames::new(frame_pointer())) {
+        *caller = address;
+    }
+    callers
+}
+
+fn print_callers(what: &str, callers: &[usize]) {
+    println!("{}:", what);
+    let indent = what.len() - what.trim_start().len() + 2;
+    for &caller in callers.iter().take_while(|&&c| c != 0) {
+        println!("{:indent$}{}", "", Symbolized(caller), indent = indent);
+    }
+}
@@ -0,0 +1,568 @@
+use core::{arch::x86_64::__cpuid, fmt};
+
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{page_table::PageTableEntry, PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use super::{FrameAllocator, PHYSICAL_MEMORY_OFFSET};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 0x1000,
+            PageSize::Size2MiB => 0x20_0000,
+            PageSize::Size1GiB => 0x4000_0000,
+        }
+    }
+
+    fn smaller(self) -> Option<Self> {
+        match self {
+            PageSize::Size4KiB => None,
+            PageSize::Size2MiB => Some(PageSize::Size4KiB),
+            PageSize::Size1GiB => Some(PageSize::Size2MiB),
+        }
+    }
+}
+
+/// Why pages couldn't be mapped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapError {
+    /// A page table was needed and there was no frame left for it.
+    OutOfFrames,
+    /// The request can't be carried out as asked, e.g. because the page is already mapped.
+    Invalid(&'static str),
+}
+
+impl MapError {
+    pub fn message(self) -> &'static str {
+        match self {
+            MapError::OutOfFrames => "No physical frames left for page table",
+            MapError::Invalid(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Where a virtual address is mapped to.
+#[derive(Clone, Copy, Debug)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    /// Size of the page containing the address.
+    pub size: PageSize,
+    pub flags: PageTableFlags,
+}
+
+pub struct Mapper {
+    pub(super) page_table: &'static mut PageTable,
+}
+
+impl Mapper {
+    pub fn new(page_table: &'static mut PageTable) -> Self {
+        Self { page_table }
+    }
+
+    pub fn translate(&self, virt: VirtAddr) -> Option<Translation> {
+        let (idx4, idx3, idx2, idx1) = virt2idx(virt);
+
+        let pdp = unsafe { self.page_table[idx4].as_page_table() }?;
+        let (entry, size) = if pdp[idx3].flags().contains(PageTableFlags::HUGE_PAGE) {
+            (&pdp[idx3], PageSize::Size1GiB)
+        } else {
+            let pd = unsafe { pdp[idx3].as_page_table() }?;
+            if pd[idx2].flags().contains(PageTableFlags::HUGE_PAGE) {
+                (&pd[idx2], PageSize::Size2MiB)
+            } else {
+                let pt = unsafe { pd[idx2].as_page_table() }?;
+                (&pt[idx1], PageSize::Size4KiB)
+            }
+        };
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        Some(Translation {
+            phys: entry.addr() + (virt.as_u64() & (size.bytes() - 1)),
+            size,
+            flags: entry.flags(),
+        })
+    }
+
+    /// Returns the entries the MMU reads to translate `virt`, from the PML4 down to the first entry
+    /// that is unused or maps a page.
+    pub fn walk(&self, virt: VirtAddr) -> PageWalk {
+        let (idx4, idx3, idx2, idx1) = virt2idx(virt);
+        let indices = [idx4, idx3, idx2, idx1];
+        let mut walk = PageWalk {
+            entries: [(0, 0); 4],
+            len: 0,
+        };
+
+        let mut table: &PageTable = self.page_table;
+        for &index in indices.iter() {
+            let entry = &table[index];
+            walk.entries[walk.len] = (index, entry.flags().bits() | entry.addr().as_u64());
+            walk.len += 1;
+            table = match unsafe { entry.as_page_table() } {
+                Some(table) => table,
+                None => break,
+            };
+        }
+        walk
+    }
+
+    pub fn is_mapped(&self, virt: VirtAddr) -> bool {
+        self.translate(virt).is_some()
+    }
+
+    /// Returns the 4 KiB frame `virt` is mapped to, even if it is part of a huge page.
+    pub fn get_physical(&self, virt: VirtAddr) -> Result<PhysFrame, &'static str> {
+        self.translate(virt)
+            .map(|t| PhysFrame::containing_address(t.phys))
+            .ok_or("Address is not mapped")
+    }
+
+    /// Maps the 4 KiB page at `virt` to `frame`.
+    pub unsafe fn map(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        self.map_page(
+            frame_allocator,
+            virt,
+            frame.start_address(),
+            PageSize::Size4KiB,
+            flags,
+        )
+    }
+
+    /// Maps a single page of `size` at `virt` to `phys`. Both addresses must be aligned to the page
+    /// size. `PRESENT` is always added to `flags`, as is `HUGE_PAGE` for 2 MiB and 1 GiB pages.
+    pub unsafe fn map_page(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: PageSize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        if virt.as_u64() % size.bytes() != 0 || phys.as_u64() % size.bytes() != 0 {
+            return Err(MapError::Invalid("Page is not aligned to its size"));
+        }
+        if size == PageSize::Size1GiB && !supports_1gib_pages() {
+            return Err(MapError::Invalid("CPU doesn't support 1 GiB pages"));
+        }
+        let (idx4, idx3, idx2, idx1) = virt2idx(virt);
+        let user = flags.contains(PageTableFlags::USER_ACCESSIBLE);
+
+        let pdp = next_table_create(&mut self.page_table[idx4], frame_allocator, user)?;
+        let entry = if size == PageSize::Size1GiB {
+            &mut pdp[idx3]
+        } else {
+            let pd = next_table_create(&mut pdp[idx3], frame_allocator, user)?;
+            if size == PageSize::Size2MiB {
+                &mut pd[idx2]
+            } else {
+                let pt = next_table_create(&mut pd[idx2], frame_allocator, user)?;
+                &mut pt[idx1]
+            }
+        };
+
+        if !entry.is_unused() {
+            return Err(MapError::Invalid("Trying to map to already existing page"));
+        }
+        entry.set_addr(phys, leaf_flags(flags, size));
+
+        Ok(())
+    }
+
+    /// Maps `len` bytes at `virt` to `phys`, using the biggest pages the alignment of both and the
+    /// CPU allow. On failure, nothing of the range stays mapped.
+    pub unsafe fn map_range(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        check_range(virt, len)?;
+        let gib_pages = supports_1gib_pages();
+        let mut offset = 0;
+        while offset < len {
+            let size = [PageSize::Size1GiB, PageSize::Size2MiB, PageSize::Size4KiB]
+                .iter()
+                .copied()
+                .find(|&size| {
+                    (size != PageSize::Size1GiB || gib_pages)
+                        && (virt.as_u64() + offset) % size.bytes() == 0
+                        && (phys.as_u64() + offset) % size.bytes() == 0
+                        && len - offset >= size.bytes()
+                })
+                .unwrap();
+            if let Err(e) =
+                self.map_page(frame_allocator, virt + offset, phys + offset, size, flags)
+            {
+                // Roll back what was mapped so far
+                let mut undo = 0;
+                while undo < offset {
+                    let (_, size) = self.unmap(frame_allocator, virt + undo).unwrap();
+                    undo += size.bytes();
+                }
+                return Err(e);
+            }
+            offset += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Unmaps the page containing `virt`, whatever its size, and returns what it was mapped to. The
+    /// memory it was mapped to is left alone; page tables left empty are freed, except for those the
+    /// kernel half of the PML4 points at, which every address space shares.
+    pub unsafe fn unmap(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+    ) -> Result<(PhysAddr, PageSize), &'static str> {
+        let (idx4, idx3, idx2, idx1) = virt2idx(virt);
+
+        let pdp = self.page_table[idx4]
+            .as_page_table_mut()
+            .ok_or("Tried unmapping unmapped page (level 4)")?;
+
+        if pdp[idx3].is_unused() {
+            return Err("Tried unmapping unmapped page (level 3)");
+        }
+        let unmapped = if pdp[idx3].flags().contains(PageTableFlags::HUGE_PAGE) {
+            let unmapped = (pdp[idx3].addr(), PageSize::Size1GiB);
+            pdp[idx3].set_unused();
+            unmapped
+        } else {
+            let pd = pdp[idx3].as_page_table_mut().unwrap();
+
+            if pd[idx2].is_unused() {
+                return Err("Tried unmapping unmapped page (level 2)");
+            }
+            let unmapped = if pd[idx2].flags().contains(PageTableFlags::HUGE_PAGE) {
+                let unmapped = (pd[idx2].addr(), PageSize::Size2MiB);
+                pd[idx2].set_unused();
+                unmapped
+            } else {
+                let pt = pd[idx2].as_page_table_mut().unwrap();
+
+                if pt[idx1].is_unused() {
+                    return Err("Tried unmapping unmapped page (level 1)");
+                }
+                let unmapped = (pt[idx1].addr(), PageSize::Size4KiB);
+                pt[idx1].set_unused();
+
+                // Page tables are reached through the physical memory offset mapping, so the frames
+                // holding tables that are now empty can be handed straight back to the frame
+                // allocator once their parent entry has been cleared.
+                if pt.iter().all(|e| e.is_unused()) {
+                    frame_allocator.deallocate_frame(pd[idx2].frame().unwrap());
+                    pd[idx2].set_unused();
+                }
+                unmapped
+            };
+
+            // Do the same for the PD
+            if pd.iter().all(|e| e.is_unused()) {
+                frame_allocator.deallocate_frame(pdp[idx3].frame().unwrap());
+                pdp[idx3].set_unused();
+            }
+            unmapped
+        };
+
+        // Same for PDP
+        if idx4 < KERNEL_HALF_START && pdp.iter().all(|e| e.is_unused()) {
+            frame_allocator.deallocate_frame(self.page_table[idx4].frame().unwrap());
+            self.page_table[idx4].set_unused();
+        }
+
+        flush(virt);
+        Ok(unmapped)
+    }
+
+    /// Splits the 2 MiB or 1 GiB page containing `virt` into 512 pages of the next smaller size,
+    /// mapping the same memory with the same flags.
+    pub unsafe fn split(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+    ) -> Result<(), MapError> {
+        let (entry, size) = self
+            .leaf_entry_mut(virt)
+            .ok_or(MapError::Invalid("Tried splitting unmapped page"))?;
+        let smaller = size
+            .smaller()
+            .ok_or(MapError::Invalid("Tried splitting 4 KiB page"))?;
+
+        let base = entry.addr();
+        let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+        let frame = frame_allocator
+            .try_allocate_frame()
+            .ok_or(MapError::OutOfFrames)?;
+        let table = table_at(frame.start_address());
+        for (i, child) in table.iter_mut().enumerate() {
+            child.set_addr(
+                base + i as u64 * smaller.bytes(),
+                leaf_flags(flags, smaller),
+            );
+        }
+        entry.set_frame(
+            frame,
+            table_flags(flags.contains(PageTableFlags::USER_ACCESSIBLE)),
+        );
+
+        flush(virt);
+        Ok(())
+    }
+
+    /// Changes the flags of every page in `virt..virt + len`, splitting huge pages that only
+    /// partly overlap the range.
+    pub unsafe fn protect(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        self.update_range(
+            frame_allocator,
+            virt,
+            len,
+            |_, _| true,
+            |entry, _, size| {
+                let addr = entry.addr();
+                entry.set_addr(addr, leaf_flags(flags, size));
+            },
+        )
+    }
+
+    /// Points the already mapped range `virt..virt + len` at `phys` instead, with new flags.
+    pub unsafe fn remap(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+        len: u64,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let target = |page: VirtAddr| phys + (page - virt);
+        self.update_range(
+            frame_allocator,
+            virt,
+            len,
+            |page, size| target(page).as_u64() % size.bytes() == 0,
+            |entry, page, size| entry.set_addr(target(page), leaf_flags(flags, size)),
+        )
+    }
+
+    /// Calls `update` on the entry of every page in `virt..virt + len`. Huge pages that stick out
+    /// of the range or that `fits` rejects are split first. Every changed page is flushed.
+    unsafe fn update_range(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+        virt: VirtAddr,
+        len: u64,
+        fits: impl Fn(VirtAddr, PageSize) -> bool,
+        mut update: impl FnMut(&mut PageTableEntry, VirtAddr, PageSize),
+    ) -> Result<(), MapError> {
+        check_range(virt, len)?;
+        let end = virt + len;
+        let mut page = virt;
+        while page < end {
+            let size = self
+                .translate(page)
+                .ok_or(MapError::Invalid("Tried changing unmapped page"))?
+                .size;
+            let page_start = page.align_down(size.bytes());
+            if size != PageSize::Size4KiB
+                && (page_start < virt || page_start + size.bytes() > end || !fits(page, size))
+            {
+                self.split(frame_allocator, page)?;
+                continue;
+            }
+
+            let (entry, _) = self.leaf_entry_mut(page).unwrap();
+            update(entry, page, size);
+            flush(page);
+            page = page_start + size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Returns the entry mapping `virt` along with the size of the page it maps.
+    unsafe fn leaf_entry_mut(&mut self, virt: VirtAddr) -> Option<(&mut PageTableEntry, PageSize)> {
+        let (idx4, idx3, idx2, idx1) = virt2idx(virt);
+
+        let pdp = self.page_table[idx4].as_page_table_mut()?;
+        let entry = &mut pdp[idx3];
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some((entry, PageSize::Size1GiB));
+        }
+        let pd = entry.as_page_table_mut()?;
+        let entry = &mut pd[idx2];
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some((entry, PageSize::Size2MiB));
+        }
+        let pt = entry.as_page_table_mut()?;
+        let entry = &mut pt[idx1];
+        if entry.is_unused() {
+            return None;
+        }
+        Some((entry, PageSize::Size4KiB))
+    }
+}
+
+/// The page-table entries used to translate an address, as returned by [`Mapper::walk`].
+pub struct PageWalk {
+    /// Index into the table and raw value of each entry.
+    entries: [(usize, u64); 4],
+    len: usize,
+}
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels = ["PML4", "PDPT", "PD", "PT"];
+        for (level, &(index, value)) in levels.iter().zip(&self.entries[..self.len]) {
+            writeln!(
+                f,
+                "  {}[{}] = {:#018x} {:?}",
+                level,
+                index,
+                value,
+                PageTableFlags::from_bits_truncate(value)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Invalidates the translation of the page containing `virt`. Only the current CPU is running for
+/// now, so there are no other TLBs to shoot down.
+fn flush(virt: VirtAddr) {
+    tlb::flush(virt);
+}
+
+fn check_range(virt: VirtAddr, len: u64) -> Result<(), MapError> {
+    if virt.as_u64() % 4096 != 0 || len % 4096 != 0 {
+        return Err(MapError::Invalid("Range is not page aligned"));
+    }
+    Ok(())
+}
+
+/// First PML4 entry of the kernel half of the address space.
+const KERNEL_HALF_START: usize = 256;
+
+/// Whether page directory pointer entries can map 1 GiB pages (CPUID pdpe1gb). Without it, the
+/// page-size bit is reserved at that level.
+fn supports_1gib_pages() -> bool {
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & 1 << 26 != 0 }
+}
+
+fn leaf_flags(flags: PageTableFlags, size: PageSize) -> PageTableFlags {
+    let flags = flags | PageTableFlags::PRESENT;
+    if size == PageSize::Size4KiB {
+        flags - PageTableFlags::HUGE_PAGE
+    } else {
+        flags | PageTableFlags::HUGE_PAGE
+    }
+}
+
+/// Flags of entries pointing to page tables. Access is restricted in the leaf entries only.
+fn table_flags(user: bool) -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if user {
+        flags | PageTableFlags::USER_ACCESSIBLE
+    } else {
+        flags
+    }
+}
+
+/// Returns the table `entry` points to, allocating an empty one if the entry is unused.
+unsafe fn next_table_create<'a>(
+    entry: &'a mut PageTableEntry,
+    frame_allocator: &mut FrameAllocator,
+    user: bool,
+) -> Result<&'a mut PageTable, MapError> {
+    if entry.is_unused() {
+        let frame = frame_allocator
+            .try_allocate_frame()
+            .ok_or(MapError::OutOfFrames)?;
+        table_at(frame.start_address()).zero();
+        entry.set_frame(frame, table_flags(user));
+    } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(MapError::Invalid("Trying to map inside a huge page"));
+    } else if user {
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+    }
+    Ok(entry.as_page_table_mut().unwrap())
+}
+
+unsafe fn table_at(phys: PhysAddr) -> &'static mut PageTable {
+    &mut *((phys.as_u64() + PHYSICAL_MEMORY_OFFSET) as *mut PageTable)
+}
+
+fn virt2idx(addr: VirtAddr) -> (usize, usize, usize, usize) {
+    let addr = addr.as_u64();
+    let idx4 = (addr >> 39 & 0x1FF) as usize;
+    let idx3 = (addr >> 30 & 0x1FF) as usize;
+    let idx2 = (addr >> 21 & 0x1FF) as usize;
+    let idx1 = (addr >> 12 & 0x1FF) as usize;
+    (idx4, idx3, idx2, idx1)
+}
+
+trait AsPageTable {
+    unsafe fn as_page_table(&self) -> Option<&PageTable>;
+    unsafe fn as_page_table_mut(&mut self) -> Option<&mut PageTable>;
+}
+
+impl AsPageTable for PageTableEntry {
+    unsafe fn as_page_table(&self) -> Option<&PageTable> {
+        if !self.is_unused()
+            && self.flags().contains(PageTableFlags::PRESENT)
+            && !self.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            Some(
+                ((self.addr().as_u64() | PHYSICAL_MEMORY_OFFSET) as *const PageTable)
+                    .as_ref()
+                    .unwrap(),
+            )
+        } else {
+            None
+        }
+    }
+
+    unsafe fn as_page_table_mut(&mut self) -> Option<&mut PageTable> {
+        if !self.is_unused()
+            && self.flags().contains(PageTableFlags::PRESENT)
+            && !self.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            Some(
+                ((self.addr().as_u64() | PHYSICAL_MEMORY_OFFSET) as *mut PageTable)
+                    .as_mut()
+                    .unwrap(),
+            )
+        } else {
+            None
+        }
+    }
+}
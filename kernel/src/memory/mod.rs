@@ -0,0 +1,278 @@
+mod frame_allocator;
+mod heap;
+#[cfg(feature = "heap_debug")]
+mod heap_debug;
+mod mapper;
+mod vma;
+
+pub use frame_allocator::FrameAllocator;
+#[cfg(feature = "heap_debug")]
+pub use heap::dump_allocations;
+pub use heap::{heap_stats, HeapStats};
+pub use mapper::{MapError, Mapper, PageSize, PageWalk, Translation};
+pub use vma::{print_layout, AddressSpace, Region, RegionKind};
+
+use core::ops::Range;
+
+use common::{MemoryRegion, MemoryRegionType};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::{Efer, EferFlags},
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{PageTable, PageTableFlags, PhysFrame},
+    },
+    VirtAddr,
+};
+
+/// All of physical memory is mapped starting at this address.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFFFF80_00000000;
+
+pub fn init(page_table: &'static mut PageTable, memory_map: &[MemoryRegion]) {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mut mapper = MAPPER.lock();
+    *frame_allocator = FrameAllocator::from_memory_map(memory_map);
+    mapper.page_table = page_table;
+    vma::init();
+
+    // Needed for `PageTableFlags::NO_EXECUTE`, which is a reserved bit otherwise
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
+
+/// Allocates `count` physically contiguous frames, for things like DMA buffers.
+/// See [`FrameAllocator::allocate_contiguous`].
+pub fn allocate_contiguous(count: usize, align: usize, max_phys: u64) -> Option<PhysFrame> {
+    interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(count, align, max_phys)
+    })
+}
+
+pub fn deallocate_contiguous(start: PhysFrame, count: usize) {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().deallocate_contiguous(start, count))
+}
+
+/// Number of physical frames that are currently free.
+pub fn free_frames() -> usize {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().free_frames())
+}
+
+static mut TEMP_PAGE_TABLE: PageTable = PageTable::new();
+static MAPPER: Mutex<Mapper> = Mutex::new(Mapper {
+    page_table: unsafe { &mut TEMP_PAGE_TABLE },
+});
+
+fn align_down(addr: usize, align: usize) -> usize {
+    let ret = addr - addr % align;
+    ret
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    let m = addr % align;
+    let ret = if m == 0 { addr } else { addr - m + align };
+    ret
+}
+
+/// Physical ranges inside bootloader memory that must survive [`reclaim_boot_memory`].
+static PRESERVED_BOOT_RANGES: Mutex<[(u64, u64); MAX_PRESERVED_BOOT_RANGES]> =
+    Mutex::new([(0, 0); MAX_PRESERVED_BOOT_RANGES]);
+const MAX_PRESERVED_BOOT_RANGES: usize = 16;
+
+/// Keeps the physical range `range` from being reclaimed along with the rest of the bootloader's
+/// memory.
+pub fn preserve_boot_range(range: Range<u64>) {
+    let mut ranges = PRESERVED_BOOT_RANGES.lock();
+    let slot = ranges
+        .iter_mut()
+        .find(|(start, end)| start == end)
+        .expect("Too many preserved boot ranges");
+    *slot = (range.start, range.end);
+}
+
+/// Hands boot services memory and loader data back to the frame allocator, and returns how many bytes
+/// were reclaimed. Runtime services, ACPI and loader code regions are kept; the latter holds the
+/// page tables and GDT the kernel still runs on.
+///
+/// Kept as well are the kernel image and every range passed to [`preserve_boot_range`]. The
+/// bootloader's stack is not, so the caller must already have switched to a kernel stack.
+/// `memory_map` must not itself live in bootloader memory.
+pub fn reclaim_boot_memory(memory_map: &[MemoryRegion], kernel_phys_range: Range<u64>) -> u64 {
+    let kernel = (kernel_phys_range.start, kernel_phys_range.end);
+    let preserved = *PRESERVED_BOOT_RANGES.lock();
+    let is_preserved = |frame: u64| {
+        preserved
+            .iter()
+            .chain(&[kernel])
+            .any(|&(start, end)| start < frame + 4096 && frame < end)
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut reclaimed = 0;
+        let reclaimable = memory_map.iter().filter(|r| {
+            matches!(
+                r.ty,
+                MemoryRegionType::LOADER_DATA
+                    | MemoryRegionType::BOOT_SERVICES_CODE
+                    | MemoryRegionType::BOOT_SERVICES_DATA
+            )
+        });
+        for region in reclaimable {
+            for frame in (region.base..region.end()).step_by(4096) {
+                // Frame 0 is never handed out
+                if frame == 0 || is_preserved(frame) {
+                    continue;
+                }
+                frame_allocator
+                    .deallocate_frame(PhysFrame::containing_address(PhysAddr::new(frame)));
+                reclaimed += 4096;
+            }
+        }
+        reclaimed
+    })
+}
+
+/// What the page-fault handler made of a fault.
+pub enum PageFault {
+    /// A page of a lazily backed region was mapped; the faulting instruction can be retried.
+    Resolved,
+    /// The access hit the guard page below a stack.
+    StackOverflow(Region),
+    /// The page belongs to a lazily backed region, but no physical frame was left to back it.
+    OutOfMemory(Region),
+    /// A genuine fault, with the region the address lies in, if any.
+    Unhandled(Option<Region>),
+}
+
+/// Tries to resolve a page fault at `addr`.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> PageFault {
+    let region = match vma::find(addr) {
+        Some(region) => region,
+        None => return PageFault::Unhandled(None),
+    };
+    if region.in_guard(addr) {
+        return PageFault::StackOverflow(region);
+    }
+    if !region.lazy || error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return PageFault::Unhandled(Some(region));
+    }
+    // Only what the heap handed out gets backed, so wild pointers and use after free still fault
+    if region.kind == RegionKind::Heap && !heap::is_handed_out(addr) {
+        return PageFault::Unhandled(Some(region));
+    }
+
+    // The fault may have happened with either lock held, in which case it can't be resolved
+    let (mut mapper, mut frame_allocator) = match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return PageFault::Unhandled(Some(region)),
+    };
+    let frame = match frame_allocator.try_allocate_frame() {
+        Some(frame) => frame,
+        None => return PageFault::OutOfMemory(region),
+    };
+    unsafe {
+        core::ptr::write_bytes(
+            (frame.start_address().as_u64() + PHYSICAL_MEMORY_OFFSET) as *mut u8,
+            0,
+            4096,
+        );
+    }
+    let page = addr.align_down(4096u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    match unsafe { mapper.map(&mut frame_allocator, page, frame, flags) } {
+        Ok(()) => PageFault::Resolved,
+        Err(e) => {
+            frame_allocator.deallocate_frame(frame);
+            match e {
+                MapError::OutOfFrames => PageFault::OutOfMemory(region),
+                MapError::Invalid(_) => PageFault::Unhandled(Some(region)),
+            }
+        }
+    }
+}
+
+/// Returns the stack region whose guard page `addr` lies in, if any. Meant for the double-fault
+/// handler, so it gives up rather than wait if the region list is locked.
+pub fn stack_guard_hit(addr: VirtAddr) -> Option<Region> {
+    vma::try_find(addr).filter(|region| region.kind == RegionKind::Stack && region.in_guard(addr))
+}
+
+/// Returns the kernel stack whose usable part holds `addr`, if any. Gives up rather than wait if the
+/// region list is locked, so it can be used from fault handlers.
+pub fn kernel_stack_containing(addr: VirtAddr) -> Option<Region> {
+    vma::try_find(addr)
+        .filter(|region| region.kind == RegionKind::Stack && addr >= region.usable_start())
+}
+
+/// Returns the page-table entries used to translate `addr`, for fault reports.
+pub fn walk(addr: VirtAddr) -> Option<PageWalk> {
+    MAPPER.try_lock().map(|mapper| mapper.walk(addr))
+}
+
+/// Maps the MMIO region `phys..phys + size` uncached into the MMIO window, and returns the address
+/// `phys` can be reached at.
+pub fn map_mmio(name: &'static str, phys: PhysAddr, size: u64) -> VirtAddr {
+    let start = phys.align_down(4096u64);
+    let len = (phys + size).align_up(4096u64) - start;
+    let region = vma::allocate(name, RegionKind::Mmio, len, 0).expect("Failed mapping MMIO region");
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        unsafe { mapper.map_range(&mut frame_allocator, region.start, start, len, flags) }
+            .expect("Failed mapping MMIO region");
+    });
+
+    region.start + (phys - start)
+}
+
+/// Allocates and maps a kernel stack of `size` bytes with an unmapped guard page below it, and
+/// returns its region. The stack grows down from `region.end()`.
+pub fn allocate_kernel_stack(name: &'static str, size: u64) -> Result<Region, &'static str> {
+    let region = vma::allocate(name, RegionKind::Stack, size, 4096)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let result = interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut page = region.usable_start();
+        while page < region.end() {
+            let mapped = match frame_allocator.try_allocate_frame() {
+                Some(frame) => unsafe { mapper.map(&mut frame_allocator, page, frame, flags) }
+                    .map_err(|e| {
+                        frame_allocator.deallocate_frame(frame);
+                        e.message()
+                    }),
+                None => Err("No physical frames left for kernel stack"),
+            };
+            if let Err(e) = mapped {
+                // Roll back what was mapped so far
+                let mut undo = region.usable_start();
+                while undo < page {
+                    let (phys, _) = unsafe { mapper.unmap(&mut frame_allocator, undo) }.unwrap();
+                    frame_allocator.deallocate_frame(PhysFrame::containing_address(phys));
+                    undo += 4096u64;
+                }
+                return Err(e);
+            }
+            page += 4096u64;
+        }
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        vma::release(region.start);
+        return Err(e);
+    }
+    Ok(region)
+}
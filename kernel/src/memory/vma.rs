@@ -0,0 +1,300 @@
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use super::{align_up, PHYSICAL_MEMORY_OFFSET};
+
+/// Every window of the kernel half spans one PML4 entry.
+const WINDOW_SIZE: u64 = 0x80_00000000;
+
+pub const HEAP_BASE: u64 = 0xFFFF8100_00000000;
+pub const HEAP_SIZE: u64 = WINDOW_SIZE;
+/// Kernel stacks are handed out from here.
+pub const STACK_WINDOW_BASE: u64 = 0xFFFF8180_00000000;
+/// MMIO mappings are handed out from here.
+pub const MMIO_WINDOW_BASE: u64 = 0xFFFF8200_00000000;
+
+const MAX_REGIONS: usize = 128;
+
+static KERNEL_SPACE: Mutex<AddressSpace> = Mutex::new(AddressSpace::new());
+
+extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+/// Reserves the regions whose place is fixed: the kernel image (placed by `link.ld`), the heap and
+/// the physical memory mapping.
+pub fn init() {
+    let (image_start, image_end) = unsafe {
+        (
+            &__kernel_start as *const u8 as u64,
+            &__kernel_end as *const u8 as u64,
+        )
+    };
+    let fixed = [
+        Region::new(
+            "kernel image",
+            RegionKind::KernelImage,
+            image_start,
+            align_up(image_end as usize, 4096) as u64 - image_start,
+        ),
+        Region {
+            lazy: true,
+            ..Region::new("heap", RegionKind::Heap, HEAP_BASE, HEAP_SIZE)
+        },
+        Region::new(
+            "physical memory",
+            RegionKind::DirectMap,
+            PHYSICAL_MEMORY_OFFSET,
+            WINDOW_SIZE,
+        ),
+    ];
+    for region in fixed.iter() {
+        reserve(*region).unwrap();
+    }
+}
+
+pub fn reserve(region: Region) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().reserve(region))
+}
+
+/// Finds room for a region of `size` bytes (plus `guard` bytes below it) in the window for `kind`.
+pub fn allocate(
+    name: &'static str,
+    kind: RegionKind,
+    size: u64,
+    guard: u64,
+) -> Result<Region, &'static str> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().allocate(name, kind, size, guard))
+}
+
+/// Forgets the region starting at `start`. Unmapping it is up to the caller.
+pub fn release(start: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().release(start))
+}
+
+/// Returns the region containing `addr`, guard included.
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().find(addr))
+}
+
+/// Like [`find`], but returns `None` if the region list is locked.
+pub fn try_find(addr: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.try_lock()?.find(addr))
+}
+
+pub fn print_layout() {
+    let space = interrupts::without_interrupts(|| KERNEL_SPACE.lock().clone());
+    println!("{}", space);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionKind {
+    KernelImage,
+    DirectMap,
+    Heap,
+    Stack,
+    Mmio,
+}
+
+impl RegionKind {
+    /// The window regions of this kind are allocated from, if they aren't placed by hand.
+    fn window(self) -> Option<(u64, u64)> {
+        match self {
+            RegionKind::Stack => Some((STACK_WINDOW_BASE, STACK_WINDOW_BASE + WINDOW_SIZE)),
+            RegionKind::Mmio => Some((MMIO_WINDOW_BASE, MMIO_WINDOW_BASE + WINDOW_SIZE)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    /// Start of the region, guard included.
+    pub start: VirtAddr,
+    /// Size of the region in bytes, guard included.
+    pub size: u64,
+    /// Bytes at the bottom of the region that are never mapped, so running off the end of a stack
+    /// faults instead of corrupting whatever lies below.
+    pub guard: u64,
+    /// Pages of the region are only mapped when first touched, by the page-fault handler.
+    pub lazy: bool,
+}
+
+impl Region {
+    pub fn new(name: &'static str, kind: RegionKind, start: u64, size: u64) -> Self {
+        Self {
+            name,
+            kind,
+            start: VirtAddr::new(start),
+            size,
+            guard: 0,
+            lazy: false,
+        }
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Start of the usable part of the region, above the guard.
+    pub fn usable_start(&self) -> VirtAddr {
+        self.start + self.guard
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn in_guard(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.usable_start()
+    }
+}
+
+/// The regions of one virtual address space, sorted by start address. The kernel half has a single
+/// instance; each process will get its own for the lower half.
+#[derive(Clone)]
+pub struct AddressSpace {
+    regions: [Option<Region>; MAX_REGIONS],
+    count: usize,
+}
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+            count: 0,
+        }
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.count]
+            .iter()
+            .map(|r| r.as_ref().unwrap())
+    }
+
+    /// Adds a region at a fixed place.
+    pub fn reserve(&mut self, region: Region) -> Result<(), &'static str> {
+        if region.size == 0 || region.start.as_u64() % 4096 != 0 || region.size % 4096 != 0 {
+            return Err("Region is not page aligned");
+        }
+        if self.count == MAX_REGIONS {
+            return Err("Too many virtual regions");
+        }
+        let index = self
+            .regions()
+            .position(|r| r.start >= region.start)
+            .unwrap_or(self.count);
+        let overlaps_prev = index > 0 && self.get(index - 1).end() > region.start;
+        let overlaps_next = index < self.count && self.get(index).start < region.end();
+        if overlaps_prev || overlaps_next {
+            return Err("Region overlaps an existing region");
+        }
+
+        for i in (index..self.count).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+        self.regions[index] = Some(region);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Adds a region at the lowest free place in the window for `kind`.
+    pub fn allocate(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        size: u64,
+        guard: u64,
+    ) -> Result<Region, &'static str> {
+        let (window_start, window_end) = kind.window().ok_or("Region kind has no window")?;
+        let size = align_up((size + guard) as usize, 4096) as u64;
+
+        let mut start = window_start;
+        for region in self.regions() {
+            if region.end().as_u64() <= start {
+                continue;
+            }
+            if region.start.as_u64() >= start + size || region.start.as_u64() >= window_end {
+                break;
+            }
+            start = region.end().as_u64();
+        }
+        if start + size > window_end {
+            return Err("Virtual address window is full");
+        }
+
+        let region = Region {
+            name,
+            kind,
+            start: VirtAddr::new(start),
+            size,
+            guard,
+            lazy: false,
+        };
+        self.reserve(region)?;
+        Ok(region)
+    }
+
+    pub fn release(&mut self, start: VirtAddr) -> Option<Region> {
+        let index = self.regions().position(|r| r.start == start)?;
+        let region = self.regions[index];
+        for i in index..self.count - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.count -= 1;
+        self.regions[self.count] = None;
+        region
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions().find(|r| r.contains(addr)).copied()
+    }
+
+    fn get(&self, index: usize) -> &Region {
+        self.regions[index].as_ref().unwrap()
+    }
+}
+
+impl fmt::Display for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "virtual memory layout:")?;
+        for region in self.regions() {
+            write!(
+                f,
+                "\n  {:#018x}-{:#018x} {:>9} {:<11} {}",
+                region.start.as_u64(),
+                region.end().as_u64(),
+                Size(region.size),
+                format!("{:?}", region.kind),
+                region.name
+            )?;
+            if region.guard != 0 {
+                write!(f, " ({} guard)", Size(region.guard))?;
+            }
+            if region.lazy {
+                write!(f, " (lazy)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats a byte count with the biggest unit that divides it.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = [("GiB", 30), ("MiB", 20), ("KiB", 10)];
+        for (unit, shift) in units.iter() {
+            if self.0 >= 1 << shift && self.0 % (1 << shift) == 0 {
+                return f.pad(&format!("{} {}", self.0 >> shift, unit));
+            }
+        }
+        f.pad(&format!("{} B", self.0))
+    }
+}
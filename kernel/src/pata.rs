@@ -1,18 +1,24 @@
 use spin::Mutex;
-use x86_64::{instructions::port::Port, structures::{idt::InterruptStackFrame, port::{PortRead, PortWrite}}};
+use x86_64::{instructions::port::Port, structures::port::{PortRead, PortWrite}};
 use alloc::prelude::v1::*;
+use core::time::Duration;
 
-use crate::{idt, pic};
+use crate::{irq::{self, IrqReturn}, pci::{self, Bar}, timer};
+
+/// How long the drive gets to clear BSY or raise DRQ before it is given up on.
+const TIMEOUT: Duration = Duration::from_secs(1);
 
 static PATA_DRIVER: Mutex<PataDriver> = Mutex::new(unsafe { PataDriver::default() });
 
 struct PataDriver {
     default_bus: Bus,
+    /// Whether a controller has been probed successfully. Only the first one is driven.
+    bound: bool,
 }
 
-extern "x86-interrupt" fn irq14(stack_frame: InterruptStackFrame) {
+fn irq14() -> IrqReturn {
     println!("IRQ 14");
-    unsafe { pic::send_eoi(14) };
+    IrqReturn::Handled
 }
 
 impl PataDriver {
@@ -23,7 +29,8 @@ impl PataDriver {
                 ctl_base: 0x3F6,
                 master_drive: NewDiskInfo::Uninitialized,
                 slave_drive: NewDiskInfo::Uninitialized,
-            }
+            },
+            bound: false,
         }
     }
 }
@@ -34,8 +41,52 @@ pub enum DiskSelect {
     Slave
 }
 
-pub unsafe fn init() {
-    PATA_DRIVER.lock().default_bus.initialize().unwrap();
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "pata",
+    matches: &[pci::Match::Class {
+        class: 0x01,
+        subclass: 0x01,
+        prog_if: None,
+    }],
+    probe,
+};
+
+/// Takes the primary channel of the first IDE controller that has working drives.
+fn probe(device: &'static pci::Device) -> Result<(), String> {
+    let mut driver = PATA_DRIVER.lock();
+    if driver.bound {
+        return Err("another IDE controller is already in use".to_string());
+    }
+
+    // Bit 0 of the programming interface is set when the channel runs in native mode, with its
+    // ports in BARs 0 and 1 rather than at the ISA addresses
+    let (io_base, ctl_base) = if device.prog_if & 1 != 0 {
+        match (device.bars[0], device.bars[1]) {
+            (Some(Bar::Io { port: io, .. }), Some(Bar::Io { port: ctl, .. })) => (io, ctl + 2),
+            _ => return Err("native-mode channel without I/O BARs".to_string()),
+        }
+    } else {
+        (0x1F0, 0x3F6)
+    };
+    device.enable_io_space();
+
+    // Native-mode channels interrupt through the PCI interrupt line instead, which nothing routes
+    // yet; reads poll anyway
+    let handler = if device.prog_if & 1 == 0 {
+        Some(irq::register_isa(14, "pata", irq14)?)
+    } else {
+        None
+    };
+    let mut bus = unsafe { Bus::new(io_base, ctl_base) };
+    if let Err(e) = unsafe { bus.initialize() } {
+        if let Some(handler) = handler {
+            irq::unregister(handler);
+        }
+        return Err(e);
+    }
+    driver.default_bus = bus;
+    driver.bound = true;
+    Ok(())
 }
 
 pub unsafe fn read_sectors(drive_select: DiskSelect, sector: u64, buffer: &mut [u8]) -> Result<(), String> {
@@ -83,22 +134,12 @@ impl Bus {
     unsafe fn initialize(&mut self) -> Result<(), String> {
         // Reset drive
         self.write_ctl(0, 0b100);
-        // Wait 5 µs
-        // Assume each read takes 30 ns
-        for _ in 0..5000 / 30 + 1 {
-            self.read_ctl(0);
-        }
+        timer::busy_wait(Duration::from_micros(5));
         self.write_ctl(0, 0);
 
-        pic::enable_irq(2);
-        idt::register_isr(0x20 + 14, irq14);
-        pic::enable_irq(14);
-
         self.write_io(6, 0xA0u8);
-        for _ in 0..16 {
-            self.read_ctl(0);
-        }
-        while self.read_ctl(0) & 0x80 > 0 {}
+        self.delay();
+        self.wait_not_busy()?;
 
         println!("Started IDENTIFY for Master");
         for i in 2..=5 {
@@ -111,13 +152,14 @@ impl Bus {
             self.master_drive = NewDiskInfo::Missing;
         } else {
             println!("Waiting for BSY to clear...");
-            while self.read_ctl(0) & 0x80 > 0 {}
+            self.wait_not_busy()?;
             let mut identify_response = [0u16; 256];
             if self.read_io::<u8>(4) > 0 || self.read_io::<u8>(5) > 0 {
                 println!("Not ATA");
                 self.master_drive = NewDiskInfo::Other;
             }
             println!("Waiting for DRQ or ERR to set...");
+            let deadline = timer::now() + TIMEOUT;
             loop {
                 let ctl = self.read_ctl(0);
                 if ctl & 0b1000 > 0 {
@@ -126,6 +168,9 @@ impl Bus {
                     println!("ATA disk 0 err during identify");
                     self.master_drive = NewDiskInfo::Other
                 }
+                if timer::now() > deadline {
+                    return Err("ATA timeout during IDENTIFY".to_string());
+                }
             }
     
             println!("Reading IDENTIFY response...");
@@ -150,10 +195,8 @@ impl Bus {
 
         println!("Enabling Slave...");
         self.write_io(6, 0xB0u8);
-        for _ in 0..16 {
-            self.read_ctl(0);
-        }
-        while self.read_ctl(0) & 0x80 > 0 {}
+        self.delay();
+        self.wait_not_busy()?;
 
         println!("Started IDENTIFY for Slave");
         for i in 2..=5 {
@@ -166,13 +209,14 @@ impl Bus {
             self.slave_drive = NewDiskInfo::Missing;
         } else {
             println!("Waiting for BSY to clear...");
-            while self.read_ctl(0) & 0x80 > 0 {}
+            self.wait_not_busy()?;
             let mut identify_response = [0u16; 256];
             if self.read_io::<u8>(4) > 0 || self.read_io::<u8>(5) > 0 {
                 println!("Not ATA");
                 self.master_drive = NewDiskInfo::Other;
             }
             println!("Waiting for DRQ or ERR to set...");
+            let deadline = timer::now() + TIMEOUT;
             loop {
                 let ctl = self.read_ctl(0);
                 if ctl & 0b1000 > 0 {
@@ -181,6 +225,9 @@ impl Bus {
                     println!("ATA disk 0 err during identify");
                     self.master_drive = NewDiskInfo::Other
                 }
+                if timer::now() > deadline {
+                    return Err("ATA timeout during IDENTIFY".to_string());
+                }
             }
     
             println!("Reading IDENTIFY response...");
@@ -284,7 +331,8 @@ impl Bus {
     
     unsafe fn poll(&mut self) -> Result<(), String> {
         let mut status_port = Port::new(self.ctl_base);
-        while status_port.read() & 0x80 > 0 {}
+        self.wait_not_busy()?;
+        let deadline = timer::now() + TIMEOUT;
         loop {
             let status: u8 = status_port.read();
             if status & 0x08 > 0 {
@@ -292,14 +340,23 @@ impl Bus {
             }
             else if status & 0x1 > 0 { break Err("ATA error".to_string()) }
             else if status & 0x20 > 0 { break Err("ATA disk error".to_string()) }
+            else if timer::now() > deadline { break Err("ATA timeout waiting for DRQ".to_string()) }
         }
     }
+
+    unsafe fn wait_not_busy(&mut self) -> Result<(), String> {
+        let deadline = timer::now() + TIMEOUT;
+        while self.read_ctl(0) & 0x80 > 0 {
+            if timer::now() > deadline {
+                return Err("ATA timeout waiting for BSY to clear".to_string());
+            }
+        }
+        Ok(())
+    }
     
+    /// The 400 ns the drive needs after a command or drive select before its status means anything.
     unsafe fn delay(&mut self) {
-        let mut status_port: Port<u8> = Port::new(self.ctl_base);
-        for _ in 0..16 {
-            status_port.read();
-        }
+        timer::busy_wait(Duration::from_nanos(400));
     }
 }
 
@@ -0,0 +1,135 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    PhysAddr, VirtAddr,
+};
+
+use super::Address;
+use crate::acpi::mcfg::EcamRegion;
+
+/// A way of reaching PCI configuration space. Offsets are in bytes and must be 4-byte aligned.
+pub trait ConfigAccess: Send + Sync {
+    unsafe fn read(&self, address: Address, offset: u16) -> u32;
+    unsafe fn write(&self, address: Address, offset: u16, value: u32);
+    /// Whether the bus can be reached at all.
+    fn has_bus(&self, segment: u16, bus: u8) -> bool;
+    /// Size of each function's configuration space: 4096 bytes with ECAM, 256 without.
+    fn size(&self) -> u16;
+}
+
+/// Memory-mapped configuration space. Each bus is mapped the first time it is accessed, so buses
+/// that don't exist take no address space.
+pub struct Ecam {
+    regions: Vec<EcamRegion>,
+    mapped: Mutex<BTreeMap<(u16, u8), VirtAddr>>,
+}
+
+impl Ecam {
+    pub fn new(regions: Vec<EcamRegion>) -> Self {
+        Self {
+            regions,
+            mapped: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn function_base(&self, address: Address) -> Option<VirtAddr> {
+        let region = self
+            .regions
+            .iter()
+            .find(|r| r.segment == address.segment && r.buses.contains(&address.bus))?;
+        let bus_base = interrupts::without_interrupts(|| {
+            *self
+                .mapped
+                .lock()
+                .entry((address.segment, address.bus))
+                .or_insert_with(|| {
+                    let phys = region.base + (((address.bus - region.buses.start()) as u64) << 20);
+                    crate::memory::map_mmio("pci ecam", PhysAddr::new(phys), 1 << 20)
+                })
+        });
+        Some(bus_base + ((address.device as u64) << 15 | (address.function as u64) << 12))
+    }
+}
+
+impl ConfigAccess for Ecam {
+    unsafe fn read(&self, address: Address, offset: u16) -> u32 {
+        match self.function_base(address) {
+            Some(base) => ((base + offset as u64).as_u64() as *const u32).read_volatile(),
+            None => !0,
+        }
+    }
+
+    unsafe fn write(&self, address: Address, offset: u16, value: u32) {
+        if let Some(base) = self.function_base(address) {
+            ((base + offset as u64).as_u64() as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn has_bus(&self, segment: u16, bus: u8) -> bool {
+        self.regions
+            .iter()
+            .any(|r| r.segment == segment && r.buses.contains(&bus))
+    }
+
+    fn size(&self) -> u16 {
+        4096
+    }
+}
+
+/// Configuration mechanism #1 through ports `0xCF8` and `0xCFC`, for machines without ECAM. Only
+/// reaches segment 0 and the first 256 bytes of each function.
+pub struct PortIo {
+    lock: Mutex<()>,
+}
+
+impl PortIo {
+    pub const fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn select(address: Address, offset: u16) {
+        let value = 0x8000_0000
+            | (address.bus as u32) << 16
+            | (address.device as u32) << 11
+            | (address.function as u32) << 8
+            | (offset as u32 & 0xFC);
+        unsafe { Port::new(0xCF8).write(value) };
+    }
+}
+
+impl ConfigAccess for PortIo {
+    unsafe fn read(&self, address: Address, offset: u16) -> u32 {
+        if address.segment != 0 || offset >= 256 {
+            return !0;
+        }
+        // The address and data ports are one shared pair
+        interrupts::without_interrupts(|| {
+            let _lock = self.lock.lock();
+            Self::select(address, offset);
+            Port::new(0xCFC).read()
+        })
+    }
+
+    unsafe fn write(&self, address: Address, offset: u16, value: u32) {
+        if address.segment != 0 || offset >= 256 {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            let _lock = self.lock.lock();
+            Self::select(address, offset);
+            Port::new(0xCFC).write(value);
+        })
+    }
+
+    fn has_bus(&self, segment: u16, _bus: u8) -> bool {
+        segment == 0
+    }
+
+    fn size(&self) -> u16 {
+        256
+    }
+}
@@ -0,0 +1,79 @@
+use alloc::{string::String, vec::Vec};
+
+use spin::Mutex;
+
+use super::{Device, DEVICES};
+
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+
+/// What a driver binds to.
+pub enum Match {
+    /// Class and subclass, and the programming interface unless it's `None`.
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+    Id {
+        vendor: u16,
+        device: u16,
+    },
+}
+
+impl Match {
+    fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Match::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+            Match::Id { vendor, device: id } => {
+                device.vendor_id == vendor && device.device_id == id
+            }
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Sets up a matching device. A device stays unbound if this fails, so another driver may
+    /// still take it.
+    pub probe: fn(&'static Device) -> Result<(), String>,
+}
+
+/// Adds `driver` to the registry. If enumeration already happened, it is bound to any matching
+/// devices that are still unbound right away.
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    if let Some(devices) = DEVICES.get() {
+        for device in devices {
+            bind(device, driver);
+        }
+    }
+}
+
+pub(super) fn bind_all(devices: &'static [Device]) {
+    // Probing may take a while, and may register more drivers
+    let drivers = DRIVERS.lock().clone();
+    for device in devices {
+        for &driver in &drivers {
+            bind(device, driver);
+        }
+    }
+}
+
+fn bind(device: &'static Device, driver: &'static Driver) {
+    if device.driver().is_some() || !driver.matches.iter().any(|m| m.matches(device)) {
+        return;
+    }
+    match (driver.probe)(device) {
+        Ok(()) => *device.driver.lock() = Some(driver.name),
+        Err(e) => println!("{}: {} driver failed: {}", device.address, driver.name, e),
+    }
+}
@@ -0,0 +1,513 @@
+//! PCI and PCI Express devices. Every bus reachable from the host bridges is enumerated once at
+//! boot, and drivers bind to the devices they match through the registry in [`driver`].
+
+mod config;
+mod driver;
+mod msi;
+
+pub use driver::{register_driver, Driver, Match};
+pub use msi::{MessageInterrupts, MessageKind};
+
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
+use core::fmt;
+
+use config::{ConfigAccess, Ecam, PortIo};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::acpi;
+
+static ACCESS: Once<Box<dyn ConfigAccess>> = Once::new();
+static DEVICES: Once<Vec<Device>> = Once::new();
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const CAPABILITY_MSI: u8 = 0x05;
+const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+const CAPABILITY_MSI_X: u8 = 0x11;
+
+/// Where a function sits: segment, bus, device and function number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A base address register, decoded and sized.
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Endpoint,
+    /// A PCI-to-PCI bridge, and the range of buses behind it.
+    Bridge {
+        secondary_bus: u8,
+        subordinate_bus: u8,
+    },
+    CardBusBridge,
+}
+
+/// An entry of the capability list, with `offset` its place in configuration space.
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    Msi {
+        offset: u8,
+        is_64_bit: bool,
+        per_vector_masking: bool,
+        max_vectors: u8,
+    },
+    MsiX {
+        offset: u8,
+        table_size: u16,
+        table_bar: u8,
+        table_offset: u32,
+        pba_bar: u8,
+        pba_offset: u32,
+    },
+    PciExpress {
+        offset: u8,
+        device_type: u8,
+    },
+    Other {
+        id: u8,
+        offset: u8,
+    },
+}
+
+/// A PCI function, as found during enumeration.
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub kind: Kind,
+    /// Indexed by BAR number; the upper half of a 64-bit BAR is `None`.
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// The legacy IRQ the firmware routed the function to, 0xFF if none.
+    pub interrupt_line: u8,
+    /// 1 to 4 for INTA# to INTD#, 0 if the function doesn't use legacy interrupts.
+    pub interrupt_pin: u8,
+    /// Name of the driver bound to the function.
+    driver: Mutex<Option<&'static str>>,
+}
+
+impl Device {
+    pub fn read_config(&self, offset: u16) -> u32 {
+        unsafe { access().read(self.address, offset) }
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        unsafe { access().write(self.address, offset, value) }
+    }
+
+    pub fn enable_io_space(&self) {
+        self.set_command(COMMAND_IO_SPACE);
+    }
+
+    pub fn enable_memory_space(&self) {
+        self.set_command(COMMAND_MEMORY_SPACE);
+    }
+
+    /// Lets the function do DMA.
+    pub fn enable_bus_mastering(&self) {
+        self.set_command(COMMAND_BUS_MASTER);
+    }
+
+    fn set_command(&self, bits: u16) {
+        // The upper half is the status register, whose bits are cleared by writing ones
+        let command = self.read_config(0x04) & 0xFFFF;
+        self.write_config(0x04, command | bits as u32);
+    }
+
+    pub fn driver(&self) -> Option<&'static str> {
+        *self.driver.lock()
+    }
+
+    /// What kind of device this is, going by its class code.
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass, self.prog_if) {
+            (0x01, 0x01, _) => "IDE controller",
+            (0x01, 0x06, _) => "SATA controller",
+            (0x01, 0x08, _) => "NVMe controller",
+            (0x01, _, _) => "Mass storage controller",
+            (0x02, _, _) => "Network controller",
+            (0x03, _, _) => "Display controller",
+            (0x04, _, _) => "Multimedia controller",
+            (0x05, _, _) => "Memory controller",
+            (0x06, 0x00, _) => "Host bridge",
+            (0x06, 0x01, _) => "ISA bridge",
+            (0x06, 0x04, _) => "PCI bridge",
+            (0x06, _, _) => "Bridge",
+            (0x0C, 0x03, 0x00) => "UHCI controller",
+            (0x0C, 0x03, 0x10) => "OHCI controller",
+            (0x0C, 0x03, 0x20) => "EHCI controller",
+            (0x0C, 0x03, 0x30) => "xHCI controller",
+            (0x0C, 0x05, _) => "SMBus controller",
+            (0x0C, _, _) => "Serial bus controller",
+            _ => "Unknown device",
+        }
+    }
+
+    fn new(address: Address) -> Self {
+        let access = access();
+        let read = |offset| unsafe { access.read(address, offset) };
+        let id = read(0x00);
+        let class = read(0x08);
+        let kind = match (read(0x0C) >> 16) as u8 & 0x7F {
+            0 => Kind::Endpoint,
+            1 => {
+                let buses = read(0x18);
+                Kind::Bridge {
+                    secondary_bus: (buses >> 8) as u8,
+                    subordinate_bus: (buses >> 16) as u8,
+                }
+            }
+            _ => Kind::CardBusBridge,
+        };
+        let interrupt = read(0x3C);
+        let mut device = Self {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            kind,
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            driver: Mutex::new(None),
+        };
+        match kind {
+            Kind::Endpoint => device.size_bars(6),
+            Kind::Bridge { .. } => device.size_bars(2),
+            // CardBus bridges keep their capability pointer elsewhere, and have no BARs that
+            // matter here
+            Kind::CardBusBridge => return device,
+        }
+        if (read(0x04) >> 16) as u16 & STATUS_CAPABILITIES != 0 {
+            device.read_capabilities();
+        }
+        device
+    }
+
+    /// Finds the address and size of the first `count` BARs. The size is what the function reports
+    /// after all ones are written to the BAR, so decoding is off while that is done.
+    fn size_bars(&mut self, count: usize) {
+        let command = self.read_config(0x04) & 0xFFFF;
+        // Nothing may touch the function's resources while they move around, not even a print from
+        // an interrupt handler if this is the display
+        interrupts::without_interrupts(|| {
+            self.write_config(
+                0x04,
+                command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32,
+            );
+            let mut i = 0;
+            while i < count {
+                let offset = 0x10 + i as u16 * 4;
+                let (bar, slots) = self.size_bar(offset, i + 1 < count);
+                self.bars[i] = bar;
+                i += slots;
+            }
+            self.write_config(0x04, command);
+        });
+    }
+
+    /// Sizes the BAR at `offset`, and returns it along with how many BAR slots it takes up.
+    fn size_bar(&self, offset: u16, has_upper_half: bool) -> (Option<Bar>, usize) {
+        let probe = |offset| {
+            let original = self.read_config(offset);
+            self.write_config(offset, !0);
+            let mask = self.read_config(offset);
+            self.write_config(offset, original);
+            (original, mask)
+        };
+
+        let (low, mask) = probe(offset);
+        if low & 1 != 0 {
+            let mask = mask & 0xFFFC;
+            let bar = match mask {
+                0 => None,
+                mask => Some(Bar::Io {
+                    port: (low & 0xFFFC) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                }),
+            };
+            return (bar, 1);
+        }
+
+        let prefetchable = low & 0x8 != 0;
+        let (address, mask, slots) = if (low >> 1) & 0x3 == 0x2 && has_upper_half {
+            let (high, high_mask) = probe(offset + 4);
+            (
+                (high as u64) << 32 | (low & !0xF) as u64,
+                (high_mask as u64) << 32 | (mask & !0xF) as u64,
+                2,
+            )
+        } else {
+            (
+                (low & !0xF) as u64,
+                0xFFFF_FFFF_0000_0000 | (mask & !0xF) as u64,
+                1,
+            )
+        };
+        // An unimplemented BAR ignores the write and stays zero
+        if mask as u32 == 0 && (slots == 1 || mask == 0) {
+            return (None, slots);
+        }
+        let bar = Bar::Memory {
+            address,
+            size: (!mask).wrapping_add(1),
+            prefetchable,
+        };
+        (Some(bar), slots)
+    }
+
+    fn read_capabilities(&mut self) {
+        let mut offset = (self.read_config(0x34) & 0xFC) as u8;
+        // A list longer than fits in configuration space must loop
+        let mut remaining = 48;
+        while offset != 0 && remaining > 0 {
+            remaining -= 1;
+            let header = self.read_config(offset as u16);
+            let id = header as u8;
+            let control = (header >> 16) as u16;
+            let capability = match id {
+                CAPABILITY_MSI => Capability::Msi {
+                    offset,
+                    is_64_bit: control & 1 << 7 != 0,
+                    per_vector_masking: control & 1 << 8 != 0,
+                    max_vectors: 1 << (control >> 1 & 0x7).min(5),
+                },
+                CAPABILITY_MSI_X => {
+                    let table = self.read_config(offset as u16 + 4);
+                    let pba = self.read_config(offset as u16 + 8);
+                    Capability::MsiX {
+                        offset,
+                        table_size: (control & 0x7FF) + 1,
+                        table_bar: (table & 0x7) as u8,
+                        table_offset: table & !0x7,
+                        pba_bar: (pba & 0x7) as u8,
+                        pba_offset: pba & !0x7,
+                    }
+                }
+                CAPABILITY_PCI_EXPRESS => Capability::PciExpress {
+                    offset,
+                    device_type: (control >> 4 & 0xF) as u8,
+                },
+                id => Capability::Other { id, offset },
+            };
+            self.capabilities.push(capability);
+            offset = (header >> 8) as u8 & 0xFC;
+        }
+    }
+}
+
+fn access() -> &'static dyn ConfigAccess {
+    &**ACCESS.get().expect("PCI used before pci::init")
+}
+
+/// Enumerates every function, through ECAM if the MCFG table lists any and through I/O ports
+/// otherwise, then binds the registered drivers. Call after [`acpi::init`].
+pub fn init() {
+    let regions = acpi::mcfg::regions();
+    let roots: Vec<(u16, u8)> = if regions.is_empty() {
+        vec![(0, 0)]
+    } else {
+        regions
+            .iter()
+            .map(|r| (r.segment, *r.buses.start()))
+            .collect()
+    };
+    let access = ACCESS.call_once(|| {
+        if regions.is_empty() {
+            println!("No MCFG table; reaching PCI configuration space through I/O ports");
+            Box::new(PortIo::new())
+        } else {
+            Box::new(Ecam::new(regions))
+        }
+    });
+
+    let mut devices = Vec::new();
+    let mut scanned = BTreeSet::new();
+    for &(segment, bus) in &roots {
+        scan_bus(&**access, segment, bus, &mut scanned, &mut devices);
+        // A multi-function host bridge means there are several host bridges, and each function
+        // number is the root bus of one
+        let host = Address::new(segment, bus, 0, 0);
+        if is_multi_function(&**access, host) {
+            for function in 1..8 {
+                let host = Address::new(segment, bus, 0, function);
+                if exists(&**access, host) {
+                    scan_bus(
+                        &**access,
+                        segment,
+                        bus + function,
+                        &mut scanned,
+                        &mut devices,
+                    );
+                }
+            }
+        }
+    }
+    devices.sort_unstable_by_key(|device: &Device| device.address);
+
+    driver::bind_all(DEVICES.call_once(|| devices));
+}
+
+/// Every function found, sorted by address. Empty before [`init`].
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[], |devices| &devices[..])
+}
+
+pub fn print_devices() {
+    for device in devices() {
+        println!(
+            "  {} {:04x}:{:04x} {:02x}{:02x}{:02x} {}{}{}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.class_name(),
+            if device.driver().is_some() {
+                " -> "
+            } else {
+                ""
+            },
+            device.driver().unwrap_or("")
+        );
+        if let Kind::Bridge {
+            secondary_bus,
+            subordinate_bus,
+        } = device.kind
+        {
+            println!(
+                "      buses {:02x}..={:02x}",
+                secondary_bus, subordinate_bus
+            );
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                }) => println!(
+                    "      BAR {}: memory {:#x}, {:#x} bytes{}",
+                    i,
+                    address,
+                    size,
+                    if *prefetchable { ", prefetchable" } else { "" }
+                ),
+                Some(Bar::Io { port, size }) => {
+                    println!("      BAR {}: I/O {:#x}, {} ports", i, port, size)
+                }
+                None => {}
+            }
+        }
+        if !device.capabilities.is_empty() {
+            print!("      capabilities:");
+            for capability in &device.capabilities {
+                match capability {
+                    Capability::Msi { max_vectors, .. } => print!(" MSI ({})", max_vectors),
+                    Capability::MsiX { table_size, .. } => print!(" MSI-X ({})", table_size),
+                    Capability::PciExpress { .. } => print!(" PCIe"),
+                    Capability::Other { id, .. } => print!(" {:02x}", id),
+                }
+            }
+            println!();
+        }
+    }
+}
+
+fn exists(access: &dyn ConfigAccess, address: Address) -> bool {
+    unsafe { access.read(address, 0x00) as u16 != 0xFFFF }
+}
+
+fn is_multi_function(access: &dyn ConfigAccess, address: Address) -> bool {
+    exists(access, address) && unsafe { access.read(address, 0x0C) } & 0x0080_0000 != 0
+}
+
+/// Adds the functions on `bus` to `devices`, then scans the buses behind any bridges among them.
+fn scan_bus(
+    access: &dyn ConfigAccess,
+    segment: u16,
+    bus: u8,
+    scanned: &mut BTreeSet<(u16, u8)>,
+    devices: &mut Vec<Device>,
+) {
+    if !access.has_bus(segment, bus) || !scanned.insert((segment, bus)) {
+        return;
+    }
+    for slot in 0..32 {
+        let first = Address::new(segment, bus, slot, 0);
+        if !exists(access, first) {
+            continue;
+        }
+        let functions = if is_multi_function(access, first) {
+            8
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let address = Address::new(segment, bus, slot, function);
+            if !exists(access, address) {
+                continue;
+            }
+            let device = Device::new(address);
+            let secondary = match device.kind {
+                Kind::Bridge { secondary_bus, .. } if secondary_bus > bus => Some(secondary_bus),
+                _ => None,
+            };
+            devices.push(device);
+            if let Some(secondary) = secondary {
+                scan_bus(access, segment, secondary, scanned, devices);
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use alloc::vec::Vec;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{Bar, Capability, Device};
+use crate::{
+    apic,
+    irq::{self, HandlerId, IrqReturn},
+};
+
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const MSI_ENABLE: u32 = 1 << 0;
+const MSI_X_FUNCTION_MASK: u32 = 1 << 14;
+const MSI_X_ENABLE: u32 = 1 << 15;
+const MSI_X_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Messages written here reach the local APIC whose ID is in bits 12 to 19.
+const MESSAGE_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MessageKind {
+    Msi,
+    MsiX,
+}
+
+/// Vectors a device signals through MSI or MSI-X. Interrupt `i` of the device arrives on
+/// `vectors()[i]`.
+pub struct MessageInterrupts {
+    pub kind: MessageKind,
+    vectors: Vec<u8>,
+    /// The mapped MSI-X table.
+    table: Option<VirtAddr>,
+}
+
+impl MessageInterrupts {
+    pub fn vectors(&self) -> &[u8] {
+        &self.vectors
+    }
+
+    /// Adds `handler` to the handlers of the device's interrupt `index`.
+    pub fn register(
+        &self,
+        index: usize,
+        name: &'static str,
+        handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+    ) -> HandlerId {
+        irq::register(self.vectors[index], name, handler)
+    }
+
+    /// Masks or unmasks the MSI-X table entry `index`. Does nothing with plain MSI.
+    pub fn set_masked(&self, index: usize, masked: bool) {
+        if let Some(table) = self.table {
+            let control = (table + index as u64 * 16 + 12u64).as_u64() as *mut u32;
+            unsafe {
+                let value = control.read_volatile() & !MSI_X_ENTRY_MASKED;
+                control.write_volatile(value | if masked { MSI_X_ENTRY_MASKED } else { 0 });
+            }
+        }
+    }
+}
+
+impl Device {
+    /// Allocates up to `count` vectors for the device's message-signaled interrupts, preferring
+    /// MSI-X, points the messages at the boot processor's local APIC and turns them on instead of
+    /// the legacy interrupt pin. There may be fewer vectors than asked for; at least one if this
+    /// succeeds.
+    pub fn enable_message_interrupts(
+        &self,
+        count: usize,
+    ) -> Result<MessageInterrupts, &'static str> {
+        let apic_id = apic::local_apic_id().ok_or("message-signaled interrupts need the APIC")?;
+        if count == 0 {
+            return Err("no interrupts asked for");
+        }
+        let address = MESSAGE_ADDRESS_BASE | (apic_id as u64 & 0xFF) << 12;
+
+        let interrupts = self
+            .capabilities
+            .iter()
+            .find_map(|capability| match *capability {
+                Capability::MsiX {
+                    offset,
+                    table_size,
+                    table_bar,
+                    table_offset,
+                    ..
+                } => Some(self.enable_msi_x(
+                    offset,
+                    table_size,
+                    table_bar,
+                    table_offset,
+                    count,
+                    address,
+                )),
+                _ => None,
+            })
+            .or_else(|| {
+                self.capabilities
+                    .iter()
+                    .find_map(|capability| match *capability {
+                        Capability::Msi {
+                            offset,
+                            is_64_bit,
+                            per_vector_masking,
+                            max_vectors,
+                        } => Some(self.enable_msi(
+                            offset,
+                            is_64_bit,
+                            per_vector_masking,
+                            max_vectors,
+                            count,
+                            address,
+                        )),
+                        _ => None,
+                    })
+            })
+            .unwrap_or(Err("the device has neither MSI nor MSI-X"))?;
+
+        self.set_command(COMMAND_INTERRUPT_DISABLE);
+        Ok(interrupts)
+    }
+
+    fn enable_msi(
+        &self,
+        offset: u8,
+        is_64_bit: bool,
+        per_vector_masking: bool,
+        max_vectors: u8,
+        count: usize,
+        address: u64,
+    ) -> Result<MessageInterrupts, &'static str> {
+        // The device puts the interrupt number in the low bits of the data, so the count is a power
+        // of two and the vectors are consecutive
+        let count = (count.min(max_vectors as usize) + 1).next_power_of_two() / 2;
+        let first = irq::allocate_vectors(count).ok_or("out of interrupt vectors")?;
+
+        let offset = offset as u16;
+        let (data_offset, mask_offset) = if is_64_bit {
+            self.write_config(offset + 8, (address >> 32) as u32);
+            (offset + 12, offset + 16)
+        } else {
+            (offset + 8, offset + 12)
+        };
+        self.write_config(offset + 4, address as u32);
+        // Fixed delivery, edge triggered
+        self.write_config(data_offset, first as u32);
+        if per_vector_masking {
+            self.write_config(mask_offset, 0);
+        }
+
+        let header = self.read_config(offset);
+        let mut control = header >> 16 & !(0x7 << 4);
+        control |= (count.trailing_zeros() << 4) | MSI_ENABLE;
+        self.write_config(offset, header & 0xFFFF | control << 16);
+
+        Ok(MessageInterrupts {
+            kind: MessageKind::Msi,
+            vectors: (first..first + count as u8).collect(),
+            table: None,
+        })
+    }
+
+    fn enable_msi_x(
+        &self,
+        offset: u8,
+        table_size: u16,
+        table_bar: u8,
+        table_offset: u32,
+        count: usize,
+        address: u64,
+    ) -> Result<MessageInterrupts, &'static str> {
+        let bar_address = match self.bars.get(table_bar as usize) {
+            Some(Some(Bar::Memory { address, .. })) => *address,
+            _ => return Err("the MSI-X table is not in a memory BAR"),
+        };
+        let count = count.min(table_size as usize);
+        let mut vectors = Vec::with_capacity(count);
+        for _ in 0..count {
+            match irq::allocate_vector() {
+                Some(vector) => vectors.push(vector),
+                None => break,
+            }
+        }
+        if vectors.is_empty() {
+            return Err("out of interrupt vectors");
+        }
+
+        self.enable_memory_space();
+        let table = crate::memory::map_mmio(
+            "msi-x table",
+            PhysAddr::new(bar_address + table_offset as u64),
+            table_size as u64 * 16,
+        );
+        // Nothing may be signaled while entries are half written
+        let offset = offset as u16;
+        let header = self.read_config(offset);
+        let control = header >> 16 | MSI_X_ENABLE | MSI_X_FUNCTION_MASK;
+        self.write_config(offset, header & 0xFFFF | control << 16);
+
+        for index in 0..table_size as usize {
+            let entry = (table + index as u64 * 16).as_u64() as *mut u32;
+            unsafe {
+                match vectors.get(index) {
+                    Some(&vector) => {
+                        entry.write_volatile(address as u32);
+                        entry.add(1).write_volatile((address >> 32) as u32);
+                        entry.add(2).write_volatile(vector as u32);
+                        entry.add(3).write_volatile(0);
+                    }
+                    None => entry.add(3).write_volatile(MSI_X_ENTRY_MASKED),
+                }
+            }
+        }
+
+        self.write_config(
+            offset,
+            header & 0xFFFF | (control & !MSI_X_FUNCTION_MASK) << 16,
+        );
+
+        Ok(MessageInterrupts {
+            kind: MessageKind::MsiX,
+            vectors,
+            table: Some(table),
+        })
+    }
+}
@@ -1,3 +1,5 @@
+use crate::irq::{InterruptController, ISA_VECTOR_BASE};
+
 unsafe fn write_master_command(command: u8) {
     asm!("out 0x20, al", in("al") command, options(nostack));
 }
@@ -134,3 +136,36 @@ pub unsafe fn get_irr() -> u16 {
     let b = read_slave_response() as u16;
     b << 8 | a
 }
+
+/// The 8259 pair as an [`InterruptController`], for machines without usable APICs.
+pub struct Pic;
+
+impl InterruptController for Pic {
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
+
+    unsafe fn enable_irq(&self, irq: u8) -> Result<(), &'static str> {
+        if irq >= 16 {
+            return Err("the 8259 PIC only has 16 IRQs");
+        }
+        // The slave's IRQs pass through the master's cascade input
+        if irq >= 8 {
+            enable_irq(2);
+        }
+        enable_irq(irq);
+        Ok(())
+    }
+
+    unsafe fn disable_irq(&self, irq: u8) {
+        disable_irq(irq);
+    }
+
+    unsafe fn end_of_interrupt(&self, vector: u8) {
+        // Only the ISA vectors come from the PICs
+        match vector.checked_sub(ISA_VECTOR_BASE) {
+            Some(irq) if irq < 16 => send_eoi(irq),
+            _ => {}
+        }
+    }
+}
@@ -1,10 +1,8 @@
 pub mod keyboard;
 
 use spin::Mutex;
-use x86_64::structures::idt::InterruptStackFrame;
 
-use crate::idt;
-use crate::pic;
+use crate::irq::{self, IrqReturn};
 
 pub struct Ps2Driver {}
 
@@ -53,11 +51,10 @@ impl Ps2Driver {
         }
         println!("PS/2 port 1 passed self test");
 
-        // Register ISR for IRQ1
-        idt::register_isr(0x20 + 1, irq1);
-
-        // Make sure IRQ1 is enabled
-        pic::enable_irq(1);
+        // Register ISR for IRQ1 and make sure it is enabled
+        if let Err(e) = irq::register_isa(1, "ps2 keyboard", irq1) {
+            println!("PS/2 controller IRQ unavailable: {}", e);
+        }
 
         // Enable interrupts from the first port
         self.write_command(0x20);
@@ -109,12 +106,12 @@ impl Ps2Driver {
     }
 }
 
-extern "x86-interrupt" fn irq1(stack_frame: InterruptStackFrame) {
+fn irq1() -> IrqReturn {
     let message = unsafe { PS2DRIVER.lock().read_data() };
 
     keyboard::handle_message(message);
 
-    unsafe { pic::send_eoi(1) };
+    IrqReturn::Handled
 }
 
 #[derive(Clone, Copy)]
@@ -0,0 +1,132 @@
+//! The CMOS real-time clock. It is assumed to keep UTC.
+
+use core::time::Duration;
+
+use common::time::DateTime;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{acpi, timer};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hour register for PM times in 12-hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+/// An update takes at most about 2 ms. Without an RTC, the flag reads as set forever.
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_micros(10);
+const UPDATE_POLLS: u32 = 1000;
+
+/// Selecting a register and reading it must not interleave.
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// The registers as read, before decoding.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// Reads the current date and time. Must run after [`timer::init`].
+pub fn read() -> Result<DateTime, &'static str> {
+    let fadt = acpi::fadt();
+    if !fadt.map_or(true, |fadt| fadt.has_cmos_rtc()) {
+        return Err("the FADT says there is no CMOS RTC");
+    }
+    let century_register = fadt.and_then(|fadt| fadt.century_register);
+
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let _guard = CMOS.lock();
+        // The registers can't be read consistently during an update, and one may start between
+        // reads, so read until two rounds agree
+        let mut previous = read_raw(century_register)?;
+        loop {
+            let raw = read_raw(century_register)?;
+            if raw == previous {
+                break Ok((raw, read_register(REG_STATUS_B)));
+            }
+            previous = raw;
+        }
+    })?;
+
+    let decode = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            (value & 0x0F) + (value >> 4) * 10
+        }
+    };
+    let hour = if status_b & STATUS_B_24_HOUR != 0 {
+        decode(raw.hour)
+    } else {
+        // 12 AM is midnight and 12 PM noon
+        let hour = decode(raw.hour & !HOUR_PM) % 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    };
+    let century = match century_register {
+        Some(_) => decode(raw.century) as u16,
+        None => 20,
+    };
+    let date_time = DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    };
+    if date_time.is_valid() {
+        Ok(date_time)
+    } else {
+        Err("the RTC holds an invalid date")
+    }
+}
+
+fn read_raw(century_register: Option<u8>) -> Result<Raw, &'static str> {
+    let mut polls = 0;
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        polls += 1;
+        if polls == UPDATE_POLLS {
+            return Err("the RTC never finished updating");
+        }
+        timer::busy_wait(UPDATE_POLL_INTERVAL);
+    }
+    Ok(Raw {
+        second: read_register(REG_SECOND),
+        minute: read_register(REG_MINUTE),
+        hour: read_register(REG_HOUR),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: century_register.map_or(0, read_register),
+    })
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(INDEX_PORT).write(register);
+        Port::<u8>::new(DATA_PORT).read()
+    }
+}
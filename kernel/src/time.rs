@@ -0,0 +1,62 @@
+//! Wall-clock time: the date and time read once at boot, carried forward by the monotonic clock
+//! in [`timer`](crate::timer).
+
+use core::time::Duration;
+
+use common::time::DateTime;
+use spin::Once;
+
+use crate::{rtc, timer};
+
+/// Unix time at the moment [`timer::now`] was zero.
+static EPOCH_OFFSET: Once<Duration> = Once::new();
+
+/// Sets the time from the RTC, or from `firmware_time` (Unix seconds as read by the bootloader)
+/// if the RTC can't be read. Must run after [`timer::init`].
+pub fn init(firmware_time: Option<u64>) {
+    let since_boot = timer::now();
+    let (unix_time, source) = match (rtc::read(), firmware_time) {
+        (Ok(date_time), _) => {
+            let seconds = date_time.unix_seconds();
+            // The firmware knows the time zone, so a large difference means the RTC keeps local time
+            if let Some(firmware_time) = firmware_time {
+                let difference = (seconds as i64 - firmware_time as i64).abs();
+                if difference > 60 {
+                    println!(
+                        "RTC and firmware clock differ by {} seconds; is the RTC in local time?",
+                        difference
+                    );
+                }
+            }
+            (seconds, "RTC")
+        }
+        (Err(e), Some(firmware_time)) => {
+            println!("RTC unusable, using the firmware's time: {}", e);
+            (firmware_time, "firmware")
+        }
+        (Err(e), None) => {
+            println!("No wall-clock time: {}", e);
+            return;
+        }
+    };
+    // The seconds read are only whole ones, so this is good to a second
+    let offset = Duration::from_secs(unix_time)
+        .checked_sub(since_boot)
+        .unwrap_or_default();
+    EPOCH_OFFSET.call_once(|| offset);
+    println!(
+        "Time: {} (from the {})",
+        DateTime::from_unix_seconds(unix_time),
+        source
+    );
+}
+
+/// Time since the Unix epoch, or `None` if it isn't known.
+pub fn unix_time() -> Option<Duration> {
+    EPOCH_OFFSET.get().map(|&offset| offset + timer::now())
+}
+
+/// The current date and time in UTC, or `None` if it isn't known.
+pub fn now() -> Option<DateTime> {
+    unix_time().map(|time| DateTime::from_unix_seconds(time.as_secs()))
+}
@@ -0,0 +1,78 @@
+use core::time::Duration;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::hpet::Hpet;
+
+const REG_CAPABILITIES: u64 = 0x00;
+const REG_CONFIGURATION: u64 = 0x10;
+const REG_MAIN_COUNTER: u64 = 0xF0;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+/// The HPET's main counter, which counts up at a fixed rate once enabled.
+pub struct HpetCounter {
+    base: VirtAddr,
+    /// Length of a tick in femtoseconds.
+    period: u64,
+    pub is_64_bit: bool,
+}
+
+impl HpetCounter {
+    /// Maps the HPET and starts its main counter.
+    pub unsafe fn init(hpet: &Hpet) -> Result<Self, &'static str> {
+        let base = crate::memory::map_mmio("hpet", PhysAddr::new(hpet.address), 0x400);
+        let counter = Self {
+            base,
+            period: 0,
+            is_64_bit: hpet.counter_is_64_bit,
+        };
+        let period = counter.read_register(REG_CAPABILITIES) >> 32;
+        // The specification caps the period at 100 ns
+        if period == 0 || period > 100_000_000 {
+            return Err("the HPET reports an invalid period");
+        }
+        let configuration = counter.read_register(REG_CONFIGURATION);
+        counter.write_register(REG_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+        Ok(Self { period, ..counter })
+    }
+
+    pub fn read(&self) -> u64 {
+        unsafe {
+            if self.is_64_bit {
+                self.read_register(REG_MAIN_COUNTER)
+            } else {
+                ((self.base + REG_MAIN_COUNTER).as_u64() as *const u32).read_volatile() as u64
+            }
+        }
+    }
+
+    /// Ticks from `start` to `end`, allowing for a 32-bit counter wrapping around once.
+    pub fn elapsed(&self, start: u64, end: u64) -> u64 {
+        if self.is_64_bit {
+            end.wrapping_sub(start)
+        } else {
+            end.wrapping_sub(start) & 0xFFFF_FFFF
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period
+    }
+
+    pub fn ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * 1_000_000 / self.period as u128) as u64
+    }
+
+    pub fn duration(&self, ticks: u64) -> Duration {
+        Duration::from_nanos((ticks as u128 * self.period as u128 / 1_000_000) as u64)
+    }
+
+    unsafe fn read_register(&self, register: u64) -> u64 {
+        ((self.base + register).as_u64() as *const u64).read_volatile()
+    }
+
+    unsafe fn write_register(&self, register: u64, value: u64) {
+        ((self.base + register).as_u64() as *mut u64).write_volatile(value)
+    }
+}
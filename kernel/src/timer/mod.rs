@@ -0,0 +1,341 @@
+//! Time since boot and timer callbacks. The clock is the TSC if it runs at a constant rate, the
+//! HPET if not, and counted ticks as a last resort; ticks come from the local APIC timer, or the
+//! PIT without the APICs.
+
+mod hpet;
+mod pit;
+mod tsc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use self::hpet::HpetCounter;
+use crate::{
+    acpi::{self, fadt::PmTimer},
+    apic::{self, LocalApic},
+    irq::{self, IrqReturn},
+};
+
+/// How often timer callbacks are checked, and how often the clock advances if it counts ticks.
+const TICK_HZ: u64 = 1000;
+const TICK: Duration = Duration::from_millis(1000 / TICK_HZ);
+
+/// How long the TSC and local APIC timer are measured against the reference clock.
+const CALIBRATION_WINDOW: Duration = Duration::from_millis(20);
+
+enum Clock {
+    Tsc { frequency: u64, start: u64 },
+    Hpet { counter: HpetCounter, start: u64 },
+    Ticks,
+}
+
+impl Clock {
+    fn name(&self) -> &'static str {
+        match self {
+            Clock::Tsc { .. } => "TSC",
+            Clock::Hpet { .. } => "HPET",
+            Clock::Ticks => "timer ticks",
+        }
+    }
+}
+
+/// A clock with a known rate that the TSC and local APIC timer are calibrated against.
+enum Reference<'a> {
+    Hpet(&'a HpetCounter),
+    PmTimer(&'a PmTimer),
+    Pit,
+}
+
+impl Reference<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Reference::Hpet(_) => "HPET",
+            Reference::PmTimer(_) => "ACPI PM timer",
+            Reference::Pit => "PIT",
+        }
+    }
+
+    /// Spins for `duration`, which must be well below the time any of the counters take to wrap.
+    fn wait(&self, duration: Duration) {
+        match self {
+            Reference::Hpet(counter) => {
+                let ticks = counter.ticks(duration);
+                let start = counter.read();
+                while counter.elapsed(start, counter.read()) < ticks {
+                    core::hint::spin_loop();
+                }
+            }
+            Reference::PmTimer(pm_timer) => {
+                let mask = if pm_timer.is_32_bit {
+                    0xFFFF_FFFF
+                } else {
+                    0xFF_FFFF
+                };
+                let ticks =
+                    (duration.as_nanos() * PmTimer::FREQUENCY as u128 / 1_000_000_000) as u32;
+                let start = pm_timer.read();
+                while pm_timer.read().wrapping_sub(start) & mask < ticks {
+                    core::hint::spin_loop();
+                }
+            }
+            Reference::Pit => pit::wait(duration),
+        }
+    }
+}
+
+/// Where the periodic tick comes from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TickSource {
+    LocalApic,
+    Pit,
+}
+
+static CLOCK: Once<Clock> = Once::new();
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Whether the tick was set up; [`sleep`] halts until it comes.
+static TICKING: AtomicBool = AtomicBool::new(false);
+
+/// Identifies a timer callback, for [`cancel`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: u64,
+    deadline: Duration,
+    period: Option<Duration>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+static TIMERS: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Picks and calibrates the clock and starts the tick. Must run after [`irq::init`], with interrupts
+/// still disabled; the tick starts once they are enabled. If the tick can't be set up, the clock
+/// still runs and waits spin instead.
+pub fn init() -> Result<(), &'static str> {
+    let hpet = acpi::hpet().and_then(|hpet| match unsafe { HpetCounter::init(hpet) } {
+        Ok(counter) => Some(counter),
+        Err(e) => {
+            println!("HPET unusable: {}", e);
+            None
+        }
+    });
+    let pm_timer = acpi::fadt().and_then(|fadt| fadt.pm_timer.as_ref());
+    let reference = match (&hpet, pm_timer) {
+        (Some(counter), _) => Reference::Hpet(counter),
+        (None, Some(pm_timer)) => Reference::PmTimer(pm_timer),
+        (None, None) => Reference::Pit,
+    };
+    let local_apic = apic::local_apic();
+    let (tsc_frequency, apic_timer_frequency) = calibrate(&reference, local_apic);
+    let reference = reference.name();
+    let tick_source = start_tick(local_apic, apic_timer_frequency);
+
+    let clock = match hpet {
+        _ if tsc::is_invariant() && tsc_frequency > 0 => Clock::Tsc {
+            frequency: tsc_frequency,
+            start: tsc::read(),
+        },
+        // A 32-bit counter wraps within minutes
+        Some(counter) if counter.is_64_bit => {
+            let start = counter.read();
+            Clock::Hpet { counter, start }
+        }
+        _ if tick_source.is_ok() => Clock::Ticks,
+        // A TSC that changes rate with the CPU's still beats counting ticks that never come
+        _ if tsc_frequency > 0 => Clock::Tsc {
+            frequency: tsc_frequency,
+            start: tsc::read(),
+        },
+        _ => Clock::Ticks,
+    };
+    let clock = CLOCK.call_once(|| clock);
+    let tick_source = tick_source?;
+
+    println!(
+        "Clock: {}, TSC at {} MHz, calibrated against the {}; ticks from the {}",
+        clock.name(),
+        tsc_frequency / 1_000_000,
+        reference,
+        match tick_source {
+            TickSource::LocalApic => "local APIC timer",
+            TickSource::Pit => "PIT",
+        }
+    );
+    Ok(())
+}
+
+/// Starts the periodic tick, from the local APIC timer if it is fast enough and the PIT otherwise.
+fn start_tick(
+    local_apic: Option<&LocalApic>,
+    apic_timer_frequency: Option<u64>,
+) -> Result<TickSource, &'static str> {
+    let tick_source = match (local_apic, apic_timer_frequency) {
+        (Some(local_apic), Some(frequency)) if frequency >= TICK_HZ => {
+            let vector = irq::allocate_vector().ok_or("out of interrupt vectors")?;
+            irq::register(vector, "timer", tick);
+            unsafe { local_apic.start_periodic_timer(vector, (frequency / TICK_HZ) as u32) };
+            TickSource::LocalApic
+        }
+        _ => {
+            irq::register_isa(0, "timer", tick)?;
+            unsafe { pit::start_periodic(TICK_HZ) };
+            TickSource::Pit
+        }
+    };
+    TICKING.store(true, Ordering::Relaxed);
+    Ok(tick_source)
+}
+
+/// Measures the TSC's frequency, and the local APIC timer's if there is one, in Hz.
+fn calibrate(reference: &Reference, local_apic: Option<&LocalApic>) -> (u64, Option<u64>) {
+    interrupts::without_interrupts(|| {
+        if let Some(local_apic) = local_apic {
+            unsafe { local_apic.start_timer_measurement() };
+        }
+        let apic_start = local_apic.map(LocalApic::timer_count);
+        let tsc_start = tsc::read();
+        reference.wait(CALIBRATION_WINDOW);
+        let tsc_end = tsc::read();
+        let apic_end = local_apic.map(LocalApic::timer_count);
+        if let Some(local_apic) = local_apic {
+            unsafe { local_apic.stop_timer() };
+        }
+
+        let per_second =
+            |ticks: u64| (ticks as u128 * 1_000_000_000 / CALIBRATION_WINDOW.as_nanos()) as u64;
+        let apic_ticks = match (apic_start, apic_end) {
+            // The timer counts down
+            (Some(start), Some(end)) => Some(per_second(start.wrapping_sub(end) as u64)),
+            _ => None,
+        };
+        (per_second(tsc_end.wrapping_sub(tsc_start)), apic_ticks)
+    })
+}
+
+/// Time since [`init`]; zero before it.
+pub fn now() -> Duration {
+    let clock = match CLOCK.get() {
+        Some(clock) => clock,
+        None => return Duration::from_secs(0),
+    };
+    match clock {
+        Clock::Tsc { frequency, start } => {
+            let ticks = tsc::read().wrapping_sub(*start);
+            Duration::from_nanos((ticks as u128 * 1_000_000_000 / *frequency as u128) as u64)
+        }
+        Clock::Hpet { counter, start } => counter.duration(counter.elapsed(*start, counter.read())),
+        Clock::Ticks => {
+            Duration::from_nanos(TICKS.load(Ordering::Relaxed) * TICK.as_nanos() as u64)
+        }
+    }
+}
+
+/// Spins for `duration`. Works with interrupts disabled, and before [`init`].
+pub fn busy_wait(duration: Duration) {
+    // Counted ticks stand still without interrupts or a tick, as does a clock that isn't set up yet,
+    // so wait on the PIT directly
+    let clock_stands_still = match CLOCK.get() {
+        Some(Clock::Ticks) => !interrupts::are_enabled() || !TICKING.load(Ordering::Relaxed),
+        Some(_) => false,
+        None => true,
+    };
+    if clock_stands_still {
+        let mut remaining = duration;
+        while remaining > Duration::from_millis(0) {
+            let step = remaining.min(Duration::from_millis(50));
+            pit::wait(step);
+            remaining -= step;
+        }
+        return;
+    }
+    let deadline = now() + duration;
+    while now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits for `duration`, halting between ticks rather than spinning. Falls back to
+/// [`busy_wait`] with interrupts disabled or without a tick.
+pub fn sleep(duration: Duration) {
+    if !interrupts::are_enabled() || !TICKING.load(Ordering::Relaxed) {
+        busy_wait(duration);
+        return;
+    }
+    let deadline = now() + duration;
+    while now() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Calls `callback` once, `delay` from now. Callbacks run in the timer interrupt, to within a tick.
+pub fn after(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    add_timer(delay, None, Box::new(callback))
+}
+
+/// Calls `callback` every `period`, the first time `period` from now. Periods shorter than a tick
+/// are rounded up to one.
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let period = period.max(TICK);
+    add_timer(period, Some(period), Box::new(callback))
+}
+
+/// Stops a timer callback from being called again. Does nothing if it already ran for the last time,
+/// and can't stop a periodic callback from within that callback.
+pub fn cancel(id: TimerId) {
+    interrupts::without_interrupts(|| TIMERS.lock().retain(|timer| timer.id != id.0));
+}
+
+fn add_timer(
+    delay: Duration,
+    period: Option<Duration>,
+    callback: Box<dyn FnMut() + Send>,
+) -> TimerId {
+    let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+    let timer = Timer {
+        id,
+        deadline: now() + delay,
+        period,
+        callback,
+    };
+    interrupts::without_interrupts(|| TIMERS.lock().push(timer));
+    TimerId(id)
+}
+
+fn tick() -> IrqReturn {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    // Callbacks run without the lock held, so they can add and cancel timers themselves. Timers
+    // added by them wait for the next tick, as do periodic ones once re-armed, so each timer runs at
+    // most once per tick.
+    let now = now();
+    let first_new = NEXT_TIMER_ID.load(Ordering::Relaxed);
+    loop {
+        let mut timer = {
+            let mut timers = TIMERS.lock();
+            match timers
+                .iter()
+                .position(|timer| timer.id < first_new && timer.deadline <= now)
+            {
+                Some(index) => timers.swap_remove(index),
+                None => break,
+            }
+        };
+        (timer.callback)();
+        if let Some(period) = timer.period {
+            timer.deadline += period;
+            // Skip periods that were missed entirely rather than calling back for each
+            if timer.deadline <= now {
+                timer.deadline = now + period;
+            }
+            TIMERS.lock().push(timer);
+        }
+    }
+    IrqReturn::Handled
+}
@@ -0,0 +1,48 @@
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+/// Rate the PIT's counters count down at, in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// The keyboard controller's port B, which holds channel 2's gate and output.
+const PORT_B: u16 = 0x61;
+
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT_2: u8 = 1 << 5;
+
+/// Makes channel 0 raise IRQ 0 `hz` times a second.
+pub unsafe fn start_periodic(hz: u64) {
+    let divisor = (FREQUENCY / hz).max(1).min(0xFFFF) as u16;
+    // Channel 0, low then high byte, rate generator
+    Port::<u8>::new(COMMAND).write(0x34);
+    let mut data = Port::<u8>::new(CHANNEL_0);
+    data.write(divisor as u8);
+    data.write((divisor >> 8) as u8);
+}
+
+/// Spins for `duration`, at most about 54 ms, using channel 2 with the speaker disconnected. Works
+/// without interrupts.
+pub fn wait(duration: Duration) {
+    let count = (duration.as_nanos() as u64 * FREQUENCY / 1_000_000_000)
+        .max(1)
+        .min(0xFFFF) as u16;
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value & !PORT_B_SPEAKER | PORT_B_GATE_2);
+        // Channel 2, low then high byte, interrupt on terminal count: the output goes high once the
+        // count reaches zero
+        Port::<u8>::new(COMMAND).write(0xB0);
+        let mut data = Port::<u8>::new(CHANNEL_2);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        while port_b.read() & PORT_B_OUTPUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
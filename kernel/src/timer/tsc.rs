@@ -0,0 +1,11 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC ticks at the same rate regardless of frequency scaling and sleep states, so it
+/// can serve as a clock.
+pub fn is_invariant() -> bool {
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & 1 << 8 != 0 }
+}
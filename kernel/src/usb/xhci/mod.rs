@@ -3,46 +3,93 @@
 use alloc::prelude::v1::*;
 
 use datastructures::{Dcbaa32, Dcbaa64, DcbaaWrapper};
-use x86_64::VirtAddr;
+use spin::Mutex;
+use x86_64::PhysAddr;
 
 use register::{Capability, Operational, Port};
 
+use crate::{
+    irq::IrqReturn,
+    pci::{self, Bar},
+};
+
 mod datastructures;
 #[macro_use]
 mod register;
 
+static CONTROLLERS: Mutex<Vec<XhciDriver>> = Mutex::new(Vec::new());
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "xhci",
+    matches: &[pci::Match::Class {
+        class: 0x0C,
+        subclass: 0x03,
+        prog_if: Some(0x30),
+    }],
+    probe,
+};
+
+fn probe(device: &'static pci::Device) -> Result<(), String> {
+    let (address, size) = match device.bars[0] {
+        Some(Bar::Memory { address, size, .. }) => (address, size),
+        _ => return Err("BAR 0 is not a memory BAR".to_string()),
+    };
+    device.enable_memory_space();
+    device.enable_bus_mastering();
+    let mut driver = unsafe { XhciDriver::new(PhysAddr::new(address), size)? };
+    match device.enable_message_interrupts(1) {
+        Ok(interrupts) => {
+            println!(
+                "xhci: {:?} on vector {:#x}",
+                interrupts.kind,
+                interrupts.vectors()[0]
+            );
+            interrupts.register(0, "xhci", interrupt);
+            driver.interrupts = Some(interrupts);
+        }
+        Err(e) => println!("xhci: no message-signaled interrupts: {}", e),
+    }
+    CONTROLLERS.lock().push(driver);
+    Ok(())
+}
+
+/// Events are reported through the interrupter's event ring, which isn't set up yet, so there is
+/// nothing to do beyond claiming the interrupt.
+fn interrupt() -> IrqReturn {
+    IrqReturn::Handled
+}
+
 pub struct XhciDriver {
     capability: &'static mut Capability,
     operational: &'static mut Operational,
     ports: &'static mut [Port],
+    dcbaa: Option<Box<dyn DcbaaWrapper>>,
+    interrupts: Option<pci::MessageInterrupts>,
 }
 
-impl XhciDriver {
-    pub unsafe fn new(mut base: *mut u32) -> Self {
-        // panic!("USB support is put on hold for now");
+// The registers and contexts are only ever touched through `CONTROLLERS`
+unsafe impl Send for XhciDriver {}
 
-        if (base as u64) < 0xFFFFFF80_00000000 {
-            base = (base as u64 | 0xFFFFFF80_00000000) as _;
-        }
-
-        crate::memory::map_phys_offset(VirtAddr::new(base as u64));
+impl XhciDriver {
+    pub unsafe fn new(base: PhysAddr, size: u64) -> Result<Self, String> {
+        let base = crate::memory::map_mmio("xhci", base, size).as_u64();
 
         let capability = (base as *mut Capability).as_mut().unwrap();
 
         let cap_length = capability.cap_length();
         let port_count = capability.max_ports();
 
-        let operational = ((base as u64 + cap_length as u64) as *mut Operational)
+        let operational = ((base + cap_length as u64) as *mut Operational)
             .as_mut()
             .unwrap();
         let ports = core::slice::from_raw_parts_mut(
-            (base as u64 + cap_length as u64 + 0x400) as *mut Port,
+            (base + cap_length as u64 + 0x400) as *mut Port,
             port_count as _,
         );
 
         let dcbaap = operational.device_context_base_address_array_pointer();
-        let dcbaap = dcbaap | 0xFFFFFF80_00000000;
-        crate::memory::map_phys_offset(VirtAddr::new(dcbaap));
+        // The array lives in RAM, which is always in the physical memory mapping
+        let dcbaap = dcbaap | crate::memory::PHYSICAL_MEMORY_OFFSET;
 
         if capability.uses_64_bit_contexts() {
             println!("64-bit contexts");
@@ -65,6 +112,8 @@ impl XhciDriver {
             capability,
             operational,
             ports,
+            dcbaa: None,
+            interrupts: None,
         };
 
         for port in driver.ports.iter() {
@@ -73,11 +122,11 @@ impl XhciDriver {
             }
         }
 
-        driver.initialize();
-        driver
+        driver.initialize()?;
+        Ok(driver)
     }
 
-    pub unsafe fn initialize(&mut self) {
+    pub unsafe fn initialize(&mut self) -> Result<(), String> {
         while !self.operational.controller_ready() {}
         println!("Controller ready");
 
@@ -87,7 +136,7 @@ impl XhciDriver {
         println!("max scratch buffers: {}", max_scratch_buffers);
 
         if max_scratch_buffers > 0 {
-            panic!("scratch buffers not yet supported");
+            return Err("scratchpad buffers not supported yet".to_string());
         }
 
         let dcbaa = if self.capability.uses_64_bit_contexts() {
@@ -100,7 +149,8 @@ impl XhciDriver {
             let dcbaa = Box::<Dcbaa32>::new(Dcbaa32::new());
             dcbaa as Box<dyn DcbaaWrapper>
         };
+        self.dcbaa = Some(dcbaa);
 
-        loop {}
+        Ok(())
     }
 }
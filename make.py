@@ -8,14 +8,15 @@ class Options:
     def __init__(self):
         self.os = None
         self.debug = False
+        self.heap_debug = False
 
 def build(options):
     cargo_command_bootloader = ["cargo", "build", "--release"]
     cargo_command_kernel = ["cargo", "build"]
     if options.debug == False:
         cargo_command_kernel.append("--release")
-    if options.debug:
-        cargo_command_bootloader.append("--features=wait_for_gdb")
+    if options.heap_debug:
+        cargo_command_kernel.append("--features=heap_debug")
     subprocess.run(cargo_command_bootloader, cwd = "./bootloader", check=True)
     subprocess.run(cargo_command_kernel, cwd = "./kernel", check=True)
     pathpart = "debug" if options.debug else "release"
@@ -25,7 +26,12 @@ def build(options):
         ["mmd", "-i", "disk.fat", "::EFI"],
         ["mmd", "-i", "disk.fat", "::EFI/BOOT"],
         ["mcopy",  "-i",  "disk.fat", "target/x86_64-unknown-uefi/release/bootloader.efi", "::EFI/BOOT/BOOTX64.EFI"],
-        ["mcopy", "-i", "disk.fat", "target/target/"+pathpart+"/kernel", "::kernel.elf"]
+        ["mmd", "-i", "disk.fat", "::hhh"],
+        ["mcopy", "-i", "disk.fat", "boot.cfg", "::hhh/boot.cfg"],
+        ["mcopy", "-i", "disk.fat", "target/target/"+pathpart+"/kernel", "::kernel.elf"],
+        # The initial ramdisk holds everything under initrd/
+        ["tar", "--format=ustar", "-cf", "target/initrd.tar", "-C", "initrd", "."],
+        ["mcopy", "-i", "disk.fat", "target/initrd.tar", "::initrd.tar"]
     ]
     if options.os == "windows":
         for command in commands:
@@ -44,8 +50,13 @@ def run(options):
 
 def install(options):
     subprocess.run(["cp",  "target/x86_64-unknown-uefi/release/bootloader.efi", "/run/media/elekrisk/6D95-4DD4/EFI/BOOT/BOOTX64.EFI"])
+    subprocess.run(["mkdir", "-p", "/run/media/elekrisk/6D95-4DD4/hhh"])
+    subprocess.run(["cp", "boot.cfg", "/run/media/elekrisk/6D95-4DD4/hhh/boot.cfg"])
     pathpart = "debug" if options.debug else "release"
+    # Keep the previous kernel around for the boot menu's fallback entry
+    subprocess.run(["cp", "/run/media/elekrisk/6D95-4DD4/kernel.elf", "/run/media/elekrisk/6D95-4DD4/kernel-old.elf"])
     subprocess.run(["cp",  "target/target/"+pathpart+"/kernel", "/run/media/elekrisk/6D95-4DD4/kernel.elf"])
+    subprocess.run(["cp", "target/initrd.tar", "/run/media/elekrisk/6D95-4DD4/initrd.tar"])
 
 if __name__ == "__main__":
     args = sys.argv
@@ -57,6 +68,8 @@ if __name__ == "__main__":
             options.debug = True
         elif args[i] == "release":
             options.debug = False
+        elif args[i] == "heap_debug":
+            options.heap_debug = True
     options.os = platform.system().lower()
 
     try: